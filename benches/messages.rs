@@ -0,0 +1,244 @@
+//! Throughput of decoding and encoding protocol messages, and of messages
+//! going through a running server, run with `cargo bench`
+//!
+//! The end to end benchmarks need the Redis at REDIS_ADDR (or the default),
+//! and are skipped when it can't be reached.
+use std::env;
+use std::future;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use futures_util::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
+
+use bannana_pho::config::{Config, Settings};
+use bannana_pho::health::WARMED_UP;
+use bannana_pho::infoops::{InfoData, InfoType, CHANNEL_ASSIGN};
+use bannana_pho::listener::Listener;
+use bannana_pho::opcodes::{get_opcode, Health, HeartbeatAckCache, SocketMessage};
+use bannana_pho::redis::check_redis;
+use bannana_pho::server;
+use bannana_pho::voice::VoiceMode;
+
+const HEARTBEAT: &str = r#"{"op": 4, "d": {}}"#;
+const IDENTIFY: &str = r#"{"op": 1, "d": {"token": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"}}"#;
+const CHANNEL_REQ: &str = r#"{"op": 6, "d": {"type": 0, "data": {"channel_id": "1234567890123456789", "guild_id": "9876543210987654321", "modes": ["xsalsa20_poly1305_lite", "xsalsa20_poly1305"]}}}"#;
+const VST_CREATE: &str = r#"{"op": 6, "d": {"type": 3, "data": {"user_id": "1111111111111111111", "channel_id": "1234567890123456789", "guild_id": "9876543210987654321"}}}"#;
+const CHANNEL_EXISTS_REQ: &str = r#"{"op": 6, "d": {"type": 16, "data": {"channel_id": "1234567890123456789", "guild_id": "9876543210987654321"}}}"#;
+
+const SECRET: &str = "bench";
+
+fn decode(c: &mut Criterion) {
+    for (name, msg) in [("heartbeat", HEARTBEAT), ("identify", IDENTIFY), ("channel_req", CHANNEL_REQ), ("vst_create", VST_CREATE)] {
+        c.bench_function(&format!("get_opcode/{}", name), |b| {
+            b.iter(|| get_opcode(black_box(Message::Text(msg.to_string()))))
+        });
+    }
+}
+
+fn encode(c: &mut Criterion) {
+    c.bench_function("encode/heartbeat_ack", |b| {
+        b.iter(|| serde_json::to_string(&SocketMessage::heartbeat_ack(black_box(Health::new(0.75)))).unwrap())
+    });
+
+    let mut heartbeat_acks = HeartbeatAckCache::default();
+    c.bench_function("encode/heartbeat_ack_cached", |b| {
+        b.iter(|| heartbeat_acks.encode(black_box(Health::new(0.75))).to_string())
+    });
+
+    c.bench_function("encode/ready", |b| {
+        b.iter(|| serde_json::to_string(&SocketMessage::ready(Health::MAX, black_box("iA2kwfszN89R7haM8Hp9m67A0cAJtbIB".to_string()), None, None)).unwrap())
+    });
+
+    c.bench_function("encode/channel_assign", |b| {
+        b.iter(|| serde_json::to_string(&SocketMessage::info(
+            InfoType::CHANNEL_ASSIGN,
+            InfoData::CHANNEL_ASSIGN(CHANNEL_ASSIGN {
+                channel_id: black_box("1234567890123456789".to_string()),
+                guild_id: Some("9876543210987654321".to_string()),
+                token: "JHxmbEBwAH6ozEvMRpr2D6powJGCB8E5Sfzf0RRMFngrQSa7MidAdQFvF7ObZSfc".to_string(),
+                mode: VoiceMode::XSalsa20Poly1305Lite,
+                region: "eu-west".to_string(),
+                token_ttl: None
+            })
+        )).unwrap())
+    });
+}
+
+/// Start a server with `settings` on top of the bench ones, giving its
+/// address, or `None` if Redis can't be reached
+fn start_server(runtime: &tokio::runtime::Runtime, settings: &[(&str, String)]) -> Option<String> {
+    let mut pairs = vec![
+        ("SECRET", SECRET.to_string()),
+        ("REDIS_ADDR", env::var("REDIS_ADDR").unwrap_or_default()),
+        ("REDIS_CONNECT_TIMEOUT", "1".to_string()),
+        ("REGION", "bench".to_string())
+    ];
+    pairs.extend(settings.iter().cloned());
+    let config = Config::from_settings(&Settings::from_pairs(pairs)).expect("Invalid bench config!");
+
+    if let Err(e) = check_redis(&config) {
+        eprintln!("Skipping the end to end benchmarks, Redis can't be reached: {}", e);
+        return None;
+    }
+
+    Some(runtime.block_on(async {
+        let listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr();
+        tokio::spawn(server::serve(config, listener, future::pending()));
+
+        while !WARMED_UP.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        addr
+    }))
+}
+
+/// Messages a second one identified connection gets answered, each sent after
+/// the reply to the previous one
+fn e2e(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let addr = match start_server(&runtime, &[]) {
+        Some(addr) => addr,
+        None => return
+    };
+    let mut socket = runtime.block_on(identify(&addr));
+
+    let mut group = c.benchmark_group("e2e");
+    group.throughput(Throughput::Elements(1));
+
+    for (name, msg) in [("heartbeat", HEARTBEAT), ("channel_exists_req", CHANNEL_EXISTS_REQ)] {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| runtime.block_on(async {
+                let start = Instant::now();
+
+                for _ in 0..iters {
+                    socket.send(Message::Text(msg.to_string())).await.unwrap();
+                    socket.next().await.unwrap().unwrap();
+                }
+
+                start.elapsed()
+            }))
+        });
+    }
+
+    group.finish();
+}
+
+/// Requests one connection sends at once before reading the replies
+const PIPELINED: usize = 32;
+
+/// Messages a second one identified connection gets answered when it sends
+/// [`PIPELINED`] at once, handling them one at a time and several at once
+fn e2e_pipelined(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("e2e_pipelined");
+    group.throughput(Throughput::Elements(PIPELINED as u64));
+
+    for concurrency in [1, 8] {
+        let addr = match start_server(&runtime, &[("INFO_CONCURRENCY", concurrency.to_string())]) {
+            Some(addr) => addr,
+            None => return
+        };
+        let mut socket = runtime.block_on(identify(&addr));
+
+        group.bench_function(format!("channel_exists_req/{}_at_once", concurrency), |b| {
+            b.iter_custom(|iters| runtime.block_on(async {
+                let start = Instant::now();
+
+                for _ in 0..iters {
+                    for _ in 0..PIPELINED {
+                        socket.feed(Message::Text(CHANNEL_EXISTS_REQ.to_string())).await.unwrap();
+                    }
+                    socket.flush().await.unwrap();
+
+                    for _ in 0..PIPELINED {
+                        socket.next().await.unwrap().unwrap();
+                    }
+                }
+
+                start.elapsed()
+            }))
+        });
+    }
+
+    group.finish();
+}
+
+/// Connections that each ask about a guild of their own
+const GUILDS: usize = 32;
+
+/// Messages a second as many connections as [`GUILDS`] get answered, each
+/// asking about a guild of its own at the same time, with one Redis shard and
+/// with several
+fn e2e_guilds(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("e2e_guilds");
+    group.throughput(Throughput::Elements(GUILDS as u64));
+
+    for shards in [1, 4] {
+        let addr = match start_server(&runtime, &[("REDIS_SHARDS", shards.to_string())]) {
+            Some(addr) => addr,
+            None => return
+        };
+
+        let mut sockets = runtime.block_on(async {
+            let mut sockets = Vec::with_capacity(GUILDS);
+
+            for guild in 0..GUILDS {
+                let msg = format!(r#"{{"op": 6, "d": {{"type": 16, "data": {{"channel_id": "1", "guild_id": "{}"}}}}}}"#, guild + 1);
+                sockets.push((identify(&addr).await, msg));
+            }
+
+            sockets
+        });
+
+        group.bench_function(format!("channel_exists_req/{}_shards", shards), |b| {
+            b.iter_custom(|iters| runtime.block_on(async {
+                let start = Instant::now();
+
+                for _ in 0..iters {
+                    futures_util::future::join_all(sockets.iter_mut().map(|(socket, msg)| async move {
+                        socket.send(Message::Text(msg.clone())).await.unwrap();
+                        socket.next().await.unwrap().unwrap();
+                    })).await;
+                }
+
+                start.elapsed()
+            }))
+        });
+    }
+
+    group.finish();
+}
+
+/// Connect to the server at `addr` and IDENTIFY
+async fn identify(addr: &str) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+
+    let hello: serde_json::Value = serde_json::from_str(socket.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    let nonce = hello["d"]["nonce"].as_str().unwrap();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+    mac.update(nonce.as_bytes());
+    let token = hex::encode(mac.finalize().into_bytes());
+
+    socket.send(Message::Text(serde_json::json!({"op": 1, "d": {"token": token}}).to_string())).await.unwrap();
+
+    let ready: serde_json::Value = serde_json::from_str(socket.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(ready["op"], 3, "IDENTIFY failed: {}", ready);
+
+    socket
+}
+
+criterion_group!(benches, decode, encode, e2e, e2e_pipelined, e2e_guilds);
+criterion_main!(benches);
@@ -0,0 +1,120 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+/// Lifecycle event logged as JSON under the `audit` target, for security
+/// auditing.
+///
+/// The fields of each event are part of a stable schema, so only add to them.
+/// Never put tokens or secrets in here.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent<'a> {
+    /// A connection was accepted
+    ConnectionOpened {
+        conn_id: &'a str,
+        peer: &'a str
+    },
+
+    /// A connection IDENTIFYed or RESUMEd
+    IdentifySucceeded {
+        conn_id: &'a str,
+        session_id: &'a str,
+        admin: bool,
+        resumed: bool
+    },
+
+    /// A connection failed to IDENTIFY or RESUME
+    IdentifyFailed {
+        conn_id: &'a str,
+        reason: &'a str,
+        resumed: bool
+    },
+
+    /// A channel was created
+    ChannelCreated {
+        conn_id: &'a str,
+        channel: &'a str
+    },
+
+    /// A channel was destroyed, `conn_id` isn't there if it was cleaned up
+    /// after its connection went away
+    ChannelDestroyed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        conn_id: Option<&'a str>,
+        channel: &'a str,
+        reason: &'a str
+    },
+
+    /// A voice state was created
+    VoiceStateCreated {
+        conn_id: &'a str,
+        session_id: &'a str,
+        channel: &'a str
+    },
+
+    /// A voice state was destroyed, `conn_id` isn't there if it was cleaned up
+    /// after its connection went away
+    VoiceStateDestroyed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        conn_id: Option<&'a str>,
+        session_id: &'a str,
+        reason: &'a str
+    },
+
+    /// A connection was closed
+    ConnectionClosed {
+        conn_id: &'a str,
+        reason: &'a str
+    }
+}
+
+/// Audit event along with when it happened
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    /// Unix timestamp in milliseconds
+    ts: u64,
+
+    #[serde(flatten)]
+    event: &'a AuditEvent<'a>
+}
+
+impl AuditEvent<'_> {
+    /// Log the event
+    pub fn emit(&self) {
+        let record = AuditRecord {
+            ts: now_millis(),
+            event: self
+        };
+
+        info!(target: "audit", "{}", serde_json::to_string(&record).unwrap());
+    }
+
+    /// Fields of an IDENTIFY or RESUME attempt as recorded to the auth audit
+    /// stream, None for the other events
+    pub fn auth_attempt_fields(&self) -> Option<Vec<(&'static str, String)>> {
+        let op = |resumed: bool| if resumed { "resume" } else { "identify" }.to_string();
+
+        match self {
+            AuditEvent::IdentifySucceeded { conn_id, admin, resumed, .. } => Some(vec![
+                ("ts", now_millis().to_string()),
+                ("op", op(*resumed)),
+                ("outcome", "success".to_string()),
+                ("conn_id", conn_id.to_string()),
+                ("admin", admin.to_string())
+            ]),
+            AuditEvent::IdentifyFailed { conn_id, reason, resumed } => Some(vec![
+                ("ts", now_millis().to_string()),
+                ("op", op(*resumed)),
+                ("outcome", "failure".to_string()),
+                ("conn_id", conn_id.to_string()),
+                ("reason", reason.to_string())
+            ]),
+            _ => None
+        }
+    }
+}
+
+/// Unix timestamp in milliseconds
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0)
+}
@@ -0,0 +1,51 @@
+use tokio::net::TcpListener;
+use crate::config::Config;
+use crate::listener::Listener;
+use crate::redis::check_redis;
+
+/// Report on the configuration, reach Redis and bind the listen addresses
+/// without serving anything, printing how each step went
+///
+/// Gives whether every step passed, steps after a failed config are skipped.
+pub async fn run(config: Result<Config, String>) -> bool {
+    let mut passed = true;
+
+    let config = match config {
+        Ok(config) => {
+            report("config", Ok(()));
+            config
+        },
+        Err(e) => {
+            report("config", Err(e));
+            println!("Check failed!");
+
+            return false;
+        }
+    };
+
+    passed &= report("redis", check_redis(&config).map_err(|e| e.to_string()));
+
+    let listener = Listener::bind(&config.listen_addr).await;
+    if let Ok(listener) = &listener {
+        listener.cleanup();
+    }
+    passed &= report(&format!("listen {}", &config.listen_addr), listener.map(|_| ()).map_err(|e| e.to_string()));
+
+    if let Some(metrics_addr) = &config.metrics_addr {
+        let listener = TcpListener::bind(metrics_addr).await;
+        passed &= report(&format!("metrics {}", metrics_addr), listener.map(|_| ()).map_err(|e| e.to_string()));
+    }
+
+    println!("{}", if passed { "Check passed!" } else { "Check failed!" });
+
+    passed
+}
+
+fn report(step: &str, result: Result<(), String>) -> bool {
+    match &result {
+        Ok(()) => println!("[ OK ] {}", step),
+        Err(e) => println!("[FAIL] {}: {}", step, e)
+    }
+
+    result.is_ok()
+}
@@ -0,0 +1,183 @@
+//! Coordination between voice servers sharing the same Redis
+//!
+//! Every node publishes the lifecycle of its channels on the `lvsp_events`
+//! pub/sub channel as JSON, tagged by `event`:
+//!
+//! ```json
+//! {"event": "channel_assigned", "node": "a1b2c3d4e5f6g7h8", "channel": "1234_5678_voice"}
+//! {"event": "channel_destroyed", "node": "a1b2c3d4e5f6g7h8", "channel": "1234_5678_voice"}
+//! {"event": "channel_migrating", "node": "a1b2c3d4e5f6g7h8", "channel": "1234_5678_voice"}
+//! ```
+//!
+//! and subscribes to it to know which node owns which channel.
+//!
+//! While draining, a node publishes `channel_migrating` for each of its
+//! channels and lets go of them once another node assigns them.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use ::redis::{Client, Commands, Connection, RedisResult};
+use serde::{Serialize, Deserialize};
+use crate::connections::{Connections, PendingCleanups};
+use crate::metrics::METRICS;
+use crate::health::WARMED_UP;
+
+/// Pub/sub channel the events are published on
+pub const EVENTS_CHANNEL: &str = "lvsp_events";
+
+/// How long to wait before subscribing again after losing the subscription
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Whether this node is draining, it doesn't take new channels while it is
+pub static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Channel lifecycle event shared between nodes
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    /// A channel was assigned on `node`
+    ChannelAssigned { node: String, channel: String },
+
+    /// A channel owned by `node` was destroyed
+    ChannelDestroyed { node: String, channel: String },
+
+    /// `node` is draining and wants the channel assigned somewhere else
+    ChannelMigrating { node: String, channel: String }
+}
+
+/// Node owning each known channel, by channel key
+pub type ChannelIndex = Arc<Mutex<HashMap<String, String>>>;
+
+impl ClusterEvent {
+    /// Publish the event to the other nodes, failing to is only logged as the
+    /// channel itself is already in Redis
+    pub fn publish(&self, redis: &mut Connection) {
+        let payload = serde_json::to_string(self).unwrap();
+        let result: RedisResult<i64> = redis.publish(EVENTS_CHANNEL, payload);
+
+        if let Err(e) = result {
+            warn!(target: "cluster", "Failed to publish {:?}: {}", self, e);
+        }
+    }
+
+    fn apply(self, node_id: &str, index: &ChannelIndex, connections: &Connections, pending_cleanups: &PendingCleanups) {
+        match self {
+            ClusterEvent::ChannelAssigned { node, channel } => {
+                if node != node_id {
+                    debug!(target: "cluster", "Channel {} assigned on node {}", &channel, &node);
+
+                    if DRAINING.load(Ordering::Relaxed) {
+                        release(&channel, &node, connections, pending_cleanups);
+                    }
+                }
+
+                index.lock().unwrap().insert(channel, node);
+            },
+            ClusterEvent::ChannelDestroyed { node, channel } => {
+                if node != node_id {
+                    debug!(target: "cluster", "Channel {} destroyed on node {}", &channel, &node);
+                }
+
+                index.lock().unwrap().remove(&channel);
+            },
+            ClusterEvent::ChannelMigrating { node, channel } => {
+                if node != node_id {
+                    debug!(target: "cluster", "Channel {} migrating off node {}", &channel, &node);
+                }
+            }
+        }
+    }
+}
+
+/// Stop taking new channels and announce every channel of this node as
+/// migrating, so Litecord can assign them on another node
+///
+/// Draining again announces the channels that weren't taken over yet again.
+pub fn drain(client: &Client, node_id: &str, connections: &Connections, pending_cleanups: &PendingCleanups) {
+    DRAINING.store(true, Ordering::Relaxed);
+
+    let mut channels: Vec<String> = connections.iter()
+        .flat_map(|connection| connection.channels.iter().cloned().collect::<Vec<_>>())
+        .collect();
+
+    channels.extend(
+        pending_cleanups.lock().unwrap()
+            .values()
+            .flat_map(|cleanup| cleanup.channels.iter().cloned())
+    );
+
+    info!(target: "cluster", "Draining, migrating {} channels off node {}!", channels.len(), node_id);
+
+    let mut redis = match client.get_connection() {
+        Ok(redis) => redis,
+        Err(e) => {
+            error!(target: "cluster", "Failed to get Redis connection, no channel was announced: {}", e);
+            return;
+        }
+    };
+
+    for channel in channels {
+        ClusterEvent::ChannelMigrating { node: node_id.to_string(), channel }.publish(&mut redis);
+        METRICS.drain_notices.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Let go of a channel another node took over, so it isn't removed from Redis
+/// when the connection that created it here goes away
+fn release(channel: &str, node: &str, connections: &Connections, pending_cleanups: &PendingCleanups) {
+    let mut released = false;
+
+    for mut connection in connections.iter_mut() {
+        released |= connection.channels.remove(channel);
+    }
+
+    for cleanup in pending_cleanups.lock().unwrap().values_mut() {
+        released |= cleanup.channels.remove(channel);
+    }
+
+    if released {
+        info!(target: "cluster", "Channel {} migrated to node {}!", channel, node);
+        METRICS.migrated_channels.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Keep the index up to date with the events of every node, including this
+/// one
+///
+/// The redis crate's pub/sub is blocking, so this runs on its own thread and
+/// subscribes again whenever the subscription is lost.
+pub fn subscribe(client: Client, node_id: String, index: ChannelIndex, connections: Connections, pending_cleanups: PendingCleanups) {
+    thread::Builder::new()
+        .name("cluster".to_string())
+        .spawn(move || loop {
+            if let Err(e) = listen(&client, &node_id, &index, &connections, &pending_cleanups) {
+                warn!(target: "cluster", "Lost the {} subscription, subscribing again: {}", EVENTS_CHANNEL, e);
+            }
+
+            thread::sleep(RESUBSCRIBE_DELAY);
+        })
+        .expect("Failed to spawn cluster subscriber!");
+}
+
+fn listen(client: &Client, node_id: &str, index: &ChannelIndex, connections: &Connections, pending_cleanups: &PendingCleanups) -> RedisResult<()> {
+    let mut connection = client.get_connection()?;
+    let mut pubsub = connection.as_pubsub();
+    pubsub.subscribe(EVENTS_CHANNEL)?;
+
+    info!(target: "cluster", "Subscribed to {} as node {}!", EVENTS_CHANNEL, node_id);
+
+    if !WARMED_UP.swap(true, Ordering::Relaxed) {
+        info!("Warmed up, taking connections!");
+    }
+
+    loop {
+        let payload: String = pubsub.get_message()?.get_payload()?;
+
+        match serde_json::from_str::<ClusterEvent>(&payload) {
+            Ok(event) => event.apply(node_id, index, connections, pending_cleanups),
+            Err(e) => debug!(target: "cluster", "Ignoring unknown event {}: {}", &payload, e)
+        }
+    }
+}
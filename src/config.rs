@@ -0,0 +1,618 @@
+use std::{env, fs};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+use crate::util::generate_token;
+use crate::voice::{UnknownVoiceMode, VoiceMode};
+use redis::{ConnectionInfo, IntoConnectionInfo};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+/// Region advertised when REGION isn't set
+const DEFAULT_REGION: &str = "unknown";
+
+/// Encryption modes used when ENCRYPTION_MODES isn't set
+const DEFAULT_ENCRYPTION_MODES: &str = "xsalsa20_poly1305_lite,xsalsa20_poly1305_suffix,xsalsa20_poly1305";
+
+/// Settings that can be given in CONFIG_FILE, every environment variable read
+/// by the config and the logging
+const FILE_SETTINGS: &[&str] = &[
+    "ADMIN_SECRET", "AUTH_AUDIT_MAX_LEN", "AUTH_AUDIT_STREAM", "CAPACITY",
+    "CHANNEL_TOKEN_TTL", "ENCRYPTION_MODES", "GUILD_CHANNEL_BURST",
+    "GUILD_CHANNEL_RATE", "HEARTBEAT_INTERVAL", "HEARTBEAT_JITTER",
+    "HEARTBEAT_TOLERANCE", "IDENTIFY_TIMEOUT", "INFO_CONCURRENCY", "LISTEN_ADDR", "LOG_RAW_FRAMES",
+    "LOG_FORMAT", "LOG_UNKNOWN_FIELDS", "MAX_BUSY_REPLIES", "MAX_CONNECTIONS", "MAX_FRAME_SIZE",
+    "MAX_IN_FLIGHT", "MAX_MESSAGE_SIZE", "MAX_PRE_AUTH_VIOLATIONS", "MAX_SESSION_CHANNELS",
+    "MAX_SESSION_VOICE_STATES", "MAX_STRING_LENGTH", "METRICS_ADDR", "NODE_ID",
+    "OUTBOUND_QUEUE_SIZE", "QUIET_PRE_AUTH", "REDIS_ADDR",
+    "REDIS_CONNECT_TIMEOUT", "REDIS_DB", "REDIS_MAX_ATTEMPTS",
+    "REDIS_MAX_RETRY_DELAY", "REDIS_PASSWORD", "REDIS_PING_INTERVAL",
+    "REDIS_RETRY_DELAY", "REDIS_SHARDS", "REDIS_TLS", "REDIS_TLS_CA", "REDIS_USERNAME",
+    "REGION", "REQUIRE_TLS", "RESUME_ENDPOINT", "RUST_LOG", "SECRET", "SECRET_FILE",
+    "SESSION_GRACE_PERIOD", "SHED_THRESHOLD", "UNAMBIGUOUS_TOKENS"
+];
+
+/// Settings the config is read from, in order of precedence: the environment
+/// of the process, `.env`, then the TOML file at CONFIG_FILE
+///
+/// Read once into memory, the environment of the process is never written to.
+/// A setting that's set but empty counts as unset.
+#[derive(Default)]
+pub struct Settings {
+    values: HashMap<String, String>,
+
+    /// CONFIG_FILE the settings were read from, if any
+    pub config_file: Option<String>
+}
+
+impl Settings {
+    /// Read the environment, `.env` and CONFIG_FILE (which can be named in
+    /// either of the first two)
+    ///
+    /// Fails if the file can't be read or parsed, or has a setting that
+    /// doesn't exist.
+    pub fn load() -> Result<Settings, String> {
+        let env: HashMap<String, String> = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+
+        // The replacement dotenv suggests loads into the environment, which
+        // is what this avoids
+        #[allow(deprecated)]
+        let dotenv: HashMap<String, String> = match dotenv::dotenv_iter() {
+            Ok(vars) => vars.collect::<Result<_, _>>().map_err(|e| format!("Failed to read .env: {}", e))?,
+            Err(_) => HashMap::new()
+        };
+
+        Settings::layered(env, dotenv)
+    }
+
+    /// Settings of `env` over `dotenv` over the CONFIG_FILE either names
+    fn layered(env: HashMap<String, String>, dotenv: HashMap<String, String>) -> Result<Settings, String> {
+        let mut settings = Settings::from_pairs(dotenv);
+        settings.values.extend(env.into_iter().filter(|(_, value)| !value.is_empty()));
+
+        if let Some(path) = settings.get("CONFIG_FILE").map(str::to_string) {
+            for (name, value) in read_config_file(&path)? {
+                settings.values.entry(name).or_insert(value);
+            }
+
+            settings.config_file = Some(path);
+        }
+
+        Ok(settings)
+    }
+
+    /// Settings made of the given pairs only, without reading the environment,
+    /// e.g. for tests
+    pub fn from_pairs<I: IntoIterator<Item = (K, V)>, K: Into<String>, V: Into<String>>(pairs: I) -> Settings {
+        Settings {
+            values: pairs.into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .filter(|(_, value)| !value.is_empty())
+                .collect(),
+            config_file: None
+        }
+    }
+
+    /// Value of a setting, None if it's unset or empty
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// Parse a setting, `default` if it's unset, failing if it's set to
+    /// something that doesn't parse
+    fn parse<T: FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        self.parse_checked(name, default, |_| true)
+    }
+
+    /// Parse a setting, `default` if it's unset, failing if it's set to
+    /// something that doesn't parse or that `valid` refuses
+    fn parse_checked<T: FromStr>(&self, name: &str, default: T, valid: impl Fn(&T) -> bool) -> Result<T, String> {
+        match self.get(name) {
+            Some(value) => value.trim().parse::<T>().ok()
+                .filter(|value| valid(value))
+                .ok_or_else(|| format!("Invalid {}: {}", name, value)),
+            None => Ok(default)
+        }
+    }
+
+    /// Read a boolean flag, `1`/`true` being on and `0`/`false` (or unset) off
+    fn flag(&self, name: &str) -> Result<bool, String> {
+        match self.get(name).map(str::trim) {
+            Some("1") | Some("true") => Ok(true),
+            Some("0") | Some("false") | None => Ok(false),
+            Some(value) => Err(format!("Invalid {}: {}, expected true or false", name, value))
+        }
+    }
+}
+
+/// Read the settings of the TOML file at `path`, named like the environment
+/// variables but in lowercase
+///
+/// Arrays are joined with commas, the way ENCRYPTION_MODES is given. Fails if
+/// the file can't be read or parsed, or has a setting that doesn't exist.
+fn read_config_file(path: &str) -> Result<Vec<(String, String)>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read CONFIG_FILE {}: {}", path, e))?;
+    let settings: toml::value::Table = toml::from_str(&contents).map_err(|e| format!("Failed to parse CONFIG_FILE {}: {}", path, e))?;
+
+    settings.into_iter()
+        .map(|(key, value)| {
+            let name = key.to_uppercase();
+
+            if !FILE_SETTINGS.contains(&name.as_str()) {
+                return Err(format!("Unknown setting {} in CONFIG_FILE {}!", key, path));
+            }
+
+            let value = setting_value(&value)
+                .ok_or_else(|| format!("Setting {} in CONFIG_FILE {} isn't a string, number, boolean or array of those!", key, path))?;
+
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn setting_value(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        toml::Value::Array(values) => values.iter()
+            .map(|value| match value {
+                toml::Value::Array(_) => None,
+                value => setting_value(value)
+            })
+            .collect::<Option<Vec<String>>>()
+            .map(|values| values.join(",")),
+        _ => None
+    }
+}
+
+/// Secrets connections authenticate with
+pub struct Secrets {
+    /// Shared secret, must be the same on Litecord
+    pub secret: String,
+
+    /// Secret for admin connections, admin connections are disabled if unset
+    /// or blank
+    pub admin_secret: Option<String>
+}
+
+impl Secrets {
+    /// Read the secrets from the settings, SECRET_FILE taking precedence over
+    /// SECRET, fails if neither is set or the secret is empty
+    pub fn from_settings(settings: &Settings) -> Result<Secrets, String> {
+        let secret = match settings.get("SECRET_FILE") {
+            Some(path) => read_secret_file(path)?,
+            None => settings.get("SECRET").map(str::to_string).ok_or_else(|| "No secret present in environment, set SECRET or SECRET_FILE!".to_string())?
+        };
+
+        // An empty key still makes a valid HMAC, just one anyone can forge
+        if secret.trim().is_empty() {
+            return Err("The secret is empty, refusing to run without authentication!".to_string());
+        }
+
+        Ok(Secrets {
+            secret,
+            admin_secret: settings.get("ADMIN_SECRET").filter(|secret| !secret.trim().is_empty()).map(str::to_string)
+        })
+    }
+}
+
+/// Read a secret mounted as a file, without the trailing newline most editors
+/// and `echo` leave
+fn read_secret_file(path: &str) -> Result<String, String> {
+    let secret = fs::read_to_string(path).map_err(|e| format!("Failed to read SECRET_FILE {}: {}", path, e))?;
+    let secret = secret.trim_end_matches(&['\r', '\n'][..]);
+
+    if secret.trim().is_empty() {
+        return Err(format!("SECRET_FILE {} is empty, refusing to run without authentication!", path));
+    }
+
+    Ok(secret.to_string())
+}
+
+/// Server configuration, read from the [`Settings`]
+pub struct Config {
+    /// Listen address of the websocket
+    pub listen_addr: String,
+
+    /// Refuse to listen in plaintext, the websocket isn't served over TLS by
+    /// this server so only a Unix socket behind a TLS-terminating proxy is
+    /// allowed
+    pub require_tls: bool,
+
+    /// Secrets connections authenticate with, reloaded on SIGHUP
+    pub secrets: RwLock<Secrets>,
+
+    /// ID of this node in the events shared with the other nodes
+    pub node_id: String,
+
+    /// Region this node serves, advertised in CHANNEL_ASSIGN and SERVER_INFO
+    pub region: String,
+
+    /// URL clients reach this node at, sent in READY so they RESUME on the
+    /// node holding their session, unset for single node deployments
+    pub resume_endpoint: Option<String>,
+
+    /// Heartbeat interval sent in HELLO
+    pub heartbeat_interval: i32,
+
+    /// How far (as a fraction of the interval) the interval sent in HELLO can
+    /// be randomized from `heartbeat_interval`, 0 always sends it as is
+    pub heartbeat_jitter: f32,
+
+    /// How far (as a fraction of the interval) the time between two heartbeats
+    /// can be from the heartbeat interval before it's warned about
+    pub heartbeat_tolerance: f32,
+
+    /// Listen address of the metrics HTTP server, not served if unset
+    pub metrics_addr: Option<String>,
+
+    /// Redis database URL, parsed, including the password and database index
+    /// if the URL has them
+    pub redis_addr: ConnectionInfo,
+
+    /// Redis username, overrides the one in the URL
+    pub redis_username: Option<String>,
+
+    /// Redis password, overrides the one in the URL
+    pub redis_password: Option<String>,
+
+    /// Redis database index, overrides the one in the URL
+    pub redis_db: Option<u16>,
+
+    /// Connect to Redis over TLS
+    pub redis_tls: bool,
+
+    /// CA bundle to verify the Redis server with, uses the system one if unset
+    pub redis_tls_ca: Option<String>,
+
+    /// How long to keep retrying to reach Redis at startup before giving up
+    pub redis_connect_timeout: Duration,
+
+    /// How often Redis is pinged to notice it going away
+    pub redis_ping_interval: Duration,
+
+    /// Redis connections the connections' Redis work is spread over, by guild
+    pub redis_shards: usize,
+
+    /// Wait before the first retry to reach Redis at startup, doubled after
+    /// every failed attempt
+    pub redis_retry_delay: Duration,
+
+    /// Longest wait between two attempts to reach Redis at startup
+    pub redis_max_retry_delay: Duration,
+
+    /// Most attempts to reach Redis at startup, 0 keeps trying until
+    /// `redis_connect_timeout`
+    pub redis_max_attempts: u32,
+
+    /// Amount of connections at which health reaches 0
+    pub capacity: usize,
+
+    /// Most connections handled at once, accepting waits while there are this
+    /// many
+    pub max_connections: usize,
+
+    /// Health under which new connections are turned away, 0 never sheds
+    pub shed_threshold: f32,
+
+    /// Encryption modes the voice server supports, most preferred first
+    pub encryption_modes: Vec<VoiceMode>,
+
+    /// How long the state of a dropped connection is kept around for it to
+    /// RESUME before being cleaned up
+    pub session_grace_period: Duration,
+
+    /// How long channel tokens are valid for, None if they last as long as
+    /// their channel
+    pub channel_token_ttl: Option<Duration>,
+
+    /// How long a connection has to IDENTIFY before it's closed
+    pub identify_timeout: Duration,
+
+    /// Don't answer messages sent before IDENTIFY, only count them
+    pub quiet_pre_auth: bool,
+
+    /// Messages sent before IDENTIFY (other than IDENTIFY and RESUME) after
+    /// which the connection is closed, 0 (the default) never closes it
+    pub max_pre_auth_violations: usize,
+
+    /// Most messages queued for a connection by other connections, it's closed
+    /// once the queue is full
+    pub outbound_queue_size: usize,
+
+    /// INFO requests of a connection handled at once, their replies still go
+    /// out in the order the requests came in
+    pub info_concurrency: usize,
+
+    /// Most INFO requests of a connection waiting for a reply, more get a BUSY
+    /// error
+    pub max_in_flight: usize,
+
+    /// Most BUSY errors of a connection waiting for their turn to go out, past
+    /// that it isn't read until replies went out
+    pub max_busy_replies: usize,
+
+    /// Most channels a session can own at once, 0 is no limit
+    pub max_session_channels: usize,
+
+    /// Most voice states a session can own at once, 0 is no limit
+    pub max_session_voice_states: usize,
+
+    /// Channel operations (CHANNEL_REQ and CHANNEL_DESTROY) allowed per second
+    /// in a guild, 0 is no limit
+    pub guild_channel_rate: f64,
+
+    /// Channel operations a guild can do at once before being held to
+    /// `guild_channel_rate`
+    pub guild_channel_burst: u32,
+
+    /// Longest string (in bytes) accepted in INFO data
+    pub max_string_length: usize,
+
+    /// Biggest message (in bytes) accepted from a client, fragmented or not
+    pub max_message_size: usize,
+
+    /// Biggest single frame (in bytes) accepted from a client
+    pub max_frame_size: usize,
+
+    /// Generate tokens and IDs from an alphabet without easily confused
+    /// characters instead of alphanumerics
+    pub unambiguous_tokens: bool,
+
+    /// Log every inbound and outbound frame at trace, off by default since
+    /// frames carry tokens (which are redacted, but still)
+    pub log_raw_frames: bool,
+
+    /// Log the top-level fields of inbound messages that aren't part of the
+    /// protocol at debug, they're still ignored either way
+    pub log_unknown_fields: bool,
+
+    /// Redis stream IDENTIFY and RESUME attempts are recorded to, unset
+    /// doesn't record them
+    pub auth_audit_stream: Option<String>,
+
+    /// Entries the auth audit stream is trimmed to, roughly
+    pub auth_audit_max_len: usize
+}
+
+impl Config {
+    /// Build the config from the settings, failing with the reason if any of
+    /// them is invalid, they're all checked here once
+    pub fn from_settings(settings: &Settings) -> Result<Config, String> {
+        let secrets = Secrets::from_settings(settings)?;
+
+        let max_message_size = settings.parse_checked("MAX_MESSAGE_SIZE", 65536, |size: &usize| *size > 0)?;
+        let max_frame_size = settings.parse_checked("MAX_FRAME_SIZE", 16384, |size: &usize| *size > 0)?;
+
+        // A frame that big could never be accepted as part of a message
+        if max_message_size < max_frame_size {
+            return Err(format!("MAX_MESSAGE_SIZE ({}) is smaller than MAX_FRAME_SIZE ({})!", max_message_size, max_frame_size));
+        }
+
+        let listen_addr = settings.get("LISTEN_ADDR").unwrap_or("0.0.0.0:3621").to_string();
+        let require_tls = settings.flag("REQUIRE_TLS")?;
+
+        // Tokens and secrets would go over the network in the clear
+        if require_tls && !listen_addr.starts_with("unix:") {
+            return Err(format!("REQUIRE_TLS is set but LISTEN_ADDR ({}) isn't a Unix socket, the websocket would be served in plaintext!", listen_addr));
+        }
+
+        Ok(Config {
+            listen_addr,
+            require_tls,
+            secrets: RwLock::new(secrets),
+            node_id: settings.get("NODE_ID").map(str::to_string).unwrap_or_else(|| generate_token(16, false)),
+            region: settings.get("REGION").filter(|region| !region.trim().is_empty()).map(str::to_string).unwrap_or_else(|| {
+                warn!("REGION isn't set, advertising the region as {}", DEFAULT_REGION);
+                DEFAULT_REGION.to_string()
+            }),
+            resume_endpoint: settings.get("RESUME_ENDPOINT").filter(|url| !url.trim().is_empty()).map(str::to_string),
+            heartbeat_interval: settings.parse_checked("HEARTBEAT_INTERVAL", 1, |interval: &i32| *interval > 0)?,
+            heartbeat_jitter: settings.parse_checked("HEARTBEAT_JITTER", 0.0, |jitter: &f32| (0.0..=1.0).contains(jitter))?,
+            heartbeat_tolerance: settings.parse_checked("HEARTBEAT_TOLERANCE", 0.5, |tolerance: &f32| *tolerance >= 0.0)?,
+            metrics_addr: settings.get("METRICS_ADDR").map(str::to_string),
+            redis_addr: parse_redis_addr(settings.get("REDIS_ADDR").unwrap_or("redis://127.0.0.1:6379"))?,
+            redis_username: settings.get("REDIS_USERNAME").map(str::to_string),
+            redis_password: settings.get("REDIS_PASSWORD").map(str::to_string),
+            redis_db: settings.get("REDIS_DB")
+                .map(|db| db.trim().parse::<u16>().map_err(|_| format!("REDIS_DB isn't a database index: {}", db)))
+                .transpose()?,
+            redis_tls: settings.flag("REDIS_TLS")?,
+            redis_tls_ca: settings.get("REDIS_TLS_CA").map(str::to_string),
+            redis_connect_timeout: Duration::from_secs(settings.parse("REDIS_CONNECT_TIMEOUT", 30)?),
+            redis_ping_interval: Duration::from_secs(settings.parse_checked("REDIS_PING_INTERVAL", 5, |interval: &u64| *interval > 0)?),
+            redis_shards: settings.parse_checked("REDIS_SHARDS", 4, |shards: &usize| *shards > 0)?,
+            redis_retry_delay: Duration::from_millis(settings.parse_checked("REDIS_RETRY_DELAY", 100, |delay: &u64| *delay > 0)?),
+            redis_max_retry_delay: Duration::from_millis(settings.parse_checked("REDIS_MAX_RETRY_DELAY", 5000, |delay: &u64| *delay > 0)?),
+            redis_max_attempts: settings.parse("REDIS_MAX_ATTEMPTS", 0)?,
+            capacity: settings.parse_checked("CAPACITY", 1000, |capacity: &usize| *capacity > 0)?,
+            max_connections: settings.parse_checked("MAX_CONNECTIONS", 10000, |max_connections: &usize| *max_connections > 0)?,
+            shed_threshold: settings.parse_checked("SHED_THRESHOLD", 0.0, |threshold: &f32| (0.0..=1.0).contains(threshold))?,
+            encryption_modes: parse_encryption_modes(settings.get("ENCRYPTION_MODES").unwrap_or(DEFAULT_ENCRYPTION_MODES))?,
+            session_grace_period: Duration::from_secs(settings.parse("SESSION_GRACE_PERIOD", 30)?),
+            channel_token_ttl: Some(settings.parse("CHANNEL_TOKEN_TTL", 0)?)
+                .filter(|ttl| *ttl > 0)
+                .map(Duration::from_secs),
+            identify_timeout: Duration::from_secs(settings.parse_checked("IDENTIFY_TIMEOUT", 10, |timeout: &u64| *timeout > 0)?),
+            quiet_pre_auth: settings.flag("QUIET_PRE_AUTH")?,
+            max_pre_auth_violations: settings.parse("MAX_PRE_AUTH_VIOLATIONS", 0)?,
+            outbound_queue_size: settings.parse_checked("OUTBOUND_QUEUE_SIZE", 64, |size: &usize| *size > 0)?,
+            info_concurrency: settings.parse_checked("INFO_CONCURRENCY", 1, |concurrency: &usize| *concurrency > 0)?,
+            max_in_flight: settings.parse_checked("MAX_IN_FLIGHT", 64, |max: &usize| *max > 0)?,
+            max_busy_replies: settings.parse_checked("MAX_BUSY_REPLIES", 64, |max: &usize| *max > 0)?,
+            max_session_channels: settings.parse("MAX_SESSION_CHANNELS", 0)?,
+            max_session_voice_states: settings.parse("MAX_SESSION_VOICE_STATES", 0)?,
+            guild_channel_rate: settings.parse_checked("GUILD_CHANNEL_RATE", 10.0, |rate: &f64| rate.is_finite() && *rate >= 0.0)?,
+            guild_channel_burst: settings.parse_checked("GUILD_CHANNEL_BURST", 20, |burst: &u32| *burst > 0)?,
+            max_string_length: settings.parse("MAX_STRING_LENGTH", 128)?,
+            max_message_size,
+            max_frame_size,
+            unambiguous_tokens: settings.flag("UNAMBIGUOUS_TOKENS")?,
+            log_raw_frames: settings.flag("LOG_RAW_FRAMES")?,
+            log_unknown_fields: settings.flag("LOG_UNKNOWN_FIELDS")?,
+            auth_audit_stream: settings.get("AUTH_AUDIT_STREAM").map(str::to_string),
+            auth_audit_max_len: settings.parse_checked("AUTH_AUDIT_MAX_LEN", 10000, |max_len: &usize| *max_len > 0)?
+        })
+    }
+}
+
+impl Config {
+    /// Read the secrets again from the environment, `.env` and CONFIG_FILE,
+    /// SECRET_FILE included, keeping the current ones if the new ones are
+    /// invalid
+    ///
+    /// The environment still wins over `.env` and the file, nothing is written
+    /// to it. Only new IDENTIFYs and RESUMEs use the new secrets, connections
+    /// already identified stay up.
+    pub fn reload_secrets(&self) {
+        match Settings::load().and_then(|settings| Secrets::from_settings(&settings)) {
+            Ok(secrets) => {
+                *self.secrets.write().unwrap() = secrets;
+                info!("Reloaded the secrets!");
+            },
+            Err(e) => error!("Failed to reload the secrets, keeping the current ones: {}", e)
+        }
+    }
+
+    /// Limits of the websocket connections
+    pub fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: Some(self.max_message_size),
+            max_frame_size: Some(self.max_frame_size),
+            ..WebSocketConfig::default()
+        }
+    }
+}
+
+/// Parse a `redis://[[username]:password@]host[:port][/db]` (or `rediss://`,
+/// `unix://`) URL
+fn parse_redis_addr(addr: &str) -> Result<ConnectionInfo, String> {
+    // The error doesn't include the URL, which might have a password in it
+    addr.into_connection_info()
+        .map_err(|e| format!("REDIS_ADDR isn't a valid redis://[:password@]host:port[/db] URL: {}", e))
+}
+
+/// Parse a comma separated list of encryption modes
+fn parse_encryption_modes(modes: &str) -> Result<Vec<VoiceMode>, String> {
+    let modes = modes.split(',')
+        .map(str::trim)
+        .filter(|mode| !mode.is_empty())
+        .map(|mode| mode.parse().map_err(|UnknownVoiceMode(mode)| format!("Unknown encryption mode {} in ENCRYPTION_MODES!", mode)))
+        .collect::<Result<Vec<VoiceMode>, String>>()?;
+
+    if modes.is_empty() {
+        return Err("ENCRYPTION_MODES is empty, no channel could ever be assigned!".to_string());
+    }
+
+    Ok(modes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pairs: &[(&str, &str)]) -> Result<Config, String> {
+        let settings = Settings::from_pairs(pairs.iter().copied().chain([("SECRET", "hunter2")]));
+        Config::from_settings(&settings)
+    }
+
+    #[test]
+    fn defaults_when_unset() {
+        let config = config(&[]).unwrap();
+
+        assert_eq!(config.heartbeat_interval, 1);
+        assert_eq!(config.listen_addr, "0.0.0.0:3621");
+        assert_eq!(config.max_frame_size, 16384);
+        assert!(config.channel_token_ttl.is_none());
+        assert_eq!(config.max_pre_auth_violations, 0);
+        assert!(!config.require_tls);
+    }
+
+    #[test]
+    fn empty_counts_as_unset() {
+        assert_eq!(config(&[("HEARTBEAT_INTERVAL", "")]).unwrap().heartbeat_interval, 1);
+    }
+
+    #[test]
+    fn invalid_values_fail() {
+        for (name, value) in [
+            ("HEARTBEAT_INTERVAL", "abc"),
+            ("HEARTBEAT_INTERVAL", "0"),
+            ("HEARTBEAT_JITTER", "2"),
+            ("CAPACITY", "-1"),
+            ("GUILD_CHANNEL_RATE", "inf"),
+            ("REDIS_DB", "zero"),
+            ("REDIS_DB", "-1"),
+            ("REDIS_SHARDS", "0"),
+            ("INFO_CONCURRENCY", "0"),
+            ("MAX_IN_FLIGHT", "0"),
+            ("MAX_BUSY_REPLIES", "0"),
+            ("REQUIRE_TLS", "yes"),
+            ("ENCRYPTION_MODES", "rot13")
+        ] {
+            let error = config(&[(name, value)]).err().unwrap_or_else(|| panic!("{}={} was accepted", name, value));
+            assert!(error.contains(name), "{} doesn't name {}", error, name);
+        }
+    }
+
+    #[test]
+    fn frame_bigger_than_message_fails() {
+        assert!(config(&[("MAX_MESSAGE_SIZE", "1024"), ("MAX_FRAME_SIZE", "2048")]).is_err());
+    }
+
+    #[test]
+    fn missing_secret_fails() {
+        assert!(Config::from_settings(&Settings::default()).is_err());
+        assert!(Config::from_settings(&Settings::from_pairs([("SECRET", "  ")])).is_err());
+    }
+
+    #[test]
+    fn config_file_settings() {
+        let path = env::temp_dir().join(format!("bannana-pho-config-{}.toml", generate_token(8, false)));
+        fs::write(&path, "heartbeat_interval = 5\nrequire_tls = false\nencryption_modes = [\"xsalsa20_poly1305\", \"xsalsa20_poly1305_lite\"]\n").unwrap();
+
+        let mut settings = read_config_file(path.to_str().unwrap()).unwrap();
+        settings.sort();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(settings, vec![
+            ("ENCRYPTION_MODES".to_string(), "xsalsa20_poly1305,xsalsa20_poly1305_lite".to_string()),
+            ("HEARTBEAT_INTERVAL".to_string(), "5".to_string()),
+            ("REQUIRE_TLS".to_string(), "false".to_string())
+        ]);
+    }
+
+    #[test]
+    fn env_wins_over_dotenv_and_file() {
+        let path = env::temp_dir().join(format!("bannana-pho-config-{}.toml", generate_token(8, false)));
+        fs::write(&path, "secret = \"file\"\nadmin_secret = \"file\"\nregion = \"file\"\n").unwrap();
+
+        let pairs = |pairs: &[(&str, &str)]| pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        let settings = Settings::layered(
+            pairs(&[("SECRET", "env"), ("ADMIN_SECRET", "")]),
+            pairs(&[("SECRET", "dotenv"), ("ADMIN_SECRET", "dotenv"), ("CONFIG_FILE", path.to_str().unwrap())])
+        );
+        fs::remove_file(&path).unwrap();
+
+        let settings = settings.unwrap();
+        assert_eq!(settings.get("SECRET"), Some("env"));
+        assert_eq!(settings.get("ADMIN_SECRET"), Some("dotenv"));
+        assert_eq!(settings.get("REGION"), Some("file"));
+        assert_eq!(settings.config_file.as_deref(), path.to_str());
+    }
+
+    #[test]
+    fn config_file_unknown_setting_fails() {
+        let path = env::temp_dir().join(format!("bannana-pho-config-{}.toml", generate_token(8, false)));
+        fs::write(&path, "heartbeat_intervall = 5\n").unwrap();
+
+        let result = read_config_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.unwrap_err().contains("heartbeat_intervall"));
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use dashmap::DashMap;
+use tokio::sync::Notify;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::Request;
+use crate::infoops::SessionInfo;
+
+/// Amount of sessions per SESSION_LIST page
+pub const SESSION_PAGE_SIZE: usize = 50;
+
+/// Longest handshake header value kept, in bytes
+const MAX_HANDSHAKE_HEADER_LENGTH: usize = 256;
+
+/// Something for a connection to do, pushed from outside of its task
+pub enum Outbound {
+    /// Send a message to the peer, the connection is closed after if it's a
+    /// close message
+    Message(Message),
+
+    /// Ask the peer to IDENTIFY again, it's unidentified until it does
+    Reidentify
+}
+
+/// What the client said about itself in the websocket handshake, for
+/// debugging, each value cut to MAX_HANDSHAKE_HEADER_LENGTH bytes
+#[derive(Default, Clone, Debug)]
+pub struct HandshakeInfo {
+    /// User-Agent header
+    pub user_agent: Option<String>,
+
+    /// Sec-WebSocket-Protocol header, the subprotocols the client offered,
+    /// none of them is picked
+    pub subprotocols: Option<String>,
+
+    /// X-Forwarded-Proto header, `https` if the proxy in front got the
+    /// connection over TLS, this server never serves TLS itself
+    pub forwarded_proto: Option<String>
+}
+
+impl HandshakeInfo {
+    pub fn from_request(request: &Request) -> HandshakeInfo {
+        let header = |name: &str| request.headers().get(name)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()))
+            .map(|value| {
+                let mut end = value.len().min(MAX_HANDSHAKE_HEADER_LENGTH);
+
+                while !value.is_char_boundary(end) {
+                    end -= 1;
+                }
+
+                value[..end].to_string()
+            });
+
+        HandshakeInfo {
+            user_agent: header("User-Agent"),
+            subprotocols: header("Sec-WebSocket-Protocol"),
+            forwarded_proto: header("X-Forwarded-Proto")
+        }
+    }
+}
+
+/// A live connection
+pub struct Connection {
+    /// Address of the peer
+    pub peer: String,
+
+    /// Headers of the websocket handshake
+    pub handshake: HandshakeInfo,
+
+    /// Queue of things for the connection to do, bounded by
+    /// `outbound_queue_size`
+    pub sender: Sender<Outbound>,
+
+    /// Notified when the queue is full, the peer isn't reading fast enough
+    /// and gets closed
+    pub too_slow: Arc<Notify>,
+
+    /// When the connection identified, None until it does
+    pub identified_at: Option<SystemTime>,
+
+    /// Heartbeat interval sent in HELLO
+    pub heartbeat_interval: i32,
+
+    /// When the last HEARTBEAT was received
+    ///
+    /// Only kept here, writing it to Redis on every heartbeat isn't worth it
+    /// for something only SESSION_LIST reads.
+    pub last_heartbeat: Option<Instant>,
+
+    /// Session ID given in READY, None until identified
+    pub session_id: Option<String>,
+
+    /// Keys of the channels created by this connection
+    pub channels: HashSet<String>,
+
+    /// Session IDs of the voice states created by this connection
+    pub voice_states: HashSet<String>,
+
+    /// Whether what the connection owns is kept for RESUME once it goes away,
+    /// false once the client closed it normally
+    pub resumable: bool
+}
+
+impl Connection {
+    pub fn new(peer: String, handshake: HandshakeInfo, sender: Sender<Outbound>, too_slow: Arc<Notify>, heartbeat_interval: i32) -> Connection {
+        Connection {
+            peer,
+            handshake,
+            sender,
+            too_slow,
+            heartbeat_interval,
+            identified_at: None,
+            last_heartbeat: None,
+            session_id: None,
+            channels: HashSet::new(),
+            voice_states: HashSet::new(),
+            resumable: true
+        }
+    }
+
+    /// Whether owning `channel_key` would take the connection over `max`
+    /// channels, 0 being no limit
+    pub fn over_channel_quota(&self, channel_key: &str, max: usize) -> bool {
+        max > 0 && self.channels.len() >= max && !self.channels.contains(channel_key)
+    }
+
+    /// Whether one more voice state would take the connection over `max`
+    /// voice states, 0 being no limit
+    pub fn over_voice_state_quota(&self, max: usize) -> bool {
+        max > 0 && self.voice_states.len() >= max
+    }
+}
+
+/// State left behind by a dropped connection, kept until `expires` so the
+/// client can RESUME and take it back
+pub struct PendingCleanup {
+    /// When the grace period runs out
+    pub expires: Instant,
+
+    /// Keys of the channels created by the connection
+    pub channels: HashSet<String>,
+
+    /// Session IDs of the voice states created by the connection
+    pub voice_states: HashSet<String>
+}
+
+/// Pending cleanups by session ID
+pub type PendingCleanups = Arc<Mutex<HashMap<String, PendingCleanup>>>;
+
+/// Live connections by connection ID, used to push messages to a connection from
+/// another one
+///
+/// Sharded so connections coming and going only lock the shard of their own
+/// ID, instead of every other connection waiting on one lock. Shard guards
+/// must never be held across an await.
+pub type Connections = Arc<DashMap<String, Connection>>;
+
+/// Queue something on the given connection, returns false if it isn't
+/// connected to this server or its queue is full
+///
+/// A full queue means the peer stopped reading, the connection is told to
+/// close instead of buffering more for it.
+pub fn send_to(connections: &Connections, conn_id: &str, outbound: Outbound) -> bool {
+    match connections.get(conn_id) {
+        Some(connection) => match connection.sender.try_send(outbound) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                connection.too_slow.notify_one();
+
+                false
+            },
+            Err(TrySendError::Closed(_)) => false
+        },
+        None => false
+    }
+}
+
+/// Update the given connection, if it's still connected
+pub fn update(connections: &Connections, conn_id: &str, f: impl FnOnce(&mut Connection)) {
+    if let Some(mut connection) = connections.get_mut(conn_id) {
+        f(&mut connection);
+    }
+}
+
+/// Stop tracking a channel and voice states that were removed from Redis by
+/// someone else than their owner, so they aren't removed again on cleanup
+///
+/// Gives the live connections that owned any of them.
+pub fn forget(connections: &Connections, pending_cleanups: &PendingCleanups, channel: Option<&str>, voice_states: &[String]) -> Vec<String> {
+    let mut owners = Vec::new();
+
+    for mut connection in connections.iter_mut() {
+        let mut owned = channel.map(|channel| connection.channels.remove(channel)).unwrap_or(false);
+
+        for session_id in voice_states {
+            owned |= connection.voice_states.remove(session_id);
+        }
+
+        if owned {
+            owners.push(connection.key().clone());
+        }
+    }
+
+    for cleanup in pending_cleanups.lock().unwrap().values_mut() {
+        if let Some(channel) = channel {
+            cleanup.channels.remove(channel);
+        }
+
+        for session_id in voice_states {
+            cleanup.voice_states.remove(session_id);
+        }
+    }
+
+    owners
+}
+
+/// List a page of the live connections, sorted by ID, along with the total
+/// amount of pages
+pub fn list(connections: &Connections, page: usize) -> (Vec<SessionInfo>, usize) {
+    let mut ids: Vec<String> = connections.iter().map(|connection| connection.key().clone()).collect();
+    ids.sort();
+
+    let pages = ids.len().div_ceil(SESSION_PAGE_SIZE);
+
+    // Connections that went away since the IDs were taken are left out
+    let sessions = ids.into_iter()
+        .skip(page * SESSION_PAGE_SIZE)
+        .take(SESSION_PAGE_SIZE)
+        .filter_map(|id| {
+            let connection = connections.get(&id)?;
+
+            Some(SessionInfo {
+                peer: connection.peer.clone(),
+                user_agent: connection.handshake.user_agent.clone(),
+                subprotocols: connection.handshake.subprotocols.clone(),
+                forwarded_proto: connection.handshake.forwarded_proto.clone(),
+                identified_at: connection.identified_at
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|time| time.as_secs()),
+                last_heartbeat_ms: connection.last_heartbeat
+                    .map(|time| time.elapsed().as_millis() as u64),
+                heartbeat_interval: connection.heartbeat_interval,
+                channels: connection.channels.iter().cloned().collect(),
+                id
+            })
+        })
+        .collect();
+
+    (sessions, pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use tokio::sync::mpsc;
+
+    const THREADS: usize = 8;
+    const PER_THREAD: usize = 200;
+
+    fn connection() -> (Connection, mpsc::Receiver<Outbound>) {
+        let (sender, receiver) = mpsc::channel(1);
+
+        (Connection::new("127.0.0.1:1".to_string(), HandshakeInfo::default(), sender, Arc::new(Notify::new()), 30), receiver)
+    }
+
+    #[test]
+    fn full_queue_makes_a_connection_too_slow() {
+        let connections: Connections = Arc::new(DashMap::new());
+        let (connection, _receiver) = connection();
+        let too_slow = connection.too_slow.clone();
+        connections.insert("a".to_string(), connection);
+
+        assert!(send_to(&connections, "a", Outbound::Reidentify));
+        assert!(!send_to(&connections, "a", Outbound::Reidentify));
+        assert!(!send_to(&connections, "gone", Outbound::Reidentify));
+
+        // The permit is there for whenever the connection looks
+        let notified = too_slow.notified();
+        futures_util::pin_mut!(notified);
+        assert!(futures_util::FutureExt::now_or_never(notified).is_some());
+    }
+
+    #[test]
+    fn connections_come_and_go_from_many_threads() {
+        let connections: Connections = Arc::new(DashMap::new());
+        let pending_cleanups: PendingCleanups = Arc::new(Mutex::new(HashMap::new()));
+
+        let threads: Vec<_> = (0..THREADS).map(|thread| {
+            let (connections, pending_cleanups) = (connections.clone(), pending_cleanups.clone());
+
+            thread::spawn(move || {
+                let mut receivers = Vec::new();
+
+                for n in 0..PER_THREAD {
+                    let id = format!("{}_{}", thread, n);
+                    let (connection, receiver) = connection();
+                    connections.insert(id.clone(), connection);
+                    receivers.push(receiver);
+
+                    update(&connections, &id, |connection| {
+                        connection.channels.insert("9_shared_voice".to_string());
+                        connection.channels.insert(format!("9_{}_voice", id));
+                        connection.voice_states.insert(id.clone());
+                    });
+
+                    send_to(&connections, &id, Outbound::Reidentify);
+                    list(&connections, n % 4);
+
+                    // Taken from every owner while the others keep adding it
+                    if n % 50 == 0 {
+                        forget(&connections, &pending_cleanups, Some("9_shared_voice"), &[]);
+                    }
+
+                    // Every other one goes away again
+                    if n % 2 == 1 {
+                        connections.remove(&id);
+                    }
+                }
+
+                receivers
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(connections.len(), THREADS * PER_THREAD / 2);
+
+        for connection in connections.iter() {
+            let id = connection.key();
+            assert!(connection.channels.contains(&format!("9_{}_voice", id)), "{} lost its channel", id);
+            assert_eq!(connection.voice_states.len(), 1, "{} has {:?}", id, connection.voice_states);
+        }
+
+        let owners = forget(&connections, &pending_cleanups, Some("9_shared_voice"), &[]);
+        assert!(owners.len() <= connections.len());
+        assert!(connections.iter().all(|connection| !connection.channels.contains("9_shared_voice")));
+
+        let (sessions, pages) = list(&connections, 0);
+        assert_eq!(pages, (THREADS * PER_THREAD / 2).div_ceil(SESSION_PAGE_SIZE));
+        assert_eq!(sessions.len(), SESSION_PAGE_SIZE);
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cluster::DRAINING;
+use crate::config::Config;
+use crate::connections::{Connection, Connections};
+use crate::opcodes::Health;
+
+/// Whether Redis answered the last keepalive ping
+pub static REDIS_UP: AtomicBool = AtomicBool::new(true);
+
+/// Whether new connections are turned away, toggled with SIGUSR2
+pub static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the node is done starting up, new connections are turned away
+/// until the cluster subscription is up and the channel index is kept current
+pub static WARMED_UP: AtomicBool = AtomicBool::new(false);
+
+/// Heartbeat intervals an identified connection can go without heartbeating
+/// before it counts as stale
+const STALE_AFTER_INTERVALS: u32 = 2;
+
+/// How long the share of stale connections is reused for, it's computed
+/// going over every connection and health is asked for on every heartbeat
+const STALE_SHARE_TTL: Duration = Duration::from_secs(1);
+
+/// Share of stale connections last computed, and when
+static STALE_SHARE: Mutex<Option<(Instant, f32)>> = Mutex::new(None);
+
+/// Compute the health of the server from its current load, going from best
+/// with no connections to worst at `capacity` connections, and worst while
+/// Redis is unreachable or the node is draining
+///
+/// The result is scaled down by the share of identified connections that
+/// stopped heartbeating, clients not getting their heartbeats through is a
+/// sign the node is struggling even when it isn't full.
+pub fn compute_health(config: &Config, connections: &Connections) -> Health {
+    if !REDIS_UP.load(Ordering::Relaxed) || DRAINING.load(Ordering::Relaxed) {
+        return Health::MIN;
+    }
+
+    let load = connections.len() as f32 / config.capacity as f32;
+
+    let stale_share = {
+        let mut cached = STALE_SHARE.lock().unwrap();
+
+        match *cached {
+            Some((computed, share)) if computed.elapsed() < STALE_SHARE_TTL => share,
+            _ => {
+                let share = stale_share(connections);
+                *cached = Some((Instant::now(), share));
+
+                share
+            }
+        }
+    };
+
+    Health::new((Health::MAX.get() - load) * (1.0 - stale_share))
+}
+
+/// Share of the identified connections that haven't heartbeated in
+/// STALE_AFTER_INTERVALS of their heartbeat interval, counting from when they
+/// identified if they never did
+pub fn stale_share(connections: &Connections) -> f32 {
+    let mut identified = 0;
+    let mut stale = 0;
+
+    for connection in connections.iter() {
+        if connection.identified_at.is_some() {
+            identified += 1;
+
+            if is_stale(&connection) {
+                stale += 1;
+            }
+        }
+    }
+
+    if identified == 0 {
+        return 0.0;
+    }
+
+    stale as f32 / identified as f32
+}
+
+fn is_stale(connection: &Connection) -> bool {
+    let limit = Duration::from_secs(connection.heartbeat_interval.max(1) as u64) * STALE_AFTER_INTERVALS;
+
+    let since = match (connection.last_heartbeat, connection.identified_at) {
+        (Some(last_heartbeat), _) => last_heartbeat.elapsed(),
+        (None, Some(identified_at)) => SystemTime::now().duration_since(identified_at).unwrap_or_default(),
+        (None, None) => return false
+    };
+
+    since > limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use dashmap::DashMap;
+    use tokio::sync::Notify;
+    use tokio::sync::mpsc;
+    use crate::connections::HandshakeInfo;
+
+    fn connection(identified_ago: Option<u64>, heartbeat_ago: Option<u64>) -> Connection {
+        let (sender, _) = mpsc::channel(1);
+        let mut connection = Connection::new("127.0.0.1:1".to_string(), HandshakeInfo::default(), sender, Arc::new(Notify::new()), 5);
+
+        connection.identified_at = identified_ago.map(|ago| SystemTime::now() - Duration::from_secs(ago));
+        connection.last_heartbeat = heartbeat_ago.map(|ago| Instant::now() - Duration::from_secs(ago));
+
+        connection
+    }
+
+    #[test]
+    fn stale_share_counts_identified_connections_gone_quiet() {
+        let connections: Connections = Arc::new(DashMap::new());
+        assert_eq!(stale_share(&connections), 0.0);
+
+        // Heartbeating every 5s, stale after 10s without one
+        connections.insert("fresh".to_string(), connection(Some(60), Some(1)));
+        connections.insert("just_identified".to_string(), connection(Some(1), None));
+        connections.insert("quiet".to_string(), connection(Some(60), Some(30)));
+        connections.insert("never_heartbeated".to_string(), connection(Some(60), None));
+
+        // Not identified yet, IDENTIFY_TIMEOUT takes care of those
+        connections.insert("unidentified".to_string(), connection(None, None));
+
+        assert_eq!(stale_share(&connections), 0.5);
+    }
+}
@@ -1,13 +1,19 @@
-use std::any::Any;
-use num_traits::real::Real;
-use serde::{Serialize, Deserialize};
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
 use serde_json::Value;
 use serde_repr::{Serialize_repr, Deserialize_repr};
-use tokio_tungstenite::tungstenite::Message;
-use crate::opcodes::INFO;
+use crate::voice::VoiceMode;
 
 /// Info message types
-#[derive(FromPrimitive, Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+///
+/// Voice states go from created (VST_CREATE), through any amount of updates
+/// (VST_UPDATE, which may move the state to another channel of its guild), to
+/// destroyed (VST_DESTROY). Updating or destroying a voice state that doesn't
+/// exist, including one that was already destroyed, or that another connection
+/// owns is answered with a STATE error. So is moving one to a channel that
+/// doesn't exist or is in another guild, and destroying a channel the
+/// connection didn't CHANNEL_REQ.
+#[derive(FromPrimitive, Serialize_repr, Deserialize_repr, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema_repr))]
 #[repr(u8)]
 pub enum InfoType {
     /// Request a channel to be created inside the voice server.
@@ -29,29 +35,95 @@ pub enum InfoType {
     VST_DONE = 4,
 
     /// Sent by the client when a user is leaving a channel OR moving between channels
-    /// in a guild. More on state transitions later on.
+    /// in a guild. See [`InfoType`] for the voice state lifecycle.
     VST_DESTROY= 5,
 
-    /// Sent to update an existing voice state. Potentially unused.
+    /// Sent to update an existing voice state, moving it to another channel or
+    /// toggling its mute and deaf flags. Answered with a VST_UPDATE_ACK.
     VST_UPDATE = 6,
 
+    /// Sent by an admin connection to forcibly remove a voice state.
+    ///
+    /// Only available to connections identified with the admin secret, anyone
+    /// else gets an AUTH error. A kicked voice state is answered with a
+    /// VST_DESTROY_ACK and the connection owning it is closed.
+    /// Kicking a voice state that doesn't exist gives a STATE error.
+    VST_KICK = 7,
+
+    /// Sent by an admin connection to list the connections to this server.
+    SESSION_LIST_REQ = 8,
+
+    /// Sent by the server in reply to a SESSION_LIST_REQ.
+    SESSION_LIST = 9,
+
+    /// Sent by an admin connection to make connections IDENTIFY again.
+    REIDENTIFY_REQ = 10,
+
+    /// Sent by an admin connection to tear down a voice state or a channel,
+    /// whichever connection created it.
+    ///
+    /// The owning connection is sent the matching VST_DESTROY or
+    /// CHANNEL_DESTROY if it's connected to this server.
+    TEARDOWN_REQ = 11,
+
+    /// Sent by the server once the channel of a CHANNEL_DESTROY was removed.
+    CHANNEL_DESTROY_ACK = 12,
+
+    /// Sent by the server once the voice state of a VST_DESTROY or VST_KICK
+    /// was removed.
+    VST_DESTROY_ACK = 13,
+
+    /// Sent by the client to ask what the server supports. Only available
+    /// once identified, like every other INFO.
+    SERVER_INFO_REQ = 14,
+
+    /// Sent by the server in reply to a SERVER_INFO_REQ.
+    SERVER_INFO = 15,
+
+    /// Sent by the client to ask whether a channel exists on this server,
+    /// cheaper than keeping track of every channel to reconcile one.
+    CHANNEL_EXISTS_REQ = 16,
+
+    /// Sent by the server in reply to a CHANNEL_EXISTS_REQ.
+    CHANNEL_EXISTS_RESULT = 17,
+
+    /// Sent by the client to swap a channel token for a new one before it
+    /// expires, without destroying the channel. The old token stops working
+    /// right away.
+    CHANNEL_TOKEN_REFRESH = 18,
+
+    /// Sent by the server with the new token of a CHANNEL_TOKEN_REFRESH.
+    CHANNEL_TOKEN_REFRESH_ACK = 19,
+
+    /// Sent by the server once a VST_UPDATE went through, with the voice state
+    /// as it is now.
+    VST_UPDATE_ACK = 20
 }
 
 /// Request a channel to be created inside the voice server.
 ///
 /// The Server MUST reply back with a CHANNEL_ASSIGN when resources are
 /// allocated for the channel.
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct CHANNEL_REQ {
     /// Channel ID
+    #[serde(deserialize_with = "deserialize_snowflake")]
     pub channel_id: String,
 
     /// Guild ID, not provided if dm / group dm
-    pub guild_id: Option<String>
+    #[serde(default, deserialize_with = "deserialize_optional_snowflake")]
+    pub guild_id: Option<String>,
+
+    /// Encryption modes the client supports, any of the server's if not
+    /// provided
+    #[serde(default)]
+    pub modes: Option<Vec<String>>
 }
 
 /// Sent by the Server to signal the successful creation of a voice channel.
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct CHANNEL_ASSIGN {
     /// Channel ID
     pub channel_id: String,
@@ -60,25 +132,420 @@ pub struct CHANNEL_ASSIGN {
     pub guild_id: Option<String>,
 
     /// Authentication token
-    pub token: String
+    pub token: String,
+
+    /// Encryption mode picked for the channel
+    pub mode: VoiceMode,
+
+    /// Region the voice server serves
+    pub region: String,
+
+    /// Seconds the token is valid for, not provided if it lasts as long as
+    /// the channel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_ttl: Option<u64>
 }
 
 /// Sent by the client to create a voice state.
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct VST_CREATE {
     /// User ID
+    #[serde(deserialize_with = "deserialize_snowflake")]
     pub user_id: String,
 
     /// Channel ID
+    #[serde(deserialize_with = "deserialize_snowflake")]
+    pub channel_id: String,
+
+    /// Guild ID, not provided if dm / group dm
+    #[serde(default, deserialize_with = "deserialize_optional_snowflake")]
+    pub guild_id: Option<String>,
+
+    /// Muted by the guild, false if not provided
+    #[serde(default)]
+    pub mute: bool,
+
+    /// Deafened by the guild, false if not provided
+    #[serde(default)]
+    pub deaf: bool,
+
+    /// Muted by the user, false if not provided
+    #[serde(default)]
+    pub self_mute: bool,
+
+    /// Deafened by the user, false if not provided
+    #[serde(default)]
+    pub self_deaf: bool
+}
+
+impl VST_CREATE {
+    /// Mute and deaf flags, by the name they're stored under in the session
+    pub fn flags(&self) -> [(&'static str, bool); 4] {
+        [("mute", self.mute), ("deaf", self.deaf), ("self_mute", self.self_mute), ("self_deaf", self.self_deaf)]
+    }
+}
+
+/// Sent by the server to indicate the success of a VST_CREATE.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct VST_DONE {
+    /// User ID
+    pub user_id: String,
+
+    /// Channel ID
+    pub channel_id: String,
+
+    /// Guild ID, not provided if dm / group dm
+    pub guild_id: Option<String>,
+
+    /// Session ID for the voice state
+    pub session_id: String,
+
+    /// Muted by the guild
+    pub mute: bool,
+
+    /// Deafened by the guild
+    pub deaf: bool,
+
+    /// Muted by the user
+    pub self_mute: bool,
+
+    /// Deafened by the user
+    pub self_deaf: bool
+}
+
+/// Sent by the client to signal the destruction of a voice channel. Be it
+/// a channel being deleted, or all members in it leaving.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CHANNEL_DESTROY {
+    /// Channel ID
+    #[serde(deserialize_with = "deserialize_snowflake")]
+    pub channel_id: String,
+
+    /// Guild ID, not provided if dm / group dm
+    #[serde(default, deserialize_with = "deserialize_optional_snowflake")]
+    pub guild_id: Option<String>
+}
+
+/// Sent by the client when a user is leaving a channel OR moving between channels
+/// in a guild. See [`InfoType`] for the voice state lifecycle.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct VST_DESTROY {
+    /// Session ID for the voice state
+    pub session_id: String
+}
+
+/// Sent to update an existing voice state, moving it to another channel or
+/// toggling its mute and deaf flags.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct VST_UPDATE {
+    /// Session ID for the voice state
+    pub session_id: String,
+
+    /// Channel ID to move the voice state to, stays in its channel if not
+    /// provided. The channel has to exist.
+    #[serde(default, deserialize_with = "deserialize_optional_snowflake")]
+    pub channel_id: Option<String>,
+
+    /// Guild ID of the channel to move to, the guild the voice state is in
+    /// already, not provided if dm / group dm
+    #[serde(default, deserialize_with = "deserialize_optional_snowflake")]
+    pub guild_id: Option<String>,
+
+    /// Muted by the guild, unchanged if not provided
+    #[serde(default)]
+    pub mute: Option<bool>,
+
+    /// Deafened by the guild, unchanged if not provided
+    #[serde(default)]
+    pub deaf: Option<bool>,
+
+    /// Muted by the user, unchanged if not provided
+    #[serde(default)]
+    pub self_mute: Option<bool>,
+
+    /// Deafened by the user, unchanged if not provided
+    #[serde(default)]
+    pub self_deaf: Option<bool>
+}
+
+impl VST_UPDATE {
+    /// Mute and deaf flags that were provided, by the name they're stored
+    /// under in the session
+    pub fn flags(&self) -> Vec<(&'static str, bool)> {
+        [("mute", self.mute), ("deaf", self.deaf), ("self_mute", self.self_mute), ("self_deaf", self.self_deaf)]
+            .into_iter()
+            .filter_map(|(name, flag)| flag.map(|flag| (name, flag)))
+            .collect()
+    }
+}
+
+/// Sent by the server once a VST_UPDATE went through, with the voice state
+/// as it is now.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct VST_UPDATE_ACK {
+    /// Session ID for the voice state
+    pub session_id: String,
+
+    /// Channel ID the voice state is in
+    pub channel_id: String,
+
+    /// Guild ID of the channel, not provided if dm / group dm
+    pub guild_id: Option<String>,
+
+    /// Muted by the guild
+    pub mute: bool,
+
+    /// Deafened by the guild
+    pub deaf: bool,
+
+    /// Muted by the user
+    pub self_mute: bool,
+
+    /// Deafened by the user
+    pub self_deaf: bool
+}
+
+/// Sent by an admin connection to forcibly remove a voice state.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct VST_KICK {
+    /// Session ID for the voice state
+    pub session_id: String
+}
+
+/// Sent by an admin connection to list the connections to this server.
+///
+/// Only available to connections identified with the admin secret.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SESSION_LIST_REQ {
+    /// Page to list, starting at 0
+    #[serde(default)]
+    pub page: usize
+}
+
+/// Sent by the server in reply to a SESSION_LIST_REQ.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SESSION_LIST {
+    /// Connections on this page
+    pub sessions: Vec<SessionInfo>,
+
+    /// Page listed
+    pub page: usize,
+
+    /// Total amount of pages
+    pub pages: usize
+}
+
+/// Sent by an admin connection to make connections IDENTIFY again.
+///
+/// Only available to connections identified with the admin secret.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct REIDENTIFY_REQ {
+    /// ID of the connection to ask, every connection but the admin one if not
+    /// provided
+    pub id: Option<String>
+}
+
+/// Sent by an admin connection to tear down a voice state or a channel,
+/// whichever connection created it.
+///
+/// Only available to connections identified with the admin secret. Exactly one
+/// of `session_id` and `channel_id` must be provided.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TEARDOWN_REQ {
+    /// Session ID of the voice state to tear down
+    pub session_id: Option<String>,
+
+    /// Channel ID of the channel to tear down, along with its voice states
+    #[serde(default, deserialize_with = "deserialize_optional_snowflake")]
+    pub channel_id: Option<String>,
+
+    /// Guild ID of the channel, not provided if dm / group dm
+    #[serde(default, deserialize_with = "deserialize_optional_snowflake")]
+    pub guild_id: Option<String>
+}
+
+/// Sent by the server once the channel of a CHANNEL_DESTROY was removed.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CHANNEL_DESTROY_ACK {
+    /// Channel ID
+    pub channel_id: String,
+
+    /// Guild ID, not provided if dm / group dm
+    pub guild_id: Option<String>
+}
+
+/// Sent by the server once the voice state of a VST_DESTROY or VST_KICK was
+/// removed.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct VST_DESTROY_ACK {
+    /// Session ID of the voice state
+    pub session_id: String
+}
+
+/// Sent by the client to ask what the server supports.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SERVER_INFO_REQ {}
+
+/// Sent by the server in reply to a SERVER_INFO_REQ.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SERVER_INFO {
+    /// Version of the server
+    pub version: String,
+
+    /// Version of the protocol the server speaks
+    pub protocol_version: u32,
+
+    /// Commit the server was built from, `unknown` if it wasn't built from a
+    /// git checkout
+    pub git_hash: String,
+
+    /// Region the server serves
+    pub region: String,
+
+    /// Optional protocol features the server supports
+    pub features: Vec<String>,
+
+    /// Encryption modes the server supports, most preferred first
+    pub encryption_modes: Vec<VoiceMode>,
+
+    /// Limits configured on the server
+    pub limits: ServerLimits
+}
+
+/// Sent by the client to ask whether a channel exists on this server.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CHANNEL_EXISTS_REQ {
+    /// Channel ID
+    #[serde(deserialize_with = "deserialize_snowflake")]
     pub channel_id: String,
 
     /// Guild ID, not provided if dm / group dm
+    #[serde(default, deserialize_with = "deserialize_optional_snowflake")]
     pub guild_id: Option<String>
 }
 
+/// Sent by the server in reply to a CHANNEL_EXISTS_REQ.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CHANNEL_EXISTS_RESULT {
+    /// Channel ID, as asked for
+    pub channel_id: String,
+
+    /// Guild ID, as asked for
+    pub guild_id: Option<String>,
+
+    /// If the channel exists
+    pub exists: bool
+}
+
+/// Sent by the client to swap a channel token for a new one.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CHANNEL_TOKEN_REFRESH {
+    /// Channel ID
+    #[serde(deserialize_with = "deserialize_snowflake")]
+    pub channel_id: String,
+
+    /// Guild ID, not provided if dm / group dm
+    #[serde(default, deserialize_with = "deserialize_optional_snowflake")]
+    pub guild_id: Option<String>,
+
+    /// Current token of the channel, it has to still be valid
+    pub token: String
+}
+
+/// Sent by the server with the new token of a CHANNEL_TOKEN_REFRESH.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CHANNEL_TOKEN_REFRESH_ACK {
+    /// Channel ID, as asked for
+    pub channel_id: String,
+
+    /// Guild ID, as asked for
+    pub guild_id: Option<String>,
+
+    /// New authentication token
+    pub token: String,
+
+    /// Seconds the new token is valid for, not provided if it lasts as long
+    /// as the channel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_ttl: Option<u64>
+}
+
+/// Limits of a server, as given in SERVER_INFO
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ServerLimits {
+    /// Longest string (in bytes) accepted in INFO data
+    pub max_string_length: usize,
+
+    /// Most channels a session can own at once, 0 is no limit
+    pub max_session_channels: usize,
+
+    /// Most voice states a session can own at once, 0 is no limit
+    pub max_session_voice_states: usize
+}
+
+/// A connection to this server, as listed in SESSION_LIST
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SessionInfo {
+    /// ID of the connection
+    pub id: String,
+
+    /// Address of the peer
+    pub peer: String,
+
+    /// User-Agent of the client, not provided if it didn't send one
+    pub user_agent: Option<String>,
+
+    /// Subprotocols the client offered in the handshake, not provided if it
+    /// didn't offer any
+    pub subprotocols: Option<String>,
+
+    /// X-Forwarded-Proto set by the proxy in front, `https` if the client
+    /// connected to it over TLS, not provided if there's no such proxy
+    pub forwarded_proto: Option<String>,
+
+    /// Unix timestamp (in seconds) of when the connection identified, not
+    /// provided if it hasn't yet
+    pub identified_at: Option<u64>,
+
+    /// Milliseconds since the last HEARTBEAT, not provided if there wasn't any
+    pub last_heartbeat_ms: Option<u64>,
+
+    /// Seconds the connection was told to heartbeat every in HELLO, the
+    /// connection is stale if `last_heartbeat_ms` is well past it
+    pub heartbeat_interval: i32,
+
+    /// Keys of the channels created by this connection
+    pub channels: Vec<String>
+}
+
 /// Info message data
-#[derive(Deserialize, Serialize, Debug)]
-#[serde(untagged)]
+///
+/// Serialized as the data of the variant alone. There's no deserializing it
+/// on its own since some variants look the same, the variant is picked from
+/// the info type by [`decode_infodata`].
+#[derive(PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema), schemars(untagged))]
 pub enum InfoData {
     /// Sent by the client to create a voice state.
     VST_CREATE(VST_CREATE),
@@ -90,71 +557,436 @@ pub enum InfoData {
     CHANNEL_REQ(CHANNEL_REQ),
 
     /// Sent by the Server to signal the successful creation of a voice channel.
-    CHANNEL_ASSIGN {
-        /// Channel ID
-        channel_id: String,
-
-        /// Guild ID, not provided if dm / group dm
-        guild_id: Option<String>,
-
-        /// Authentication token
-        token: String
-    },
+    CHANNEL_ASSIGN(CHANNEL_ASSIGN),
 
     /// Sent by the client to signal the destruction of a voice channel. Be it
     /// a channel being deleted, or all members in it leaving.
-    CHANNEL_DESTROY {
-        /// Channel ID
-        channel_id: String,
-
-        /// Guild ID, not provided if dm / group dm
-        guild_id: Option<String>
-    },
+    CHANNEL_DESTROY(CHANNEL_DESTROY),
 
     /// Sent by the server to indicate the success of a VST_CREATE.
-    VST_DONE {
-        /// User ID
-        user_id: String,
+    VST_DONE(VST_DONE),
 
-        /// Channel ID
-        channel_id: String,
+    /// Sent by the client when a user is leaving a channel OR moving between channels
+    /// in a guild. See [`InfoType`] for the voice state lifecycle.
+    VST_DESTROY(VST_DESTROY),
 
-        /// Guild ID, not provided if dm / group dm
-        guild_id: Option<String>,
+    /// Sent to update an existing voice state, moving it to another channel or
+    /// toggling its mute and deaf flags.
+    VST_UPDATE(VST_UPDATE),
 
-        /// Session ID for the voice state
-        session_id: String
-    },
+    /// Sent by the server once a VST_UPDATE went through, with the voice
+    /// state as it is now.
+    VST_UPDATE_ACK(VST_UPDATE_ACK),
 
-    /// Sent by the client when a user is leaving a channel OR moving between channels
-    /// in a guild. More on state transitions later on.
-    VST_DESTROY {
-        /// Session ID for the voice state
-        session_id: String
-    },
+    /// Sent by an admin connection to forcibly remove a voice state.
+    VST_KICK(VST_KICK),
+
+    /// Sent by an admin connection to list the connections to this server.
+    SESSION_LIST_REQ(SESSION_LIST_REQ),
+
+    /// Sent by the server in reply to a SESSION_LIST_REQ.
+    SESSION_LIST(SESSION_LIST),
+
+    /// Sent by an admin connection to make connections IDENTIFY again.
+    REIDENTIFY_REQ(REIDENTIFY_REQ),
+
+    /// Sent by an admin connection to tear down a voice state or a channel.
+    TEARDOWN_REQ(TEARDOWN_REQ),
+
+    /// Sent by the server once the channel of a CHANNEL_DESTROY was removed.
+    CHANNEL_DESTROY_ACK(CHANNEL_DESTROY_ACK),
+
+    /// Sent by the server once the voice state of a VST_DESTROY was removed.
+    VST_DESTROY_ACK(VST_DESTROY_ACK),
+
+    /// Sent by the client to ask what the server supports.
+    SERVER_INFO_REQ(SERVER_INFO_REQ),
 
-    /// Sent to update an existing voice state. Potentially unused.
-    VST_UPDATE {
-        session_id: String
+    /// Sent by the server in reply to a SERVER_INFO_REQ.
+    SERVER_INFO(SERVER_INFO),
+
+    /// Sent by the client to ask whether a channel exists on this server.
+    CHANNEL_EXISTS_REQ(CHANNEL_EXISTS_REQ),
+
+    /// Sent by the server in reply to a CHANNEL_EXISTS_REQ.
+    CHANNEL_EXISTS_RESULT(CHANNEL_EXISTS_RESULT),
+
+    /// Sent by the client to swap a channel token for a new one.
+    CHANNEL_TOKEN_REFRESH(CHANNEL_TOKEN_REFRESH),
+
+    /// Sent by the server with the new token of a CHANNEL_TOKEN_REFRESH.
+    CHANNEL_TOKEN_REFRESH_ACK(CHANNEL_TOKEN_REFRESH_ACK)
+}
+
+impl Serialize for InfoData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            InfoData::VST_CREATE(dn) => dn.serialize(serializer),
+            InfoData::CHANNEL_REQ(dn) => dn.serialize(serializer),
+            InfoData::CHANNEL_ASSIGN(dn) => dn.serialize(serializer),
+            InfoData::CHANNEL_DESTROY(dn) => dn.serialize(serializer),
+            InfoData::VST_DONE(dn) => dn.serialize(serializer),
+            InfoData::VST_DESTROY(dn) => dn.serialize(serializer),
+            InfoData::VST_UPDATE(dn) => dn.serialize(serializer),
+            InfoData::VST_UPDATE_ACK(dn) => dn.serialize(serializer),
+            InfoData::VST_KICK(dn) => dn.serialize(serializer),
+            InfoData::SESSION_LIST_REQ(dn) => dn.serialize(serializer),
+            InfoData::SESSION_LIST(dn) => dn.serialize(serializer),
+            InfoData::REIDENTIFY_REQ(dn) => dn.serialize(serializer),
+            InfoData::TEARDOWN_REQ(dn) => dn.serialize(serializer),
+            InfoData::CHANNEL_DESTROY_ACK(dn) => dn.serialize(serializer),
+            InfoData::VST_DESTROY_ACK(dn) => dn.serialize(serializer),
+            InfoData::SERVER_INFO_REQ(dn) => dn.serialize(serializer),
+            InfoData::SERVER_INFO(dn) => dn.serialize(serializer),
+            InfoData::CHANNEL_EXISTS_REQ(dn) => dn.serialize(serializer),
+            InfoData::CHANNEL_EXISTS_RESULT(dn) => dn.serialize(serializer),
+            InfoData::CHANNEL_TOKEN_REFRESH(dn) => dn.serialize(serializer),
+            InfoData::CHANNEL_TOKEN_REFRESH_ACK(dn) => dn.serialize(serializer)
+        }
+    }
+}
+
+impl InfoData {
+    /// Every string in the data, to check their length before any of them
+    /// makes it to Redis
+    pub fn strings(&self) -> Vec<&str> {
+        match self {
+            InfoData::VST_CREATE(dn) => [&dn.user_id, &dn.channel_id].into_iter()
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::CHANNEL_REQ(dn) => [&dn.channel_id].into_iter()
+                .chain(&dn.guild_id)
+                .chain(dn.modes.iter().flatten())
+                .map(String::as_str)
+                .collect(),
+            InfoData::CHANNEL_ASSIGN(dn) => [&dn.channel_id, &dn.token, &dn.region].into_iter()
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::CHANNEL_DESTROY(dn) => [&dn.channel_id].into_iter()
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::VST_DONE(dn) => [&dn.user_id, &dn.channel_id, &dn.session_id].into_iter()
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::VST_DESTROY(dn) => vec![&dn.session_id],
+            InfoData::VST_UPDATE(dn) => [&dn.session_id].into_iter()
+                .chain(&dn.channel_id)
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::VST_UPDATE_ACK(dn) => [&dn.session_id, &dn.channel_id].into_iter()
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::VST_KICK(dn) => vec![&dn.session_id],
+            InfoData::SESSION_LIST_REQ(_) => vec![],
+            InfoData::SESSION_LIST(dn) => dn.sessions.iter()
+                .flat_map(|session| [&session.id, &session.peer].into_iter()
+                    .chain(&session.user_agent)
+                    .chain(&session.subprotocols)
+                    .chain(&session.forwarded_proto)
+                    .chain(&session.channels))
+                .map(String::as_str)
+                .collect(),
+            InfoData::REIDENTIFY_REQ(dn) => dn.id.iter().map(String::as_str).collect(),
+            InfoData::TEARDOWN_REQ(dn) => dn.session_id.iter()
+                .chain(&dn.channel_id)
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::CHANNEL_DESTROY_ACK(dn) => [&dn.channel_id].into_iter()
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::VST_DESTROY_ACK(dn) => vec![&dn.session_id],
+            InfoData::SERVER_INFO_REQ(_) => vec![],
+            InfoData::SERVER_INFO(dn) => [&dn.version, &dn.git_hash, &dn.region].into_iter()
+                .chain(&dn.features)
+                .map(String::as_str)
+                .collect(),
+            InfoData::CHANNEL_EXISTS_REQ(dn) => [&dn.channel_id].into_iter()
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::CHANNEL_EXISTS_RESULT(dn) => [&dn.channel_id].into_iter()
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::CHANNEL_TOKEN_REFRESH(dn) => [&dn.channel_id, &dn.token].into_iter()
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect(),
+            InfoData::CHANNEL_TOKEN_REFRESH_ACK(dn) => [&dn.channel_id, &dn.token].into_iter()
+                .chain(&dn.guild_id)
+                .map(String::as_str)
+                .collect()
+        }
+    }
+}
+
+/// A snowflake as sent by the client, a string like the protocol says or an
+/// integer like some clients send anyway
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawSnowflake {
+    String(String),
+    Integer(u64)
+}
+
+impl From<RawSnowflake> for String {
+    fn from(snowflake: RawSnowflake) -> String {
+        match snowflake {
+            RawSnowflake::String(snowflake) => snowflake,
+            RawSnowflake::Integer(snowflake) => snowflake.to_string()
+        }
+    }
+}
+
+/// Refuse a snowflake that isn't only ASCII digits, an empty one would make
+/// for a malformed Redis key like `{guild_id}__voice` and others could collide
+/// with another key, like a guild ID of `dm` or a channel ID of `1_2`
+fn digits_only<E: de::Error>(snowflake: String) -> Result<String, E> {
+    if snowflake.is_empty() || !snowflake.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(E::custom("snowflake isn't a number"));
+    }
+
+    Ok(snowflake)
+}
+
+/// Deserialize a snowflake given as either a string or an integer
+fn deserialize_snowflake<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    RawSnowflake::deserialize(deserializer).map(String::from).and_then(digits_only)
+}
+
+/// Deserialize an optional snowflake given as either a string or an integer,
+/// null or missing being None but an empty string still refused
+fn deserialize_optional_snowflake<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Option::<RawSnowflake>::deserialize(deserializer)?
+        .map(|snowflake| digits_only(String::from(snowflake)))
+        .transpose()
+}
+
+/// Decode info data as the variant for the given type
+pub fn decode_infodata(_type: &InfoType, data: Value) -> Result<InfoData, serde_json::Error> {
+    match _type {
+        InfoType::CHANNEL_REQ => serde_json::from_value(data).map(InfoData::CHANNEL_REQ),
+        InfoType::CHANNEL_ASSIGN => serde_json::from_value(data).map(InfoData::CHANNEL_ASSIGN),
+        InfoType::CHANNEL_DESTROY => serde_json::from_value(data).map(InfoData::CHANNEL_DESTROY),
+        InfoType::VST_CREATE => serde_json::from_value(data).map(InfoData::VST_CREATE),
+        InfoType::VST_DONE => serde_json::from_value(data).map(InfoData::VST_DONE),
+        InfoType::VST_DESTROY => serde_json::from_value(data).map(InfoData::VST_DESTROY),
+        InfoType::VST_UPDATE => serde_json::from_value(data).map(InfoData::VST_UPDATE),
+        InfoType::VST_UPDATE_ACK => serde_json::from_value(data).map(InfoData::VST_UPDATE_ACK),
+        InfoType::VST_KICK => serde_json::from_value(data).map(InfoData::VST_KICK),
+        InfoType::SESSION_LIST_REQ => serde_json::from_value(data).map(InfoData::SESSION_LIST_REQ),
+        InfoType::SESSION_LIST => serde_json::from_value(data).map(InfoData::SESSION_LIST),
+        InfoType::REIDENTIFY_REQ => serde_json::from_value(data).map(InfoData::REIDENTIFY_REQ),
+        InfoType::TEARDOWN_REQ => serde_json::from_value(data).map(InfoData::TEARDOWN_REQ),
+        InfoType::CHANNEL_DESTROY_ACK => serde_json::from_value(data).map(InfoData::CHANNEL_DESTROY_ACK),
+        InfoType::VST_DESTROY_ACK => serde_json::from_value(data).map(InfoData::VST_DESTROY_ACK),
+        InfoType::SERVER_INFO_REQ => serde_json::from_value(data).map(InfoData::SERVER_INFO_REQ),
+        InfoType::SERVER_INFO => serde_json::from_value(data).map(InfoData::SERVER_INFO),
+        InfoType::CHANNEL_EXISTS_REQ => serde_json::from_value(data).map(InfoData::CHANNEL_EXISTS_REQ),
+        InfoType::CHANNEL_EXISTS_RESULT => serde_json::from_value(data).map(InfoData::CHANNEL_EXISTS_RESULT),
+        InfoType::CHANNEL_TOKEN_REFRESH => serde_json::from_value(data).map(InfoData::CHANNEL_TOKEN_REFRESH),
+        InfoType::CHANNEL_TOKEN_REFRESH_ACK => serde_json::from_value(data).map(InfoData::CHANNEL_TOKEN_REFRESH_ACK)
     }
 }
 
-pub async fn get_infotype(msg: Message) -> Result<(InfoType, InfoData), ()> {
-    let msg = msg.to_text().unwrap();
-    trace!(target: "infoops", "Decoding message: {}", &msg);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use serde_json::json;
+    use tokio_tungstenite::tungstenite::Message;
+    use crate::opcodes::get_opcode;
+    use crate::util::{mangle, random_json};
+
+    const FUZZ_ROUNDS: usize = 20000;
+
+    fn valid_messages() -> Vec<String> {
+        [
+            json!({"op": 6, "d": {"type": 0, "data": {"channel_id": "1", "guild_id": "2", "modes": ["xsalsa20_poly1305"]}}}),
+            json!({"op": 6, "d": {"type": 3, "data": {"user_id": "1", "channel_id": "2", "guild_id": "3"}}}),
+            json!({"op": 6, "d": {"type": 6, "data": {"session_id": "abc", "channel_id": "2", "self_mute": true}}}),
+            json!({"op": 6, "d": {"type": 18, "data": {"channel_id": "1", "token": "abc"}}})
+        ].iter().map(Value::to_string).collect()
+    }
 
-    let message_json: Result<Value, serde_json::Error> = serde_json::from_str(msg);
+    fn channel_req(data: Value) -> Result<CHANNEL_REQ, serde_json::Error> {
+        serde_json::from_value(data)
+    }
 
-    if message_json.is_ok() {
-        // TODO: Maybe find a better way?
-        let info_data: INFO = serde_json::from_value(
-            message_json.unwrap().get("d").unwrap().clone()
-        ).expect("Failed to get inner data for InfoData!");
+    #[test]
+    fn snowflakes_are_strings_or_integers() {
+        let as_string = channel_req(json!({"channel_id": "123", "guild_id": "456"})).unwrap();
+        let as_integer = channel_req(json!({"channel_id": 123, "guild_id": 456})).unwrap();
+        assert_eq!(as_string, as_integer);
+        assert_eq!(as_integer.channel_id, "123");
+        assert_eq!(as_integer.guild_id.as_deref(), Some("456"));
 
-        trace!(target: "infoops", "Decoded as Op: {:?} Data: {:?}", &info_data._type, &info_data.data);
+        // Too big for an f64 to hold exactly, like most real snowflakes
+        let big = channel_req(json!({"channel_id": 175928847299117063_u64})).unwrap();
+        assert_eq!(big.channel_id, "175928847299117063");
 
-        Ok((info_data._type, info_data.data))
-    } else {
-        Err(())
+        let vst: VST_CREATE = serde_json::from_value(json!({"user_id": 1, "channel_id": "2", "guild_id": 3})).unwrap();
+        assert_eq!((vst.user_id.as_str(), vst.channel_id.as_str(), vst.guild_id.as_deref()), ("1", "2", Some("3")));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn missing_or_null_guild_is_a_dm() {
+        assert_eq!(channel_req(json!({"channel_id": 1})).unwrap().guild_id, None);
+        assert_eq!(channel_req(json!({"channel_id": 1, "guild_id": null})).unwrap().guild_id, None);
+    }
+
+    #[test]
+    fn snowflakes_that_arent_whole_numbers_are_refused() {
+        assert!(channel_req(json!({"channel_id": -1})).is_err());
+        assert!(channel_req(json!({"channel_id": 1.5})).is_err());
+        assert!(channel_req(json!({"channel_id": 1.0})).is_err());
+        assert!(channel_req(json!({"channel_id": "-1"})).is_err());
+        assert!(channel_req(json!({"channel_id": true})).is_err());
+        assert!(channel_req(json!({"channel_id": ["1"]})).is_err());
+        assert!(channel_req(json!({"channel_id": "1", "guild_id": -2})).is_err());
+    }
+
+    #[test]
+    fn empty_snowflakes_are_refused() {
+        assert!(channel_req(json!({"channel_id": ""})).is_err());
+        assert!(channel_req(json!({"channel_id": "1", "guild_id": ""})).is_err());
+        assert!(channel_req(json!({"channel_id": " "})).is_err());
+    }
+
+    #[test]
+    fn colliding_snowflakes_are_refused() {
+        // ` 123` and `123` would be different channels in the same place,
+        // `1_2` in guild 3 would be the key of channel 2 in guild `3_1`, and
+        // guild `dm` would share its channels with the dms
+        assert!(channel_req(json!({"channel_id": " 123"})).is_err());
+        assert!(channel_req(json!({"channel_id": "123 "})).is_err());
+        assert!(channel_req(json!({"channel_id": "1_2", "guild_id": "3"})).is_err());
+        assert!(channel_req(json!({"channel_id": "2", "guild_id": "3_1"})).is_err());
+        assert!(channel_req(json!({"channel_id": "1", "guild_id": "dm"})).is_err());
+        assert!(channel_req(json!({"channel_id": "١٢٣"})).is_err());
+    }
+
+    #[test]
+    fn token_ttl_is_left_out_when_tokens_dont_expire() {
+        let assign = CHANNEL_ASSIGN {
+            channel_id: "1".to_string(),
+            guild_id: None,
+            token: "abc".to_string(),
+            mode: VoiceMode::XSalsa20Poly1305,
+            region: "test".to_string(),
+            token_ttl: None
+        };
+        let value = serde_json::to_value(InfoData::CHANNEL_ASSIGN(assign)).unwrap();
+        assert!(value.get("token_ttl").is_none(), "Got {}", value);
+        assert!(matches!(decode_infodata(&InfoType::CHANNEL_ASSIGN, value), Ok(InfoData::CHANNEL_ASSIGN(CHANNEL_ASSIGN { token_ttl: None, .. }))));
+
+        let ack = CHANNEL_TOKEN_REFRESH_ACK {
+            channel_id: "1".to_string(),
+            guild_id: Some("2".to_string()),
+            token: "abc".to_string(),
+            token_ttl: Some(60)
+        };
+        let value = serde_json::to_value(InfoData::CHANNEL_TOKEN_REFRESH_ACK(ack)).unwrap();
+        assert_eq!(value["token_ttl"], 60);
+
+        let value = serde_json::to_value(CHANNEL_TOKEN_REFRESH_ACK { token_ttl: None, ..serde_json::from_value(value).unwrap() }).unwrap();
+        assert!(value.get("token_ttl").is_none(), "Got {}", value);
+    }
+
+    /// Data of every info type, with every optional field provided
+    fn every_infodata() -> Vec<(u8, Value)> {
+        let session = json!({
+            "id": "abc", "peer": "127.0.0.1:1234", "user_agent": "test", "subprotocols": "lvsp",
+            "forwarded_proto": "https", "identified_at": 1600000000, "last_heartbeat_ms": 100,
+            "heartbeat_interval": 40, "channels": ["9_1_voice"]
+        });
+
+        vec![
+            (0, json!({"channel_id": "1", "guild_id": "2", "modes": ["xsalsa20_poly1305"]})),
+            (1, json!({"channel_id": "1", "guild_id": "2", "token": "abc", "mode": "xsalsa20_poly1305_lite", "region": "test", "token_ttl": 60})),
+            (2, json!({"channel_id": "1", "guild_id": "2"})),
+            (3, json!({"user_id": "1", "channel_id": "2", "guild_id": "3", "mute": true, "deaf": false, "self_mute": true, "self_deaf": false})),
+            (4, json!({"user_id": "1", "channel_id": "2", "guild_id": "3", "session_id": "abc", "mute": true, "deaf": false, "self_mute": true, "self_deaf": false})),
+            (5, json!({"session_id": "abc"})),
+            (6, json!({"session_id": "abc", "channel_id": "2", "guild_id": "3", "mute": true, "deaf": false, "self_mute": true, "self_deaf": false})),
+            (7, json!({"session_id": "abc"})),
+            (8, json!({"page": 2})),
+            (9, json!({"sessions": [session], "page": 0, "pages": 1})),
+            (10, json!({"id": "abc"})),
+            (11, json!({"session_id": "abc", "channel_id": "1", "guild_id": "2"})),
+            (12, json!({"channel_id": "1", "guild_id": "2"})),
+            (13, json!({"session_id": "abc"})),
+            (14, json!({})),
+            (15, json!({
+                "version": "1.0.0", "protocol_version": 1, "git_hash": "unknown", "region": "test",
+                "features": ["resume"], "encryption_modes": ["aead_aes256_gcm_rtpsize", "xsalsa20_poly1305"],
+                "limits": {"max_string_length": 256, "max_session_channels": 0, "max_session_voice_states": 8}
+            })),
+            (16, json!({"channel_id": "1", "guild_id": "2"})),
+            (17, json!({"channel_id": "1", "guild_id": "2", "exists": true})),
+            (18, json!({"channel_id": "1", "guild_id": "2", "token": "abc"})),
+            (19, json!({"channel_id": "1", "guild_id": "2", "token": "def", "token_ttl": 60})),
+            (20, json!({"session_id": "abc", "channel_id": "2", "guild_id": "3", "mute": true, "deaf": false, "self_mute": true, "self_deaf": false}))
+        ]
+    }
+
+    #[test]
+    fn every_infodata_roundtrips() {
+        let every = every_infodata();
+
+        for _type in 0..=20u8 {
+            assert!(every.iter().any(|(listed, _)| *listed == _type), "No data for type {}", _type);
+        }
+        assert!(<InfoType as num::FromPrimitive>::from_u8(21).is_none(), "A new info type needs data here");
+
+        for (_type, value) in every {
+            let _type: InfoType = num::FromPrimitive::from_u8(_type).unwrap();
+            let data = decode_infodata(&_type, value.clone())
+                .unwrap_or_else(|e| panic!("{:?} data {} doesn't decode: {}", _type, value, e));
+
+            let encoded = serde_json::to_value(&data).unwrap();
+            assert_eq!(encoded, value, "{:?} encoded differently", _type);
+            assert_eq!(decode_infodata(&_type, encoded).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn info_never_panics_on_random_json() {
+        let mut rng = StdRng::seed_from_u64(106);
+
+        for _ in 0..FUZZ_ROUNDS {
+            let d = match rng.gen_range(0..3) {
+                0 => json!({"type": random_json(&mut rng, 0), "data": random_json(&mut rng, 3)}),
+                1 => json!({"type": rng.gen_range(0..22), "data": random_json(&mut rng, 3)}),
+                _ => random_json(&mut rng, 4)
+            };
+
+            let _ = get_opcode(Message::Text(json!({"op": 6, "d": d}).to_string()));
+        }
+    }
+
+    #[test]
+    fn info_never_panics_on_mangled_messages() {
+        let mut rng = StdRng::seed_from_u64(106);
+        let valid = valid_messages();
+
+        for msg in &valid {
+            assert!(get_opcode(Message::Text(msg.clone())).is_ok(), "{} doesn't decode", msg);
+        }
+
+        for _ in 0..FUZZ_ROUNDS {
+            let msg = &valid[rng.gen_range(0..valid.len())];
+
+            let _ = get_opcode(mangle(&mut rng, msg));
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Voice server for Litecord, speaking LVSP over a websocket with the voice
+//! state kept in Redis
+//!
+//! The binary in `main.rs` reads the config and runs [`server::serve`], the
+//! modules are public for the benchmarks and integration tests.
+#![allow(non_camel_case_types, clippy::upper_case_acronyms, non_local_definitions)]
+
+#[macro_use] extern crate num_derive;
+#[macro_use] extern crate log;
+
+pub mod opcodes;
+pub mod infoops;
+pub mod util;
+pub mod connections;
+pub mod config;
+pub mod redis;
+pub mod shards;
+pub mod ordered;
+pub mod metrics;
+pub mod health;
+pub mod listener;
+pub mod audit;
+pub mod cluster;
+pub mod logging;
+pub mod version;
+pub mod voice;
+pub mod ratelimit;
+pub mod check;
+pub mod server;
+#[cfg(feature = "schema")]
+pub mod schema;
@@ -0,0 +1,81 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+/// Listener on a TCP address, or on a Unix socket when given as `unix:/path/to.sock`
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf)
+}
+
+/// Stream accepted from a [`Listener`], handed to `accept_conn` as the
+/// transport inside
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream)
+}
+
+impl Listener {
+    pub async fn bind(addr: &str) -> io::Result<Listener> {
+        match addr.strip_prefix("unix:") {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                remove_stale_socket(&path)?;
+
+                Ok(Listener::Unix(UnixListener::bind(&path)?, path))
+            },
+            None => Ok(Listener::Tcp(TcpListener::bind(addr).await?))
+        }
+    }
+
+    /// Accept a connection, giving back the stream and the address of the peer
+    pub async fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+
+                Ok((Stream::Tcp(stream), peer.to_string()))
+            },
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), format!("unix:{}", path.display())))
+            }
+        }
+    }
+
+    /// Address the listener is bound to, with the port picked by the OS when
+    /// bound to port 0
+    pub fn local_addr(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(|addr| addr.to_string()).unwrap_or_default(),
+            Listener::Unix(_, path) => format!("unix:{}", path.display())
+        }
+    }
+
+    /// Remove the socket file of a Unix listener
+    pub fn cleanup(&self) {
+        if let Listener::Unix(_, path) = self {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Remove a socket left behind by a previous run, as long as nothing is
+/// listening on it anymore
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "Socket is still in use")),
+        Err(_) => {
+            warn!("Removing stale socket {}!", path.display());
+
+            std::fs::remove_file(path)
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{IsTerminal, Write};
+use log::Record;
+use serde_json::{json, Value};
+use crate::config::Settings;
+
+tokio::task_local! {
+    /// ID of the connection the current task handles, added to every JSON log
+    /// line written from it, helpers included, without passing it around
+    pub static CONN_ID: String;
+}
+
+/// How log lines are written
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LogFormat {
+    /// Colored text, for people
+    Pretty,
+
+    /// One JSON object per line with `ts`, `level`, `target` and `message`,
+    /// plus `conn` when logged while handling a connection, for log pipelines
+    Json
+}
+
+/// Set up logging in the LOG_FORMAT format (`pretty` or `json`), pretty if
+/// stderr is a terminal and JSON otherwise when it's unset
+///
+/// Runs before the config is read so reading it can log already, RUST_LOG
+/// filters both formats the same. Both are read from the settings, so they
+/// can be given in `.env` or CONFIG_FILE too.
+pub fn init(settings: &Settings) {
+    let requested = settings.get("LOG_FORMAT").map(str::to_string);
+    let filters = settings.get("RUST_LOG").unwrap_or("");
+
+    let format = match requested.as_deref() {
+        Some("pretty") => LogFormat::Pretty,
+        Some("json") => LogFormat::Json,
+        _ if std::io::stderr().is_terminal() => LogFormat::Pretty,
+        _ => LogFormat::Json
+    };
+
+    match format {
+        LogFormat::Pretty => pretty_env_logger::formatted_builder().parse_filters(filters).init(),
+        LogFormat::Json => {
+            env_logger::Builder::new()
+                .parse_filters(filters)
+                .format(|buf, record| writeln!(buf, "{}", json_line(buf.timestamp_millis().to_string(), record)))
+                .init();
+        }
+    }
+
+    if let Some(requested) = requested.filter(|requested| requested != "pretty" && requested != "json") {
+        warn!("Unknown LOG_FORMAT {}, logging as {:?}", requested, format);
+    }
+}
+
+/// The JSON log line for `record` logged at `ts`, with `conn` set to CONN_ID
+/// when the current task has one
+pub fn json_line(ts: String, record: &Record) -> Value {
+    let mut line = json!({
+        "ts": ts,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string()
+    });
+
+    if let Ok(conn_id) = CONN_ID.try_with(String::clone) {
+        line["conn"] = conn_id.into();
+    }
+
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for code deep under a connection handler, which doesn't get
+    /// the connection ID passed in
+    fn helper_line() -> Value {
+        json_line("0".to_string(), &Record::builder().target("helper").args(format_args!("From a helper")).build())
+    }
+
+    #[tokio::test]
+    async fn lines_get_the_conn_id_of_their_task() {
+        assert!(helper_line().get("conn").is_none());
+
+        let line = CONN_ID.scope("abc".to_string(), async { helper_line() }).await;
+        assert_eq!(line["conn"], "abc");
+        assert_eq!(line["target"], "helper");
+        assert_eq!(line["message"], "From a helper");
+
+        // Other tasks don't see it
+        let line = CONN_ID.scope("abc".to_string(), async { tokio::spawn(async { helper_line() }).await.unwrap() }).await;
+        assert!(line.get("conn").is_none());
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use futures_util::StreamExt;
+use futures_util::stream::FuturesUnordered;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Error;
+use crate::health::{PAUSED, REDIS_UP, WARMED_UP};
+use crate::cluster::DRAINING;
+use crate::version;
+
+/// Biggest request accepted by the metrics server
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// How long requests being answered at shutdown are given to finish
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Kinds of errors a connection can end with
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ErrorCategory {
+    /// The peer broke the websocket protocol, sent invalid UTF-8 or too much
+    Protocol,
+
+    /// The underlying socket failed
+    Io,
+
+    /// TLS failed
+    Tls,
+
+    /// Anything else
+    Other
+}
+
+impl ErrorCategory {
+    pub const ALL: [ErrorCategory; 4] = [ErrorCategory::Protocol, ErrorCategory::Io, ErrorCategory::Tls, ErrorCategory::Other];
+
+    /// Category of a websocket error, None if it's just the connection closing
+    pub fn of(e: &Error) -> Option<ErrorCategory> {
+        match e {
+            Error::ConnectionClosed | Error::AlreadyClosed => None,
+            Error::Protocol(_) | Error::Utf8 | Error::Capacity(_) => Some(ErrorCategory::Protocol),
+            Error::Io(_) => Some(ErrorCategory::Io),
+            Error::Tls(_) => Some(ErrorCategory::Tls),
+            _ => Some(ErrorCategory::Other)
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCategory::Protocol => "protocol",
+            ErrorCategory::Io => "io",
+            ErrorCategory::Tls => "tls",
+            ErrorCategory::Other => "other"
+        }
+    }
+}
+
+/// Counters of the server, exposed in the Prometheus text format
+pub struct Metrics {
+    /// Connections accepted
+    pub connections: AtomicU64,
+
+    /// Connections turned away because health was under the shed threshold
+    pub shed_connections: AtomicU64,
+
+    /// Connections closed for not reading their outbound queue fast enough
+    pub slow_connections: AtomicU64,
+
+    /// Channel operations refused for going over the guild rate limit
+    pub rate_limited: AtomicU64,
+
+    /// INFO requests refused for going over `max_in_flight`
+    pub busy_requests: AtomicU64,
+
+    /// Heartbeats that came too early or too late, see `heartbeat_tolerance`
+    pub heartbeat_deviations: AtomicU64,
+
+    /// Channels announced as migrating while draining
+    pub drain_notices: AtomicU64,
+
+    /// Channels taken over by another node while draining
+    pub migrated_channels: AtomicU64,
+
+    /// Connections that ended with an error, by ErrorCategory
+    connection_errors: [AtomicU64; 4]
+}
+
+pub static METRICS: Metrics = Metrics {
+    connections: AtomicU64::new(0),
+    shed_connections: AtomicU64::new(0),
+    slow_connections: AtomicU64::new(0),
+    rate_limited: AtomicU64::new(0),
+    busy_requests: AtomicU64::new(0),
+    heartbeat_deviations: AtomicU64::new(0),
+    drain_notices: AtomicU64::new(0),
+    migrated_channels: AtomicU64::new(0),
+    connection_errors: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)]
+};
+
+impl Metrics {
+    pub fn connection_error(&self, category: ErrorCategory) {
+        self.connection_errors[category as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render the counters in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "# TYPE lvsp_build_info gauge").unwrap();
+        writeln!(
+            out,
+            "lvsp_build_info{{version=\"{}\",git_hash=\"{}\",protocol_version=\"{}\"}} 1",
+            version::VERSION,
+            version::GIT_HASH,
+            version::LVSP_VERSION
+        ).unwrap();
+
+        writeln!(out, "# TYPE lvsp_connections_total counter").unwrap();
+        writeln!(out, "lvsp_connections_total {}", self.connections.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# TYPE lvsp_shed_connections_total counter").unwrap();
+        writeln!(out, "lvsp_shed_connections_total {}", self.shed_connections.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# TYPE lvsp_slow_connections_total counter").unwrap();
+        writeln!(out, "lvsp_slow_connections_total {}", self.slow_connections.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# TYPE lvsp_rate_limited_total counter").unwrap();
+        writeln!(out, "lvsp_rate_limited_total {}", self.rate_limited.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# TYPE lvsp_busy_requests_total counter").unwrap();
+        writeln!(out, "lvsp_busy_requests_total {}", self.busy_requests.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# TYPE lvsp_heartbeat_deviations_total counter").unwrap();
+        writeln!(out, "lvsp_heartbeat_deviations_total {}", self.heartbeat_deviations.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# TYPE lvsp_drain_notices_total counter").unwrap();
+        writeln!(out, "lvsp_drain_notices_total {}", self.drain_notices.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# TYPE lvsp_migrated_channels_total counter").unwrap();
+        writeln!(out, "lvsp_migrated_channels_total {}", self.migrated_channels.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# TYPE lvsp_draining gauge").unwrap();
+        writeln!(out, "lvsp_draining {}", DRAINING.load(Ordering::Relaxed) as u8).unwrap();
+
+        writeln!(out, "# TYPE lvsp_paused gauge").unwrap();
+        writeln!(out, "lvsp_paused {}", PAUSED.load(Ordering::Relaxed) as u8).unwrap();
+
+        writeln!(out, "# TYPE lvsp_redis_up gauge").unwrap();
+        writeln!(out, "lvsp_redis_up {}", REDIS_UP.load(Ordering::Relaxed) as u8).unwrap();
+
+        writeln!(out, "# TYPE lvsp_connection_errors_total counter").unwrap();
+        for category in ErrorCategory::ALL {
+            writeln!(
+                out,
+                "lvsp_connection_errors_total{{category=\"{}\"}} {}",
+                category.as_str(),
+                self.connection_errors[category as usize].load(Ordering::Relaxed)
+            ).unwrap();
+        }
+
+        out
+    }
+}
+
+/// Serve the metrics over plain HTTP on `/metrics`, along with `/healthz`
+/// which fails while warming up or while Redis is unreachable
+///
+/// Stops accepting once `shutdown` turns true, then gives the requests being
+/// answered up to SHUTDOWN_GRACE to finish.
+pub async fn serve(addr: String, mut shutdown: watch::Receiver<bool>) {
+    let socket = TcpListener::bind(&addr).await.expect("Failed to bind metrics address!");
+    info!(target: "metrics", "Serving metrics on {}!", &addr);
+
+    let mut requests = FuturesUnordered::new();
+
+    loop {
+        tokio::select! {
+            accepted = socket.accept() => match accepted {
+                Ok((stream, _)) => requests.push(handle_request(stream)),
+                Err(_) => break
+            },
+            Some(result) = requests.next() => log_result(result),
+            _ = shutdown.changed() => break
+        }
+    }
+
+    drop(socket);
+    debug!(target: "metrics", "Stopped accepting, finishing {} requests", requests.len());
+
+    let finish = async {
+        while let Some(result) = requests.next().await {
+            log_result(result);
+        }
+    };
+
+    if tokio::time::timeout(SHUTDOWN_GRACE, finish).await.is_err() {
+        debug!(target: "metrics", "Gave up on unfinished requests");
+    }
+}
+
+fn log_result(result: std::io::Result<()>) {
+    if let Err(e) = result {
+        debug!(target: "metrics", "Failed to answer metrics request: {}", e);
+    }
+}
+
+async fn handle_request(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    // Only the request line matters, read until the end of the headers
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split(' ');
+
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", METRICS.render()),
+        (Some("GET"), Some("/healthz")) => match (WARMED_UP.load(Ordering::Relaxed), REDIS_UP.load(Ordering::Relaxed)) {
+            (false, _) => ("503 Service Unavailable", "warming up\n".to_string()),
+            (true, false) => ("503 Service Unavailable", "redis unreachable\n".to_string()),
+            (true, true) => ("200 OK", "ok\n".to_string())
+        },
+        _ => ("404 Not Found", String::new())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
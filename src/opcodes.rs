@@ -7,19 +7,22 @@
 //! The message data is defined by each opcode.
 //!
 //! **Note:** the snowflake type follows the same rules as the Discord Gateway's
-//! snowflake type: A string encoding a Discord Snowflake.
+//! snowflake type: A string encoding a Discord Snowflake. Integers are taken
+//! too, but anything that isn't made of ASCII digits is refused with DECODE.
 //!
 //! [Source](https://gitlab.com/litecord/litecord/-/blob/master/docs/lvsp.md)
-use std::any::Any;
-use num_traits::real::Real;
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error as _;
 use serde_json::Value;
 use serde_repr::{Serialize_repr, Deserialize_repr};
 use tokio_tungstenite::tungstenite::Message;
-use crate::infoops::{InfoData, InfoType};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use crate::infoops::{decode_infodata, InfoData, InfoType};
 
 /// Op codes sent/received by Litecord
-#[derive(FromPrimitive, Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(FromPrimitive, Serialize_repr, Deserialize_repr, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema_repr))]
 #[repr(u8)]
 pub enum OpCode {
     /// Sent by the server when a connection is established.
@@ -28,14 +31,20 @@ pub enum OpCode {
     /// Sent by the client to identify itself.
     IDENTIFY = 1,
 
+    /// Sent by the client instead of IDENTIFY to take back the state of a
+    /// session whose connection dropped.
     RESUME = 2,
 
+    /// Sent by the server once the client identified or resumed.
     READY = 3,
 
     /// Sent by the client as a keepalive / health monitoring method.
     ///
     /// The server MUST reply with a HEARTBEAT_ACK message back in a reasonable
     /// time period.
+    ///
+    /// Also accepted before IDENTIFY, so clients on slow links can keep alive
+    /// while identifying. It doesn't extend `identify_timeout` though.
     HEARTBEAT = 4,
 
     /// Sent by the server in reply to a HEARTBEAT message coming from the client.
@@ -45,90 +54,438 @@ pub enum OpCode {
     ///
     /// The INFO message is extensible in which many request / response scenarios
     /// are laid on.
-    INFO = 6
+    ///
+    /// Within a connection, the replies to INFO requests (ERRORs included) are
+    /// sent in the order the requests were received, even with several handled
+    /// at once (`INFO_CONCURRENCY`). HEARTBEAT_ACKs and the replies to other
+    /// messages don't wait for them.
+    INFO = 6,
+
+    /// Sent by the server when a message couldn't be handled, the connection
+    /// stays open.
+    ERROR = 7,
+
+    /// Sent by the server to ask the client to IDENTIFY again, e.g. after the
+    /// secret was rotated.
+    ///
+    /// Carries a fresh nonce, the client MUST reply with an IDENTIFY using it.
+    /// Until it does the connection is unidentified, but keeps its channels and
+    /// voice states.
+    REIDENTIFY = 8
 }
 
+/// Longest reason a close frame can carry, 125 bytes of control frame payload
+/// minus the 2 bytes of the code
+const MAX_CLOSE_REASON: usize = 123;
+
+/// Wait suggested before coming back to an overloaded node
+const OVERLOADED_RETRY_AFTER_MS: u64 = 30000;
+
 /// Possible error codes
-#[derive(FromPrimitive, Deserialize, Serialize)]
+///
+/// When used to close the connection, only GENERAL, DRAINING, SLOW, OVERLOADED,
+/// RATE_LIMITED and BUSY are reconnectable, the others will keep failing until
+/// the client fixes what it's sending. See [`ErrorCode::reconnect_behavior`].
+#[derive(FromPrimitive, Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema_repr))]
+#[repr(u16)]
 pub enum ErrorCode {
     /// General error, reconnect
     GENERAL = 4000,
 
-    /// Authentication failure
+    /// Authentication failure, don't reconnect without fixing the token
     AUTH = 4001,
 
     /// Decode error, given message failed to decode as json
-    DECODE = 4002
+    DECODE = 4002,
+
+    /// Invalid state transition, e.g. updating or destroying a voice state
+    /// that doesn't exist
+    STATE = 4003,
+
+    /// Unsupported operation, given message decoded as json but its opcode is
+    /// unknown or can't be sent by the client
+    UNSUPPORTED = 4004,
+
+    /// None of the encryption modes given in CHANNEL_REQ are supported
+    ENCRYPTION = 4005,
+
+    /// The node is draining and doesn't take new channels, use another one
+    DRAINING = 4006,
+
+    /// The session already owns as many channels or voice states as it's
+    /// allowed to, destroy some first
+    LIMIT = 4007,
+
+    /// The client didn't read what was sent to it fast enough, reconnect
+    SLOW = 4008,
+
+    /// The node is out of capacity (shedding load or paused), reconnect to
+    /// another node or to this one after a long wait
+    OVERLOADED = 4009,
+
+    /// Too many channel operations in the guild, the operation wasn't done,
+    /// retry after `retry_after_ms`
+    RATE_LIMITED = 4010,
+
+    /// Too many INFO requests of the connection are waiting for a reply, the
+    /// request wasn't done, wait for replies before sending more
+    BUSY = 4011
+}
+
+/// How a client should reconnect after being closed with an error code
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReconnectHint {
+    /// Don't reconnect until what caused the error is fixed
+    Never,
+
+    /// Reconnect after waiting, backing off if it keeps happening
+    Backoff,
+
+    /// Reconnect right away, to another node if possible
+    Immediately,
+
+    /// Reconnect to another node, or to this one only after a long wait
+    Elsewhere
+}
+
+/// Advisory sent as the reason of a close frame, tells the client whether and
+/// how quickly it should reconnect.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CloseAdvice {
+    /// Short human readable reason for the close
+    pub reason: String,
+
+    /// If the client can reconnect without changing anything
+    pub reconnectable: bool,
+
+    /// Suggested amount of milliseconds to wait before reconnecting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>
+}
+
+impl ErrorCode {
+    /// Human readable description sent along with the code
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::GENERAL => "General error, reconnect",
+            ErrorCode::AUTH => "Authentication failed",
+            ErrorCode::DECODE => "Failed to decode message",
+            ErrorCode::STATE => "Invalid state transition",
+            ErrorCode::UNSUPPORTED => "Unsupported opcode",
+            ErrorCode::ENCRYPTION => "No supported encryption mode",
+            ErrorCode::DRAINING => "Node is draining",
+            ErrorCode::LIMIT => "Session limit reached",
+            ErrorCode::SLOW => "Client too slow to read",
+            ErrorCode::OVERLOADED => "Server overloaded",
+            ErrorCode::RATE_LIMITED => "Rate limited",
+            ErrorCode::BUSY => "Too many requests in flight"
+        }
+    }
+
+    /// How the client should reconnect after being closed with this code
+    ///
+    /// - GENERAL, SLOW, RATE_LIMITED, BUSY: [`ReconnectHint::Backoff`]
+    /// - DRAINING: [`ReconnectHint::Immediately`], to another node
+    /// - OVERLOADED: [`ReconnectHint::Elsewhere`]
+    /// - AUTH, DECODE, STATE, UNSUPPORTED, ENCRYPTION, LIMIT: [`ReconnectHint::Never`]
+    pub fn reconnect_behavior(&self) -> ReconnectHint {
+        match self {
+            ErrorCode::GENERAL | ErrorCode::SLOW | ErrorCode::RATE_LIMITED | ErrorCode::BUSY => ReconnectHint::Backoff,
+            ErrorCode::DRAINING => ReconnectHint::Immediately,
+            ErrorCode::OVERLOADED => ReconnectHint::Elsewhere,
+            ErrorCode::AUTH | ErrorCode::DECODE | ErrorCode::STATE | ErrorCode::UNSUPPORTED | ErrorCode::ENCRYPTION | ErrorCode::LIMIT => ReconnectHint::Never
+        }
+    }
+
+    /// Reconnect advisory for this error code, with the given reason
+    pub fn close_advice(&self, reason: &str) -> CloseAdvice {
+        let hint = self.reconnect_behavior();
+
+        CloseAdvice {
+            reason: reason.to_string(),
+            reconnectable: hint != ReconnectHint::Never,
+            retry_after_ms: match hint {
+                ReconnectHint::Backoff => Some(1000),
+                ReconnectHint::Elsewhere => Some(OVERLOADED_RETRY_AFTER_MS),
+                ReconnectHint::Never | ReconnectHint::Immediately => None
+            }
+        }
+    }
+
+    /// Close frame carrying this error code and its reconnect advisory, with
+    /// the message of the code as the reason
+    pub fn close_frame(&self) -> CloseFrame<'static> {
+        self.close_frame_with(self.message())
+    }
+
+    /// Close frame carrying this error code and its reconnect advisory, with
+    /// the given reason, which is cut short if the advisory wouldn't fit in a
+    /// close frame
+    pub fn close_frame_with(&self, reason: &str) -> CloseFrame<'static> {
+        let mut reason = reason;
+        let mut advice = serde_json::to_string(&self.close_advice(reason)).unwrap();
+
+        while advice.len() > MAX_CLOSE_REASON {
+            let end = reason.char_indices().last().map(|(index, _)| index).unwrap_or(0);
+            reason = &reason[..end];
+            advice = serde_json::to_string(&self.close_advice(reason)).unwrap();
+        }
+
+        CloseFrame {
+            code: CloseCode::from(*self as u16),
+            reason: advice.into()
+        }
+    }
+}
+
+/// Health of the server, where 0 is the worst and 1 is the best.
+///
+/// Values are clamped into `0.0..=1.0` on construction (NaN becomes 0), so
+/// an out of range health can never be put on the wire.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(from = "f32")]
+pub struct Health(f32);
+
+impl Health {
+    /// Worst possible health
+    pub const MIN: Health = Health(0.0);
+
+    /// Best possible health
+    pub const MAX: Health = Health(1.0);
+
+    pub fn new(value: f32) -> Health {
+        if value.is_nan() {
+            Health::MIN
+        } else {
+            Health(value.clamp(0.0, 1.0))
+        }
+    }
+
+    pub fn get(self) -> f32 {
+        self.0
+    }
+}
+
+impl From<f32> for Health {
+    fn from(value: f32) -> Health {
+        Health::new(value)
+    }
 }
 
 /// Sent by the client to identify itself.
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct IDENTIFY {
     /// HMAC SHA256 string of a shared secret and the HELLO nonce
-    pub token: String
+    pub token: String,
+
+    /// Random string for the server to prove it knows the secret with, see
+    /// the `proof` of READY
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<String>
+}
+
+/// Sent by the client instead of IDENTIFY to take back the state of a session
+/// whose connection dropped.
+///
+/// Only works within the session grace period, after that the state is gone
+/// and the server replies with a STATE error.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RESUME {
+    /// HMAC SHA256 string of a shared secret and the HELLO nonce
+    pub token: String,
+
+    /// Session ID given in READY
+    pub session_id: String,
+
+    /// Random string for the server to prove it knows the secret with, see
+    /// the `proof` of READY
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<String>
+}
+
+/// Sent by the server when a connection is established.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct HELLO {
+    /// Amount of seconds to heartbeat with
+    pub heartbeat_interval: i32,
+
+    /// Random 10-character string used in authentication
+    pub nonce: String
+}
+
+/// Sent by the server once the client identified or resumed.
+///
+/// Only PartialEq since [`Health`] is a float.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct READY {
+    /// Health of the server (where 0 is worst and 1 is best)
+    pub health: Health,
+
+    /// Session ID to RESUME with if the connection drops
+    pub session_id: String,
+
+    /// HMAC SHA256 string of the secret the client identified with and
+    /// `lvsp-server-proof:` followed by the challenge, only provided if the
+    /// client sent a challenge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<String>,
+
+    /// URL of the node holding the session, to connect to for RESUME since
+    /// sessions aren't shared between nodes, only provided if the node has
+    /// one configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume_url: Option<String>
 }
 
-/// Sent by either client or a server to send information between each other.
+/// Sent by the client as a keepalive / health monitoring method.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct HEARTBEAT {}
+
+/// Sent by the server in reply to a HEARTBEAT message coming from the client.
 ///
-/// The INFO message is extensible in which many request / response scenarios are laid on.
-#[derive(Deserialize, Serialize, Debug)]
+/// Only PartialEq since [`Health`] is a float.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct HEARTBEAT_ACK {
+    /// Health of the server (where 0 is worst and 1 is best)
+    pub health: Health
+}
+
+/// Sent by either client or a server to send information between eachother.
+///
+/// Deserializing decodes the data by the info type, see [`decode_infodata`].
+#[derive(Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct INFO {
     /// Info type
     #[serde(rename = "type")]
     pub _type: InfoType,
 
     /// Info data, varies depending on InfoType
-    pub data: InfoData
+    pub data: InfoData,
+
+    /// Only check the request and reply with what would be done, without
+    /// changing anything, false if not provided
+    ///
+    /// Replies to a dry run carry placeholders for what would have been
+    /// generated: the `token` of CHANNEL_ASSIGN and the `session_id` of
+    /// VST_DONE are empty strings.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub validate_only: bool
+}
+
+impl<'de> Deserialize<'de> for INFO {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<INFO, D::Error> {
+        #[derive(Deserialize)]
+        struct RawInfo {
+            #[serde(rename = "type")]
+            _type: InfoType,
+            data: Value,
+            #[serde(default)]
+            validate_only: bool
+        }
+
+        let raw = RawInfo::deserialize(deserializer)?;
+        let data = decode_infodata(&raw._type, raw.data).map_err(D::Error::custom)?;
+
+        Ok(INFO { _type: raw._type, data, validate_only: raw.validate_only })
+    }
+}
+
+/// Sent by the server when a message couldn't be handled, the connection
+/// stays open.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ERROR {
+    /// Error code
+    pub code: ErrorCode,
+
+    /// Human readable description of the error
+    pub message: String,
+
+    /// Milliseconds to wait before retrying, only provided with RATE_LIMITED
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>
+}
+
+/// Sent by the server to ask the client to IDENTIFY again.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct REIDENTIFY {
+    /// Random 10-character string to use in the new IDENTIFY
+    pub nonce: String
 }
 
 /// Message data for the socket
-#[derive(Deserialize, Serialize, Debug)]
-#[serde(untagged)]
+///
+/// Only PartialEq since [`Health`] is a float.
+///
+/// Serialized as the data of the variant alone. There's no deserializing it
+/// on its own since some variants look the same, the variant is picked from
+/// the opcode by [`decode_messagedata`].
+#[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema), schemars(untagged))]
 pub enum MessageData {
     /// Sent by the server when a connection is established.
-    HELLO {
-        /// Amount of milliseconds to heartbeat with
-        heartbeat_interval: i32,
-
-        /// Random 10-character string used in authentication
-        nonce: String
-    },
+    HELLO(HELLO),
 
     /// Sent by the client to identify itself.
     IDENTIFY(IDENTIFY),
 
-    READY {
-        /// Health of the server (where 0 is worst and 1 is best)
-        health: f32
-    },
+    /// Sent by the client to take back the state of a dropped session.
+    RESUME(RESUME),
+
+    /// Sent by the server once the client identified or resumed.
+    READY(READY),
 
     /// Sent by the client as a keepalive / health monitoring method.
     ///
     /// The server MUST reply with a HEARTBEAT_ACK message back in a reasonable
     /// time period.
-    HEARTBEAT {},
+    HEARTBEAT(HEARTBEAT),
 
     /// Sent by the server in reply to a HEARTBEAT message coming from the client.
     ///
     /// The `health` field is a measure of the server's overall health. It is a
     /// float going from 0 to 1, where 0 is the worst health possible, and 1 is the
     /// best health possible.
-    HEARTBEAT_ACK {
-        /// Health of the server (where 0 is worst and 1 is best)
-        health: f32
-    },
+    HEARTBEAT_ACK(HEARTBEAT_ACK),
 
     /// Sent by either client or a server to send information between eachother.
     ///
     /// The INFO message is extensible in which many request / response scenarios
     /// are laid on.
-    INFO {
-        /// Info type
-        #[serde(rename = "type")]
-        _type: InfoType,
+    INFO(INFO),
+
+    /// Sent by the server when a message couldn't be handled, the connection
+    /// stays open.
+    ERROR(ERROR),
 
-        /// Info data, varies depending on InfoType
-        data: InfoData
+    /// Sent by the server to ask the client to IDENTIFY again.
+    REIDENTIFY(REIDENTIFY)
+}
+
+impl Serialize for MessageData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MessageData::HELLO(dn) => dn.serialize(serializer),
+            MessageData::IDENTIFY(dn) => dn.serialize(serializer),
+            MessageData::RESUME(dn) => dn.serialize(serializer),
+            MessageData::READY(dn) => dn.serialize(serializer),
+            MessageData::HEARTBEAT(dn) => dn.serialize(serializer),
+            MessageData::HEARTBEAT_ACK(dn) => dn.serialize(serializer),
+            MessageData::INFO(dn) => dn.serialize(serializer),
+            MessageData::ERROR(dn) => dn.serialize(serializer),
+            MessageData::REIDENTIFY(dn) => dn.serialize(serializer)
+        }
     }
 }
 
@@ -136,7 +493,10 @@ pub enum MessageData {
 ///
 /// **Note:** the snowflake type follows the same rules as the Discord Gateway's
 /// snowflake type: A string encoding a Discord Snowflake.
-#[derive(Deserialize, Serialize)]
+///
+/// Deserializing decodes the data by the opcode, see [`decode_messagedata`].
+#[derive(Serialize, PartialEq, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct SocketMessage {
     /// Operator code
     pub op: OpCode,
@@ -145,19 +505,408 @@ pub struct SocketMessage {
     pub d: MessageData
 }
 
+impl<'de> Deserialize<'de> for SocketMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SocketMessage, D::Error> {
+        #[derive(Deserialize)]
+        struct RawMessage {
+            op: OpCode,
+            d: Value
+        }
 
-pub fn get_opcode(msg: Message) -> Result<(OpCode, MessageData), ()> {
-    let msg = msg.to_text().unwrap();
+        let raw = RawMessage::deserialize(deserializer)?;
+        let d = decode_messagedata(&raw.op, raw.d).map_err(D::Error::custom)?;
+
+        Ok(SocketMessage { op: raw.op, d })
+    }
+}
+
+/// Decode message data as the variant for the given opcode
+pub fn decode_messagedata(op: &OpCode, d: Value) -> Result<MessageData, serde_json::Error> {
+    match op {
+        OpCode::HELLO => serde_json::from_value(d).map(MessageData::HELLO),
+        OpCode::IDENTIFY => serde_json::from_value(d).map(MessageData::IDENTIFY),
+        OpCode::RESUME => serde_json::from_value(d).map(MessageData::RESUME),
+        OpCode::READY => serde_json::from_value(d).map(MessageData::READY),
+        OpCode::HEARTBEAT => serde_json::from_value(d).map(MessageData::HEARTBEAT),
+        OpCode::HEARTBEAT_ACK => serde_json::from_value(d).map(MessageData::HEARTBEAT_ACK),
+        OpCode::INFO => serde_json::from_value(d).map(MessageData::INFO),
+        OpCode::ERROR => serde_json::from_value(d).map(MessageData::ERROR),
+        OpCode::REIDENTIFY => serde_json::from_value(d).map(MessageData::REIDENTIFY)
+    }
+}
+
+impl MessageData {
+    /// Opcode the data belongs to
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            MessageData::HELLO(_) => OpCode::HELLO,
+            MessageData::IDENTIFY(_) => OpCode::IDENTIFY,
+            MessageData::RESUME(_) => OpCode::RESUME,
+            MessageData::READY(_) => OpCode::READY,
+            MessageData::HEARTBEAT(_) => OpCode::HEARTBEAT,
+            MessageData::HEARTBEAT_ACK(_) => OpCode::HEARTBEAT_ACK,
+            MessageData::INFO(_) => OpCode::INFO,
+            MessageData::ERROR(_) => OpCode::ERROR,
+            MessageData::REIDENTIFY(_) => OpCode::REIDENTIFY
+        }
+    }
+}
+
+/// Constructors for the messages sent by the server, each sets the opcode that
+/// goes with its data
+impl SocketMessage {
+    pub fn hello(heartbeat_interval: i32, nonce: String) -> SocketMessage {
+        SocketMessage {
+            op: OpCode::HELLO,
+            d: MessageData::HELLO(HELLO { heartbeat_interval, nonce })
+        }
+    }
+
+    pub fn ready(health: Health, session_id: String, proof: Option<String>, resume_url: Option<String>) -> SocketMessage {
+        SocketMessage {
+            op: OpCode::READY,
+            d: MessageData::READY(READY { health, session_id, proof, resume_url })
+        }
+    }
+
+    pub fn heartbeat_ack(health: Health) -> SocketMessage {
+        SocketMessage {
+            op: OpCode::HEARTBEAT_ACK,
+            d: MessageData::HEARTBEAT_ACK(HEARTBEAT_ACK { health })
+        }
+    }
+
+    pub fn info(_type: InfoType, data: InfoData) -> SocketMessage {
+        SocketMessage {
+            op: OpCode::INFO,
+            d: MessageData::INFO(INFO { _type, data, validate_only: false })
+        }
+    }
+
+    pub fn error(code: ErrorCode) -> SocketMessage {
+        SocketMessage {
+            op: OpCode::ERROR,
+            d: MessageData::ERROR(ERROR { code, message: code.message().to_string(), retry_after_ms: None })
+        }
+    }
+
+    pub fn rate_limited(retry_after_ms: u64) -> SocketMessage {
+        let code = ErrorCode::RATE_LIMITED;
+
+        SocketMessage {
+            op: OpCode::ERROR,
+            d: MessageData::ERROR(ERROR { code, message: code.message().to_string(), retry_after_ms: Some(retry_after_ms) })
+        }
+    }
+
+    pub fn reidentify(nonce: String) -> SocketMessage {
+        SocketMessage {
+            op: OpCode::REIDENTIFY,
+            d: MessageData::REIDENTIFY(REIDENTIFY { nonce })
+        }
+    }
+
+    /// Check that the data goes with the opcode, gives the opcode it goes
+    /// with if it doesn't
+    pub fn validate(&self) -> Result<(), OpCode> {
+        let expected = self.d.opcode();
+
+        if self.op == expected {
+            Ok(())
+        } else {
+            Err(expected)
+        }
+    }
+}
+
+/// Encoded HEARTBEAT_ACK of the last health it was asked for
+///
+/// HEARTBEAT_ACK is by far the most sent message and health rarely changes
+/// between two heartbeats, so the frame is only serialized again when it does.
+#[derive(Default)]
+pub struct HeartbeatAckCache {
+    last: Option<(Health, String)>
+}
+
+impl HeartbeatAckCache {
+    /// Encoded HEARTBEAT_ACK carrying `health`
+    pub fn encode(&mut self, health: Health) -> &str {
+        match &self.last {
+            Some((cached, _)) if *cached == health => {},
+            _ => {
+                let frame = serde_json::to_string(&SocketMessage::heartbeat_ack(health)).unwrap();
+                self.last = Some((health, frame));
+            }
+        }
+
+        &self.last.as_ref().unwrap().1
+    }
+}
+
+/// Top-level fields of a message that aren't `op` or `d`, which decoding
+/// ignores, to spot clients speaking a newer version of the protocol
+pub fn unknown_fields(msg: &Message) -> Vec<String> {
+    let value: Option<Value> = msg.to_text().ok().and_then(|msg| serde_json::from_str(msg).ok());
+
+    match value {
+        Some(Value::Object(fields)) => fields.keys()
+            .filter(|field| *field != "op" && *field != "d")
+            .cloned()
+            .collect(),
+        _ => vec![]
+    }
+}
+
+/// Decode a message, failing with DECODE if it isn't valid json or its data
+/// doesn't go with its opcode, and with UNSUPPORTED if the opcode is unknown
+///
+/// The opcode is read first and the data decoded as the variant it implies,
+/// so data that happens to fit another variant is never mistaken for it.
+pub fn get_opcode(msg: Message) -> Result<(OpCode, MessageData), ErrorCode> {
+    let msg = msg.to_text().map_err(|_| ErrorCode::DECODE)?;
     trace!(target: "opcodes", "Decoding message: {}", &msg);
-    let message_json: Result<SocketMessage, serde_json::Error> = serde_json::from_str(msg);
 
-    if message_json.is_ok() {
-        let output = message_json.unwrap();
+    let mut value: Value = serde_json::from_str(msg).map_err(|_| ErrorCode::DECODE)?;
+    let op = value.get("op").and_then(Value::as_u64).ok_or(ErrorCode::DECODE)?;
+    let op: OpCode = num::FromPrimitive::from_u64(op).ok_or(ErrorCode::UNSUPPORTED)?;
+
+    let d = value.get_mut("d").map(Value::take).ok_or(ErrorCode::DECODE)?;
+    let d = decode_messagedata(&op, d).map_err(|_| ErrorCode::DECODE)?;
+
+    trace!(target: "opcodes", "Decoded as Op: {:?} Data: {:?}", &op, &d);
+
+    Ok((op, d))
+}
+
+/// Opcode `msg` claims to have, whether or not the rest of it decodes
+pub fn claimed_opcode(msg: &Message) -> Option<OpCode> {
+    let value: Value = serde_json::from_str(msg.to_text().ok()?).ok()?;
+
+    value.get("op").and_then(Value::as_u64).and_then(num::FromPrimitive::from_u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use serde_json::json;
+    use crate::util::{mangle, random_json};
+
+    const FUZZ_ROUNDS: usize = 20000;
+
+    fn valid_messages() -> Vec<String> {
+        [
+            json!({"op": 1, "d": {"token": "0123456789abcdef"}}),
+            json!({"op": 2, "d": {"token": "0123456789abcdef", "session_id": "abc"}}),
+            json!({"op": 4, "d": {}}),
+            json!({"op": 6, "d": {"type": 0, "data": {"channel_id": "1", "guild_id": "2"}}})
+        ].iter().map(Value::to_string).collect()
+    }
+
+    #[test]
+    fn get_opcode_never_panics_on_random_json() {
+        let mut rng = StdRng::seed_from_u64(106);
+
+        for _ in 0..FUZZ_ROUNDS {
+            let msg = match rng.gen_range(0..3) {
+                0 => json!({"op": random_json(&mut rng, 0), "d": random_json(&mut rng, 3)}),
+                1 => json!({"op": rng.gen_range(0..10), "d": random_json(&mut rng, 3)}),
+                _ => random_json(&mut rng, 4)
+            };
+
+            let _ = get_opcode(Message::Text(msg.to_string()));
+        }
+    }
+
+    #[test]
+    fn get_opcode_never_panics_on_mangled_messages() {
+        let mut rng = StdRng::seed_from_u64(106);
+        let valid = valid_messages();
+
+        for msg in &valid {
+            assert!(get_opcode(Message::Text(msg.clone())).is_ok(), "{} doesn't decode", msg);
+        }
+
+        for _ in 0..FUZZ_ROUNDS {
+            let msg = &valid[rng.gen_range(0..valid.len())];
 
-        trace!(target: "opcodes", "Decoded as Op: {:?} Data: {:?}", &output.op, &output.d);
+            let _ = get_opcode(mangle(&mut rng, msg));
+        }
+    }
+
+    #[test]
+    fn claimed_opcode_of_messages_that_dont_decode() {
+        let info = Message::Text(json!({"op": 6, "d": {"type": 16, "data": {}}}).to_string());
+        assert!(get_opcode(info.clone()).is_err());
+        assert_eq!(claimed_opcode(&info), Some(OpCode::INFO));
+
+        assert_eq!(claimed_opcode(&Message::Text(json!({"op": 99, "d": {}}).to_string())), None);
+        assert_eq!(claimed_opcode(&Message::Text("{\"op\": 6".to_string())), None);
+    }
+
+    #[test]
+    fn data_decodes_as_its_opcode() {
+        let decode = |op: OpCode, d: Value| decode_messagedata(&op, d).map(|data| data.opcode());
+
+        // Data that would fit another variant, decoded as what it was sent as
+        assert_eq!(decode(OpCode::HEARTBEAT, json!({"health": 1.0})).unwrap(), OpCode::HEARTBEAT);
+        assert!(decode(OpCode::READY, json!({"health": 1.0})).is_err());
+        assert_eq!(decode(OpCode::HEARTBEAT_ACK, json!({"health": 1.0, "session_id": "abc"})).unwrap(), OpCode::HEARTBEAT_ACK);
+        assert_eq!(decode(OpCode::IDENTIFY, json!({"token": "abc", "session_id": "def"})).unwrap(), OpCode::IDENTIFY);
 
-        Ok((output.op, output.d))
-    } else {
-        Err(())
+        // Data of another opcode doesn't fit
+        assert!(decode(OpCode::IDENTIFY, json!({"health": 1.0})).is_err());
+        assert!(decode(OpCode::RESUME, json!({"token": "abc"})).is_err());
+        assert!(decode(OpCode::HELLO, json!({"nonce": "0123456789"})).is_err());
+        assert!(decode(OpCode::ERROR, json!({"nonce": "0123456789"})).is_err());
+        assert!(decode(OpCode::REIDENTIFY, json!({"code": 4000, "message": "General error"})).is_err());
+        assert!(decode(OpCode::INFO, json!({"token": "abc"})).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn get_opcode_decodes_by_opcode() {
+        let decode = |msg: Value| get_opcode(Message::Text(msg.to_string()));
+
+        let (op, d) = decode(json!({"op": 1, "d": {"token": "abc", "session_id": "def"}})).unwrap();
+        assert_eq!(op, OpCode::IDENTIFY);
+        assert_eq!(d, MessageData::IDENTIFY(IDENTIFY { token: "abc".to_string(), challenge: None }));
+
+        let (op, d) = decode(json!({"op": 4, "d": {"health": 1.0}})).unwrap();
+        assert_eq!((op, d), (OpCode::HEARTBEAT, MessageData::HEARTBEAT(HEARTBEAT {})));
+
+        assert_eq!(decode(json!({"op": 2, "d": {"token": "abc"}})), Err(ErrorCode::DECODE));
+        assert_eq!(decode(json!({"op": 6, "d": {"type": 3, "data": {"channel_id": "1", "guild_id": "2"}}})), Err(ErrorCode::DECODE));
+        assert_eq!(decode(json!({"op": 42, "d": {}})), Err(ErrorCode::UNSUPPORTED));
+    }
+
+    #[test]
+    fn constructors_set_the_opcode_of_their_data() {
+        let channel_exists = decode_infodata(&InfoType::CHANNEL_EXISTS_RESULT, json!({"channel_id": "1", "guild_id": null, "exists": true})).unwrap();
+
+        let messages = [
+            (SocketMessage::hello(40000, "0123456789".to_string()), OpCode::HELLO),
+            (SocketMessage::ready(Health::MAX, "abc".to_string(), None, None), OpCode::READY),
+            (SocketMessage::heartbeat_ack(Health::MAX), OpCode::HEARTBEAT_ACK),
+            (SocketMessage::info(InfoType::CHANNEL_EXISTS_RESULT, channel_exists), OpCode::INFO),
+            (SocketMessage::error(ErrorCode::DECODE), OpCode::ERROR),
+            (SocketMessage::rate_limited(1000), OpCode::ERROR),
+            (SocketMessage::reidentify("0123456789".to_string()), OpCode::REIDENTIFY)
+        ];
+
+        for (msg, op) in messages {
+            assert_eq!(msg.op, op, "{:?} has the wrong opcode", msg);
+            assert_eq!(msg.d.opcode(), op);
+            assert_eq!(msg.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn mismatched_opcode_fails_validation() {
+        let mismatched = [
+            (SocketMessage { op: OpCode::READY, d: MessageData::HEARTBEAT_ACK(HEARTBEAT_ACK { health: Health::MAX }) }, OpCode::HEARTBEAT_ACK),
+            (SocketMessage { op: OpCode::HEARTBEAT_ACK, d: MessageData::HEARTBEAT(HEARTBEAT {}) }, OpCode::HEARTBEAT),
+            (SocketMessage { op: OpCode::ERROR, d: MessageData::REIDENTIFY(REIDENTIFY { nonce: "0123456789".to_string() }) }, OpCode::REIDENTIFY),
+            (SocketMessage { op: OpCode::INFO, ..SocketMessage::hello(40000, "0123456789".to_string()) }, OpCode::HELLO)
+        ];
+
+        for (msg, expected) in mismatched {
+            assert_eq!(msg.validate(), Err(expected), "{:?} passed", msg);
+        }
+    }
+
+    #[test]
+    fn every_error_code_has_its_reconnect_hint() {
+        let hints = [
+            (ErrorCode::GENERAL, ReconnectHint::Backoff),
+            (ErrorCode::AUTH, ReconnectHint::Never),
+            (ErrorCode::DECODE, ReconnectHint::Never),
+            (ErrorCode::STATE, ReconnectHint::Never),
+            (ErrorCode::UNSUPPORTED, ReconnectHint::Never),
+            (ErrorCode::ENCRYPTION, ReconnectHint::Never),
+            (ErrorCode::DRAINING, ReconnectHint::Immediately),
+            (ErrorCode::LIMIT, ReconnectHint::Never),
+            (ErrorCode::SLOW, ReconnectHint::Backoff),
+            (ErrorCode::OVERLOADED, ReconnectHint::Elsewhere),
+            (ErrorCode::RATE_LIMITED, ReconnectHint::Backoff),
+            (ErrorCode::BUSY, ReconnectHint::Backoff)
+        ];
+
+        for code in 4000..=4011 {
+            let code: ErrorCode = num::FromPrimitive::from_u16(code).unwrap();
+            assert!(hints.iter().any(|(listed, _)| *listed == code), "No hint for {:?}", code);
+        }
+        assert!(<ErrorCode as num::FromPrimitive>::from_u16(4012).is_none(), "A new error code needs its hint here");
+
+        for (code, hint) in hints {
+            assert_eq!(code.reconnect_behavior(), hint, "{:?}", code);
+
+            let advice = code.close_advice("reason");
+            assert_eq!(advice.reconnectable, hint != ReconnectHint::Never, "{:?}", code);
+            assert_eq!(advice.retry_after_ms, match hint {
+                ReconnectHint::Backoff => Some(1000),
+                ReconnectHint::Elsewhere => Some(OVERLOADED_RETRY_AFTER_MS),
+                ReconnectHint::Never | ReconnectHint::Immediately => None
+            }, "{:?}", code);
+        }
+    }
+
+    /// One message of every opcode, with every optional field provided
+    fn every_message() -> Vec<SocketMessage> {
+        let channel_req = decode_infodata(&InfoType::CHANNEL_REQ, json!({"channel_id": "1", "guild_id": "2"})).unwrap();
+
+        vec![
+            SocketMessage::hello(40000, "0123456789".to_string()),
+            SocketMessage { op: OpCode::IDENTIFY, d: MessageData::IDENTIFY(IDENTIFY {
+                token: "abc".to_string(),
+                challenge: Some("def".to_string())
+            }) },
+            SocketMessage { op: OpCode::RESUME, d: MessageData::RESUME(RESUME {
+                token: "abc".to_string(),
+                session_id: "ghi".to_string(),
+                challenge: Some("def".to_string())
+            }) },
+            SocketMessage::ready(Health::new(0.5), "ghi".to_string(), Some("jkl".to_string()), Some("wss://node".to_string())),
+            SocketMessage { op: OpCode::HEARTBEAT, d: MessageData::HEARTBEAT(HEARTBEAT {}) },
+            SocketMessage::heartbeat_ack(Health::new(0.25)),
+            SocketMessage { op: OpCode::INFO, d: MessageData::INFO(INFO { _type: InfoType::CHANNEL_REQ, data: channel_req, validate_only: true }) },
+            SocketMessage::error(ErrorCode::STATE),
+            SocketMessage::rate_limited(1500),
+            SocketMessage::reidentify("0123456789".to_string())
+        ]
+    }
+
+    #[test]
+    fn every_message_roundtrips() {
+        let messages = every_message();
+
+        for op in 0..=8 {
+            let op: OpCode = num::FromPrimitive::from_u64(op).unwrap();
+            assert!(messages.iter().any(|msg| msg.op == op), "No {:?} message", op);
+        }
+
+        for msg in messages {
+            let encoded = serde_json::to_string(&msg).unwrap();
+            let decoded: SocketMessage = serde_json::from_str(&encoded)
+                .unwrap_or_else(|e| panic!("{} doesn't decode: {}", encoded, e));
+
+            assert_eq!(decoded, msg, "{} decoded differently", encoded);
+            assert_eq!(get_opcode(Message::Text(encoded)).unwrap(), (msg.op, msg.d));
+        }
+    }
+
+    #[test]
+    fn optional_fields_roundtrip_when_missing() {
+        let messages = [
+            SocketMessage::ready(Health::MAX, "ghi".to_string(), None, None),
+            SocketMessage::error(ErrorCode::DECODE),
+            SocketMessage { op: OpCode::IDENTIFY, d: MessageData::IDENTIFY(IDENTIFY { token: "abc".to_string(), challenge: None }) }
+        ];
+
+        for msg in messages {
+            let encoded = serde_json::to_value(&msg).unwrap();
+            assert!(encoded["d"].as_object().unwrap().values().all(|value| !value.is_null()), "Got {}", encoded);
+            assert_eq!(serde_json::from_value::<SocketMessage>(encoded).unwrap(), msg);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Futures run a few at a time, their outputs given in the order they were
+//! pushed, for the INFO requests of a connection
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use futures_util::StreamExt;
+use futures_util::stream::FuturesOrdered;
+
+type Job<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Jobs running up to `concurrency` at once, the rest waiting their turn
+pub struct OrderedQueue<T> {
+    running: FuturesOrdered<Job<T>>,
+    waiting: VecDeque<Job<T>>,
+    concurrency: usize
+}
+
+impl<T> OrderedQueue<T> {
+    pub fn new(concurrency: usize) -> OrderedQueue<T> {
+        OrderedQueue { running: FuturesOrdered::new(), waiting: VecDeque::new(), concurrency: concurrency.max(1) }
+    }
+
+    /// Queue `job`, it starts once fewer than `concurrency` jobs pushed before
+    /// it are still running or waiting for their output to be taken
+    pub fn push(&mut self, job: impl Future<Output = T> + Send + 'static) {
+        let job: Job<T> = Box::pin(job);
+
+        if self.running.len() < self.concurrency {
+            self.running.push(job);
+        } else {
+            self.waiting.push_back(job);
+        }
+    }
+
+    /// Jobs whose output wasn't taken yet, running or waiting
+    pub fn len(&self) -> usize {
+        self.running.len() + self.waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Output of the oldest job once it's done, or `None` if there's none
+    ///
+    /// Cancel safe, so it can be raced against other work in a `select!`.
+    pub async fn next(&mut self) -> Option<T> {
+        let output = self.running.next().await?;
+
+        if let Some(job) = self.waiting.pop_front() {
+            self.running.push(job);
+        }
+
+        Some(output)
+    }
+
+    /// Run every job to the end, dropping their outputs
+    pub async fn finish(&mut self) {
+        while self.next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn outputs_keep_push_order() {
+        let mut queue = OrderedQueue::new(4);
+
+        // Later jobs finish first
+        for job in 0..8u64 {
+            queue.push(async move {
+                tokio::time::sleep(Duration::from_millis(40 - job * 5)).await;
+                job
+            });
+        }
+
+        let mut outputs = Vec::new();
+        while let Some(output) = queue.next().await {
+            outputs.push(output);
+        }
+
+        assert_eq!(outputs, (0..8).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn runs_at_most_concurrency_at_once() {
+        let mut queue = OrderedQueue::new(3);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..10 {
+            let (running, most) = (running.clone(), most.clone());
+
+            queue.push(async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+
+        assert_eq!(queue.len(), 10);
+        queue.finish().await;
+
+        assert!(queue.is_empty());
+        assert_eq!(most.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn one_at_a_time_runs_in_order() {
+        let mut queue = OrderedQueue::new(1);
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        for job in 0..5u64 {
+            let order = order.clone();
+
+            queue.push(async move {
+                order.lock().unwrap().push(("started", job));
+                tokio::time::sleep(Duration::from_millis(5 - job)).await;
+                order.lock().unwrap().push(("done", job));
+            });
+        }
+
+        queue.finish().await;
+
+        let expected: Vec<_> = (0..5).flat_map(|job| [("started", job), ("done", job)]).collect();
+        assert_eq!(*order.lock().unwrap(), expected);
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+
+/// How often buckets that refilled completely are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token bucket of one key
+struct Bucket {
+    /// Operations that can still be done right away
+    tokens: f64,
+
+    /// When `tokens` was last brought up to date
+    updated: Instant
+}
+
+/// Token buckets by key, each refilling at `rate` operations per second up to
+/// `burst` operations
+///
+/// Kept in-process, so the limit applies per node rather than cluster-wide.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<String, Bucket>
+}
+
+/// Rate limiter of channel operations by guild ID, or by channel ID for dms
+pub type GuildRateLimiter = Arc<RateLimiter>;
+
+impl RateLimiter {
+    /// Rate limiter allowing `rate` operations per second per key, with bursts
+    /// of up to `burst` operations, a rate of 0 disables it
+    pub fn new(rate: f64, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: DashMap::new()
+        }
+    }
+
+    /// Take a token from the bucket of `key`, gives how long until one is
+    /// available if it's empty
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(key.to_string()).or_insert(Bucket { tokens: self.burst, updated: now });
+
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Forget the buckets that refilled completely, they're the same as new
+    /// ones
+    fn sweep(&self) {
+        let now = Instant::now();
+
+        self.buckets.retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate < self.burst
+        });
+    }
+}
+
+/// Periodically forget the buckets of keys that went quiet
+pub async fn sweep(limiter: GuildRateLimiter) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        limiter.sweep();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_refused_until_refilled() {
+        let limiter = RateLimiter::new(10.0, 3);
+
+        for _ in 0..3 {
+            assert!(limiter.check("1").is_ok());
+        }
+
+        let retry_after = limiter.check("1").unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_millis(100), "Retry after {:?}", retry_after);
+
+        // Other keys have buckets of their own
+        assert!(limiter.check("2").is_ok());
+
+        std::thread::sleep(retry_after);
+        assert!(limiter.check("1").is_ok());
+    }
+
+    #[test]
+    fn zero_rate_never_limits() {
+        let limiter = RateLimiter::new(0.0, 1);
+
+        for _ in 0..100 {
+            assert!(limiter.check("1").is_ok());
+        }
+    }
+
+    #[test]
+    fn sweep_forgets_full_buckets() {
+        let limiter = RateLimiter::new(1000.0, 1);
+        limiter.check("1").unwrap();
+
+        std::thread::sleep(Duration::from_millis(5));
+        limiter.sweep();
+        assert!(limiter.buckets.is_empty());
+    }
+}
@@ -0,0 +1,623 @@
+use std::{env, mem, process};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use ::redis::{Client, Commands, Connection, ConnectionAddr, ConnectionInfo, ErrorKind, RedisResult, Script};
+use rand::Rng;
+use crate::config::Config;
+use crate::connections::{Connections, PendingCleanup, PendingCleanups};
+use crate::health::REDIS_UP;
+use crate::audit::AuditEvent;
+use crate::cluster::ClusterEvent;
+
+/// How often pending cleanups are checked for an expired grace period
+const CLEANUP_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a keepalive ping can take before Redis counts as unreachable
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Add the voice state ARGV[1] to the channel KEYS[1] with its session KEYS[2],
+/// owned by the connection ARGV[2] and ARGV[3..] being its flags as
+/// name/value pairs, as long as it isn't in the channel already
+const CREATE_VOICE_STATE: &str = r#"
+if redis.call('SADD', KEYS[1], ARGV[1]) == 0 then
+    return 0
+end
+
+redis.call('HSET', KEYS[2], 'channel', KEYS[1], 'connection', ARGV[2], unpack(ARGV, 3))
+
+return 1
+"#;
+
+/// Move a voice state from the channel KEYS[2] to KEYS[3], as long as its
+/// session KEYS[1] is still in KEYS[2] and KEYS[3] exists
+const MOVE_VOICE_STATE: &str = r#"
+if redis.call('HGET', KEYS[1], 'channel') ~= KEYS[2] then
+    return 0
+end
+
+if redis.call('EXISTS', KEYS[3]) == 0 then
+    return 0
+end
+
+redis.call('SREM', KEYS[2], ARGV[1])
+redis.call('SADD', KEYS[3], ARGV[1])
+redis.call('HSET', KEYS[1], 'channel', KEYS[3])
+
+return 1
+"#;
+
+/// Remove a voice state along with its session KEYS[1], as long as it's owned
+/// by the connection ARGV[2] if given
+///
+/// The channel is only known once the session is read, so it isn't declared
+/// in KEYS, which is fine outside of Redis Cluster.
+const DESTROY_VOICE_STATE: &str = r#"
+local session = redis.call('HMGET', KEYS[1], 'channel', 'connection')
+local channel = session[1]
+
+if not channel or (ARGV[2] and session[2] ~= ARGV[2]) then
+    return 0
+end
+
+redis.call('SREM', channel, ARGV[1])
+redis.call('DEL', KEYS[1])
+
+return 1
+"#;
+
+/// Swap the token ARGV[1] of the channel KEYS[1] for ARGV[2], as long as it's
+/// still a live token of the channel, KEYS[2] and KEYS[3] being the expiry
+/// keys of the old and new token and ARGV[3] the TTL in seconds (0 for none)
+const REFRESH_CHANNEL_TOKEN: &str = r#"
+if redis.call('SISMEMBER', KEYS[1], 'token_' .. ARGV[1]) == 0 then
+    return 0
+end
+
+local ttl = tonumber(ARGV[3])
+
+if ttl > 0 and redis.call('EXISTS', KEYS[2]) == 0 then
+    return 0
+end
+
+redis.call('SREM', KEYS[1], 'token_' .. ARGV[1])
+redis.call('DEL', KEYS[2])
+redis.call('SADD', KEYS[1], 'token_' .. ARGV[2])
+
+if ttl > 0 then
+    redis.call('SET', KEYS[3], KEYS[1], 'EX', ttl)
+end
+
+return 1
+"#;
+
+/// Remove the channel KEYS[1] along with the sessions of its voice states and
+/// the expiry keys of its tokens, giving everything that was in it
+///
+/// Like [`DESTROY_VOICE_STATE`], the keys that go with the members aren't
+/// declared in KEYS.
+const DESTROY_CHANNEL: &str = r#"
+local members = redis.call('SMEMBERS', KEYS[1])
+
+for _, member in ipairs(members) do
+    if string.sub(member, 1, 6) == 'token_' then
+        redis.call('DEL', string.sub(member, 7) .. '_token')
+    else
+        redis.call('DEL', member .. '_session')
+    end
+end
+
+redis.call('DEL', KEYS[1])
+
+return members
+"#;
+
+/// Channel whose state is kept in Redis
+pub struct ChannelKey {
+    /// Guild ID, `dm` for dms / group dms
+    pub guild: String,
+
+    /// Channel ID
+    pub channel: String
+}
+
+impl ChannelKey {
+    pub fn new(guild_id: Option<&str>, channel_id: &str) -> ChannelKey {
+        ChannelKey {
+            guild: guild_id.unwrap_or("dm").to_string(),
+            channel: channel_id.to_string()
+        }
+    }
+
+    /// Key of the set holding the channel's tokens and voice states,
+    /// `{guild}_{channel}_voice`, so `dm_{channel}_voice` for dms
+    pub fn to_redis_key(&self) -> String {
+        format!("{}_{}_voice", self.guild, self.channel)
+    }
+
+    /// Channel of a key made by [`ChannelKey::to_redis_key`]
+    pub fn from_redis_key(key: &str) -> Option<ChannelKey> {
+        let (guild, channel) = key.strip_suffix("_voice")?.split_once('_')?;
+
+        Some(ChannelKey { guild: guild.to_string(), channel: channel.to_string() })
+    }
+
+    /// Guild ID, None for dms / group dms
+    pub fn guild_id(&self) -> Option<&str> {
+        (self.guild != "dm").then_some(self.guild.as_str())
+    }
+
+    /// Key the channel's Redis work is routed to a shard by, its guild, or the
+    /// channel itself for dms so they don't all end up on one shard
+    pub fn shard_key(&self) -> &str {
+        self.guild_id().unwrap_or(&self.channel)
+    }
+}
+
+/// Voice state as kept in its session
+pub struct VoiceState {
+    /// Channel it's in
+    pub channel: ChannelKey,
+
+    /// Connection that owns it
+    pub connection: String,
+
+    /// Muted by the guild
+    pub mute: bool,
+
+    /// Deafened by the guild
+    pub deaf: bool,
+
+    /// Muted by the user
+    pub self_mute: bool,
+
+    /// Deafened by the user
+    pub self_deaf: bool
+}
+
+/// Read a voice state, gives None if it doesn't exist
+///
+/// A session with a channel key this server doesn't make doesn't count as a
+/// voice state.
+pub fn get_voice_state(redis: &mut Connection, session_id: &str) -> RedisResult<Option<VoiceState>> {
+    let session: HashMap<String, String> = redis.hgetall(format!("{}_session", session_id))?;
+    let flag = |name: &str| session.get(name).map(|flag| flag == "1").unwrap_or(false);
+
+    let channel = session.get("channel").and_then(|channel_key| ChannelKey::from_redis_key(channel_key));
+
+    Ok(match (channel, session.get("connection")) {
+        (Some(channel), Some(connection)) => Some(VoiceState {
+            channel,
+            connection: connection.clone(),
+            mute: flag("mute"),
+            deaf: flag("deaf"),
+            self_mute: flag("self_mute"),
+            self_deaf: flag("self_deaf")
+        }),
+        _ => None
+    })
+}
+
+/// Add a voice state to a channel, owned by `conn_id`, gives false if the
+/// session ID is already in the channel
+///
+/// Done in a script so the voice state is never in the channel without a
+/// session, where a destroy or a move would pass it over.
+pub fn create_voice_state(redis: &mut Connection, channel_key: &str, session_id: &str, conn_id: &str, flags: &[(&str, bool)]) -> RedisResult<bool> {
+    let script = Script::new(CREATE_VOICE_STATE);
+    let mut invocation = script.key(channel_key);
+    invocation.key(format!("{}_session", session_id)).arg(session_id).arg(conn_id);
+
+    for (name, value) in flags {
+        invocation.arg(*name).arg(*value);
+    }
+
+    invocation.invoke(redis)
+}
+
+/// Key holding the channel of a token for as long as the token is valid, only
+/// set when channel tokens expire
+fn token_key(token: &str) -> String {
+    format!("{}_token", token)
+}
+
+/// Add a token to a channel, expiring after `ttl` if there's one, gives false
+/// if the token is already in the channel
+///
+/// The token stays in the channel set after it expires, until it's refreshed
+/// or the channel is destroyed, so checking it takes both keys.
+pub fn add_channel_token(redis: &mut Connection, channel_key: &str, token: &str, ttl: Option<Duration>) -> RedisResult<bool> {
+    let mut pipe = ::redis::pipe();
+    pipe.atomic().sadd(channel_key, format!("token_{}", token));
+
+    if let Some(ttl) = ttl {
+        pipe.set_ex(token_key(token), channel_key, ttl.as_secs() as usize).ignore();
+    }
+
+    let (added,): (i64,) = pipe.query(redis)?;
+
+    Ok(added == 1)
+}
+
+/// Check that a token was given out for a channel and, if tokens expire,
+/// hasn't expired yet
+pub fn check_channel_token(redis: &mut Connection, channel_key: &str, token: &str, expires: bool) -> RedisResult<bool> {
+    if !redis.sismember(channel_key, format!("token_{}", token))? {
+        return Ok(false);
+    }
+
+    if expires {
+        return redis.exists(token_key(token));
+    }
+
+    Ok(true)
+}
+
+/// Replace a live token of a channel with `new`, expiring after `ttl` if
+/// there's one, gives false if `old` isn't a live token of the channel
+///
+/// Done in a script so the channel can't be destroyed in between and come
+/// back holding only the new token.
+pub fn refresh_channel_token(redis: &mut Connection, channel_key: &str, old: &str, new: &str, ttl: Option<Duration>) -> RedisResult<bool> {
+    Script::new(REFRESH_CHANNEL_TOKEN)
+        .key(channel_key)
+        .key(token_key(old))
+        .key(token_key(new))
+        .arg(old)
+        .arg(new)
+        .arg(ttl.map(|ttl| ttl.as_secs()).unwrap_or(0))
+        .invoke(redis)
+}
+
+/// Remove a voice state, gives false if it doesn't exist or isn't owned by
+/// `owner`, any owner goes if None
+///
+/// Done in a script so it can't interleave with a move and leave the voice
+/// state in the channel it was moved to.
+pub fn destroy_voice_state(redis: &mut Connection, session_id: &str, owner: Option<&str>) -> RedisResult<bool> {
+    let script = Script::new(DESTROY_VOICE_STATE);
+    let mut invocation = script.key(format!("{}_session", session_id));
+    invocation.arg(session_id);
+
+    if let Some(owner) = owner {
+        invocation.arg(owner);
+    }
+
+    invocation.invoke(redis)
+}
+
+/// Move a voice state from the channel `from` to the channel `to`, gives
+/// false if it isn't in `from` anymore or `to` doesn't exist
+///
+/// Done in a script so the voice state is never in both channels or in
+/// neither, even with a destroy or another move going on at the same time.
+pub fn move_voice_state(redis: &mut Connection, session_id: &str, from: &str, to: &str) -> RedisResult<bool> {
+    Script::new(MOVE_VOICE_STATE)
+        .key(format!("{}_session", session_id))
+        .key(from)
+        .key(to)
+        .arg(session_id)
+        .invoke(redis)
+}
+
+/// Fetch and remove the nonce of a connection in one step, gives None if it
+/// was already used or never stored
+///
+/// A nonce verifies a single IDENTIFY or RESUME, whether it went through or
+/// not, so the same one can never check two tokens.
+pub fn take_nonce(redis: &mut Connection, conn_id: &str) -> RedisResult<Option<String>> {
+    ::redis::cmd("GETDEL").arg(format!("{}_nonce", conn_id)).query(redis)
+}
+
+/// Record an IDENTIFY or RESUME attempt of `peer` to the auth audit stream,
+/// trimmed to about `auth_audit_max_len` entries, `fields` being those of
+/// [`AuditEvent::auth_attempt_fields`]
+///
+/// Does nothing if there's no stream configured.
+pub fn record_auth_attempt(redis: &mut Connection, config: &Config, peer: &str, mut fields: Vec<(&'static str, String)>) -> RedisResult<()> {
+    let stream = match &config.auth_audit_stream {
+        Some(stream) => stream,
+        None => return Ok(())
+    };
+
+    fields.push(("node", config.node_id.clone()));
+    fields.push(("peer", peer.to_string()));
+
+    ::redis::cmd("XADD")
+        .arg(stream)
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(config.auth_audit_max_len)
+        .arg("*")
+        .arg(fields)
+        .query(redis)
+}
+
+/// Remove a channel along with its voice states, gives the voice states that
+/// were in it or None if it doesn't exist
+///
+/// Done in a script so a voice state or token added while it runs can't
+/// outlive the channel.
+pub fn destroy_channel(redis: &mut Connection, channel_key: &str) -> RedisResult<Option<Vec<String>>> {
+    let members: Vec<String> = Script::new(DESTROY_CHANNEL).key(channel_key).invoke(redis)?;
+
+    if members.is_empty() {
+        return Ok(None);
+    }
+
+    // Everything in the channel but its tokens is a voice state
+    Ok(Some(members.into_iter().filter(|member| !member.starts_with("token_")).collect()))
+}
+
+/// Build the connection info from `REDIS_ADDR`, with the credentials and TLS
+/// settings from the config applied on top
+fn connection_info(config: &Config) -> ConnectionInfo {
+    let mut info = config.redis_addr.clone();
+
+    if config.redis_username.is_some() {
+        info.redis.username = config.redis_username.clone();
+    }
+
+    if config.redis_password.is_some() {
+        info.redis.password = config.redis_password.clone();
+    }
+
+    // The redis crate SELECTs this on every new connection, so it sticks
+    // across reconnects
+    if let Some(db) = config.redis_db {
+        info.redis.db = i64::from(db);
+    }
+
+    if config.redis_tls {
+        if let ConnectionAddr::Tcp(host, port) = info.addr {
+            info.addr = ConnectionAddr::TcpTls { host, port, insecure: false };
+        }
+    }
+
+    info
+}
+
+/// Point OpenSSL, which native-tls goes through, at REDIS_TLS_CA
+///
+/// It picks its CA bundle from the environment, so this has to run before the
+/// runtime starts any thread, writing the environment isn't thread-safe.
+pub fn use_tls_ca(config: &Config) {
+    if let Some(ca) = config.redis_tls_ca.as_ref().filter(|_| config.redis_tls) {
+        env::set_var("SSL_CERT_FILE", ca);
+    }
+}
+
+/// Connect to Redis once and ping it, without retrying, for `--check`
+pub fn check_redis(config: &Config) -> RedisResult<()> {
+    let client = Client::open(connection_info(config))?;
+    let mut redis = client.get_connection_with_timeout(config.redis_connect_timeout)?;
+
+    ::redis::cmd("PING").query(&mut redis)
+}
+
+/// Open the Redis client and wait until the server can be reached, retrying
+/// with exponential backoff and jitter for up to `redis_connect_timeout` or
+/// `redis_max_attempts` attempts.
+///
+/// The backoff starts at `redis_retry_delay` and doubles up to
+/// `redis_max_retry_delay`, each wait is picked between half and all of it.
+///
+/// Exits the process if Redis is still unreachable after that or if it rejects
+/// the credentials, there's not much a voice server can do without it.
+pub async fn connect_redis(config: &Config) -> Client {
+    let info = connection_info(config);
+
+    // Never log the password
+    info!(
+        "Connecting to Redis at {} db {} (tls: {}, user: {}, password: {})",
+        &info.addr,
+        info.redis.db,
+        matches!(info.addr, ConnectionAddr::TcpTls { .. }),
+        info.redis.username.as_deref().unwrap_or("default"),
+        if info.redis.password.is_some() { "<redacted>" } else { "none" }
+    );
+
+    let client = Client::open(info).expect("Invalid Redis URL!");
+
+    let deadline = Instant::now() + config.redis_connect_timeout;
+    let mut delay = config.redis_retry_delay.min(config.redis_max_retry_delay);
+    let mut attempt = 1;
+
+    loop {
+        match client.get_connection_with_timeout(config.redis_max_retry_delay) {
+            Ok(_) => return client,
+            Err(e) if e.kind() == ErrorKind::AuthenticationFailed => {
+                error!("Redis rejected the credentials, check REDIS_USERNAME and REDIS_PASSWORD: {}", e);
+                process::exit(1);
+            },
+            Err(e) => {
+                if Instant::now() + delay > deadline || attempt == config.redis_max_attempts {
+                    error!("Failed to connect to Redis after {} attempts, giving up: {}", attempt, e);
+                    process::exit(1);
+                }
+
+                // Sleep somewhere between half and all of the delay, so a bunch
+                // of instances starting together don't retry in lockstep
+                let jittered = rand::thread_rng().gen_range(delay / 2..=delay);
+                warn!("Failed to connect to Redis (attempt {}), retrying in {:?}: {}", attempt, jittered, e);
+
+                tokio::time::sleep(jittered).await;
+
+                delay = (delay * 2).min(config.redis_max_retry_delay);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Ping Redis every `interval` so a dropped connection shows up in the health
+/// and `/healthz` before a request runs into it
+///
+/// Runs forever, the connection is opened again on the next ping after one
+/// fails.
+pub async fn keepalive(client: Client, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    let mut redis: Option<Connection> = None;
+
+    loop {
+        interval.tick().await;
+
+        let result = ping(&client, &mut redis);
+
+        if result.is_err() {
+            redis = None;
+        }
+
+        let up = result.is_ok();
+
+        // Only log when it changes, not on every ping
+        if REDIS_UP.swap(up, Ordering::Relaxed) != up {
+            match result {
+                Ok(()) => info!(target: "keepalive", "Redis is reachable again!"),
+                Err(e) => error!(target: "keepalive", "Redis is unreachable: {}", e)
+            }
+        }
+    }
+}
+
+/// Ping Redis, opening the connection first if there's none
+fn ping(client: &Client, redis: &mut Option<Connection>) -> RedisResult<()> {
+    if redis.is_none() {
+        let connection = client.get_connection_with_timeout(PING_TIMEOUT)?;
+        connection.set_read_timeout(Some(PING_TIMEOUT))?;
+
+        *redis = Some(connection);
+    }
+
+    ::redis::cmd("PING").query(redis.as_mut().unwrap())
+}
+
+/// Remove the state of dropped sessions once their grace period runs out
+///
+/// Runs forever, cleanups that fail are kept and retried on the next sweep.
+pub async fn cleanup_sweep(client: Client, node_id: String, pending_cleanups: PendingCleanups) {
+    let mut interval = tokio::time::interval(CLEANUP_SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let now = Instant::now();
+        clean_up(&client, &node_id, &pending_cleanups, |cleanup| cleanup.expires <= now);
+    }
+}
+
+/// Remove the state of every session right away, grace period or not, for
+/// shutdown
+///
+/// Connections still open hand over what they own first, so nothing is left
+/// behind for sessions nobody can RESUME on this node anymore.
+pub fn cleanup_all(client: &Client, node_id: &str, pending_cleanups: &PendingCleanups, connections: &Connections) {
+    // Locked before the connections, like a connection going away does
+    let mut cleanups = pending_cleanups.lock().unwrap();
+
+    for mut connection in connections.iter_mut() {
+        if let Some(session_id) = connection.session_id.clone() {
+            let channels = mem::take(&mut connection.channels);
+            let voice_states = mem::take(&mut connection.voice_states);
+
+            if !channels.is_empty() || !voice_states.is_empty() {
+                cleanups.insert(session_id, PendingCleanup { expires: Instant::now(), channels, voice_states });
+            }
+        }
+    }
+
+    drop(cleanups);
+
+    clean_up(client, node_id, pending_cleanups, |_| true);
+
+    let left = pending_cleanups.lock().unwrap().len();
+    if left > 0 {
+        error!(target: "cleanup", "Failed to clean up {} sessions, their state stays in Redis", left);
+    }
+}
+
+/// Remove the state of the pending cleanups that are `due`, keeping the ones
+/// that fail for the next try
+fn clean_up(client: &Client, node_id: &str, pending_cleanups: &PendingCleanups, due: impl Fn(&PendingCleanup) -> bool) {
+    let due: Vec<String> = pending_cleanups.lock().unwrap()
+        .iter()
+        .filter(|(_, cleanup)| due(cleanup))
+        .map(|(session_id, _)| session_id.clone())
+        .collect();
+
+    if due.is_empty() {
+        return;
+    }
+
+    let mut redis = match client.get_connection() {
+        Ok(redis) => redis,
+        Err(e) => {
+            warn!(target: "cleanup", "Failed to get Redis connection, retrying cleanup later: {}", e);
+            return;
+        }
+    };
+
+    for session_id in due {
+        // Might have been resumed since
+        let cleanup = match pending_cleanups.lock().unwrap().remove(&session_id) {
+            Some(cleanup) => cleanup,
+            None => continue
+        };
+
+        match remove_state(&mut redis, node_id, &cleanup) {
+            Ok(()) => debug!(target: "cleanup", "Cleaned up session {}", &session_id),
+            Err(e) => {
+                warn!(target: "cleanup", "Failed to clean up session {}, retrying later: {}", &session_id, e);
+                pending_cleanups.lock().unwrap().insert(session_id, cleanup);
+            }
+        }
+    }
+}
+
+/// Remove the channels and voice states left behind by a session, along with
+/// the tokens of the channels
+///
+/// Goes through the same scripts and functions as VST_DESTROY and
+/// CHANNEL_DESTROY, so nothing it leaves behind differs from those.
+fn remove_state(redis: &mut Connection, node_id: &str, cleanup: &PendingCleanup) -> RedisResult<()> {
+    for session_id in &cleanup.voice_states {
+        if destroy_voice_state(redis, session_id, None)? {
+            AuditEvent::VoiceStateDestroyed { conn_id: None, session_id, reason: "session expired" }.emit();
+        }
+    }
+
+    for channel_key in &cleanup.channels {
+        if let Some(voice_states) = destroy_channel(redis, channel_key)? {
+            for session_id in &voice_states {
+                AuditEvent::VoiceStateDestroyed { conn_id: None, session_id, reason: "session expired" }.emit();
+            }
+
+            AuditEvent::ChannelDestroyed { conn_id: None, channel: channel_key, reason: "session expired" }.emit();
+            ClusterEvent::ChannelDestroyed { node: node_id.to_string(), channel: channel_key.clone() }.publish(redis);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dm_keys_are_apart_from_guild_keys() {
+        let dm = ChannelKey::new(None, "1");
+        let guild = ChannelKey::new(Some("1"), "1");
+
+        assert_eq!(dm.to_redis_key(), "dm_1_voice");
+        assert_eq!(guild.to_redis_key(), "1_1_voice");
+
+        let dm = ChannelKey::from_redis_key("dm_1_voice").unwrap();
+        assert_eq!((dm.guild_id(), dm.channel.as_str()), (None, "1"));
+        assert_eq!(dm.shard_key(), "1");
+
+        let guild = ChannelKey::from_redis_key("2_1_voice").unwrap();
+        assert_eq!((guild.guild_id(), guild.channel.as_str()), (Some("2"), "1"));
+        assert_eq!(guild.shard_key(), "2");
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+
+use crate::infoops::{InfoData, InfoType};
+use crate::opcodes::{ErrorCode, MessageData, OpCode, SocketMessage};
+
+/// Write the JSON Schema of the protocol messages into `dir`, one file per type
+pub fn write_schemas(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    write_schema(dir, "SocketMessage", schema_for!(SocketMessage))?;
+    write_schema(dir, "MessageData", schema_for!(MessageData))?;
+    write_schema(dir, "OpCode", schema_for!(OpCode))?;
+    write_schema(dir, "ErrorCode", schema_for!(ErrorCode))?;
+    write_schema(dir, "InfoData", schema_for!(InfoData))?;
+    write_schema(dir, "InfoType", schema_for!(InfoType))?;
+
+    Ok(())
+}
+
+fn write_schema(dir: &Path, name: &str, schema: RootSchema) -> io::Result<()> {
+    let path = dir.join(format!("{}.json", name));
+    fs::write(&path, serde_json::to_string_pretty(&schema)?)?;
+
+    info!("Wrote {}", path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use serde_json::Value;
+    use crate::util::generate_token;
+
+    const TYPES: [&str; 6] = ["SocketMessage", "MessageData", "OpCode", "ErrorCode", "InfoData", "InfoType"];
+
+    #[test]
+    fn every_type_gets_a_schema() {
+        let dir = env::temp_dir().join(format!("bannana-pho-schema-{}", generate_token(8, false)));
+        write_schemas(&dir).unwrap();
+
+        for name in TYPES {
+            let schema: Value = serde_json::from_str(&fs::read_to_string(dir.join(format!("{}.json", name))).unwrap()).unwrap();
+            assert_eq!(schema["title"], name);
+        }
+
+        // Nested types end up as definitions of the types holding them
+        let message: Value = serde_json::from_str(&fs::read_to_string(dir.join("SocketMessage.json")).unwrap()).unwrap();
+        for definition in ["OpCode", "MessageData", "InfoData", "InfoType", "ErrorCode", "Health", "VoiceMode"] {
+            assert!(message["definitions"].get(definition).is_some(), "No {} in SocketMessage", definition);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
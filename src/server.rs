@@ -0,0 +1,1705 @@
+use std::future::{self, Future};
+use std::io::Error;
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify, OwnedSemaphorePermit, Semaphore};
+
+use futures_util::{SinkExt, StreamExt};
+use futures_util::stream::SplitSink;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use crate::opcodes::{claimed_opcode, get_opcode, unknown_fields, CloseAdvice, ErrorCode, HeartbeatAckCache, MessageData, OpCode, SocketMessage};
+
+use crate::infoops::{InfoData, InfoType, ServerLimits, CHANNEL_ASSIGN, CHANNEL_DESTROY, CHANNEL_DESTROY_ACK, CHANNEL_EXISTS_RESULT, CHANNEL_TOKEN_REFRESH_ACK, SERVER_INFO, SESSION_LIST, VST_DESTROY, VST_DESTROY_ACK, VST_DONE, VST_UPDATE, VST_UPDATE_ACK};
+
+use ::redis::{Client, RedisResult};
+use crate::{cluster, connections, logging, metrics, ratelimit, redis, version};
+
+use crate::util::{generate_token, heartbeat_deviates, jittered_heartbeat_interval, log_raw_frame, server_proof, verify_token, TokenError, CONNECTION_ID_LENGTH, NONCE_LENGTH};
+use crate::connections::{Connection, Connections, HandshakeInfo, Outbound, PendingCleanup, PendingCleanups};
+use crate::config::Config;
+use crate::metrics::{ErrorCategory, METRICS};
+use crate::health::{compute_health, PAUSED, WARMED_UP};
+use crate::audit::AuditEvent;
+use crate::listener::{Listener, Stream};
+use crate::cluster::{ChannelIndex, ClusterEvent, DRAINING};
+use crate::redis::{add_channel_token, check_channel_token, create_voice_state, destroy_channel, destroy_voice_state, get_voice_state, move_voice_state, record_auth_attempt, refresh_channel_token, take_nonce, ChannelKey, VoiceState};
+use crate::shards::Shards;
+use crate::ordered::OrderedQueue;
+use crate::voice::negotiate_mode;
+use crate::ratelimit::{GuildRateLimiter, RateLimiter};
+
+use ::redis::Commands;
+
+/// State shared by every connection, handed to each of them in an `Arc`
+pub struct ServerState {
+    pub config: Config,
+    pub redis_client: Client,
+    pub connections: Connections,
+    pub pending_cleanups: PendingCleanups,
+    pub channel_index: ChannelIndex,
+    pub guild_rate_limiter: GuildRateLimiter,
+
+    /// Where the connections' Redis work runs
+    pub shards: Shards,
+
+    /// Turns true on shutdown, connections close as it does
+    pub shutdown: watch::Receiver<bool>
+}
+
+/// How long connections get to close on shutdown before what they own is
+/// removed from Redis anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the websocket on `listener` until `shutdown` completes
+pub async fn serve(config: Config, listener: Listener, shutdown: impl Future<Output = ()>) -> Result<(), Error> {
+    let redis_client = redis::connect_redis(&config).await;
+
+    // Turns true on shutdown, for the connections and whatever else needs to
+    // stop along with the accept loop
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+
+    let shards = Shards::start(&redis_client, config.redis_shards);
+    info!("Spreading Redis work over {} shards", config.redis_shards);
+
+    let guild_rate_limiter = GuildRateLimiter::new(RateLimiter::new(config.guild_channel_rate, config.guild_channel_burst));
+    let state = Arc::new(ServerState {
+        config,
+        redis_client,
+        connections: Connections::default(),
+        pending_cleanups: PendingCleanups::default(),
+        channel_index: ChannelIndex::default(),
+        guild_rate_limiter,
+        shards,
+        shutdown: shutdown_receiver.clone()
+    });
+    let ServerState { config, redis_client, connections, pending_cleanups, channel_index, guild_rate_limiter, .. } = &*state;
+
+    cluster::subscribe(redis_client.clone(), config.node_id.clone(), channel_index.clone(), connections.clone(), pending_cleanups.clone());
+
+    tokio::spawn(redis::cleanup_sweep(redis_client.clone(), config.node_id.clone(), pending_cleanups.clone()));
+    tokio::spawn(redis::keepalive(redis_client.clone(), config.redis_ping_interval));
+    tokio::spawn(ratelimit::sweep(guild_rate_limiter.clone()));
+
+    let metrics_server = config.metrics_addr.as_ref().map(|metrics_addr| {
+        tokio::spawn(metrics::serve(metrics_addr.clone(), shutdown_receiver.clone()))
+    });
+
+    let listen_addr = listener.local_addr();
+    info!("Listening on {}!", &listen_addr);
+
+    match &listener {
+        _ if config.require_tls => info!("TLS required, only taking connections from the proxy in front of {}", &listen_addr),
+        Listener::Tcp(_) => warn!("Serving the websocket in plaintext on {}, put it behind a TLS-terminating proxy outside of local testing!", &listen_addr),
+        Listener::Unix(..) => info!("Serving the websocket in plaintext on {}, TLS is left to the proxy in front of it", &listen_addr)
+    }
+
+    let connection_slots = Arc::new(Semaphore::new(config.max_connections));
+
+    tokio::pin!(shutdown);
+
+    let mut drain = signal(SignalKind::user_defined1()).expect("Failed to listen for SIGUSR1!");
+    let mut pause = signal(SignalKind::user_defined2()).expect("Failed to listen for SIGUSR2!");
+    let mut reload = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP!");
+
+    loop {
+        tokio::select! {
+            accepted = accept_with_slot(&listener, &connection_slots) => {
+                let (stream, peer, slot) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => break
+                };
+
+                let conn_id: String = generate_token(CONNECTION_ID_LENGTH, config.unambiguous_tokens);
+
+                info!(target: "initial", "Connecting to peer {} as {}...", &peer, &conn_id);
+                AuditEvent::ConnectionOpened { conn_id: &conn_id, peer: &peer }.emit();
+                METRICS.connections.fetch_add(1, Ordering::Relaxed);
+
+                match stream {
+                    Stream::Tcp(stream) => tokio::spawn(logging::CONN_ID.scope(conn_id.clone(), accept_conn(state.clone(), conn_id, peer, stream, slot))),
+                    Stream::Unix(stream) => tokio::spawn(logging::CONN_ID.scope(conn_id.clone(), accept_conn(state.clone(), conn_id, peer, stream, slot)))
+                };
+            },
+            _ = drain.recv() => {
+                cluster::drain(redis_client, &config.node_id, connections, pending_cleanups);
+            },
+            _ = pause.recv() => {
+                // fetch_xor gives the previous state
+                if PAUSED.fetch_xor(true, Ordering::Relaxed) {
+                    info!("Accepting new connections again!");
+                } else {
+                    info!("Paused accepting new connections, existing ones stay up!");
+                }
+            },
+            _ = reload.recv() => {
+                config.reload_secrets();
+            },
+            _ = &mut shutdown => {
+                info!("Shutting down!");
+                break;
+            }
+        }
+    }
+
+    listener.cleanup();
+    let _ = shutdown_sender.send(true);
+
+    // Every connection gives its slot back once it's closed and what it owned
+    // is pending cleanup
+    let all_slots = u32::try_from(config.max_connections).unwrap_or(u32::MAX);
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, connection_slots.acquire_many(all_slots)).await.is_err() {
+        warn!("Connections still open after {:?}, cleaning up without them", SHUTDOWN_TIMEOUT);
+    }
+
+    redis::cleanup_all(redis_client, &config.node_id, pending_cleanups, connections);
+
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.await.expect("Metrics server panicked!");
+    }
+
+    Ok(())
+}
+
+/// Wait for a free connection slot, then accept a connection into it
+///
+/// While every slot is taken new connections wait in the listen backlog, no
+/// task is spawned for them until one closes.
+async fn accept_with_slot(listener: &Listener, slots: &Arc<Semaphore>) -> std::io::Result<(Stream, String, OwnedSemaphorePermit)> {
+    if slots.available_permits() == 0 {
+        warn!(target: "initial", "At the connection limit, waiting for a connection to close before accepting more");
+    }
+
+    let slot = slots.clone().acquire_owned().await.expect("Connection slots closed!");
+    let (stream, peer) = listener.accept().await?;
+
+    Ok((stream, peer, slot))
+}
+
+/// Wait for SIGINT or SIGTERM
+pub async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM!");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {}
+    }
+}
+
+async fn accept_conn<S: AsyncRead + AsyncWrite + Unpin + Send>(state: Arc<ServerState>, conn_id: String, peer: String, stream: S, _slot: OwnedSemaphorePermit) {
+    let ServerState { config, connections, pending_cleanups, shards, .. } = &*state;
+    let mut requests = OrderedQueue::new(config.info_concurrency);
+    let result = handle_conn(&state, conn_id.clone(), peer.clone(), stream, &mut requests).await;
+
+    // What it asked for before it went away still happens, so it's owned by
+    // the session when the cleanup comes around
+    requests.finish().await;
+
+    // Handed over to the pending cleanups under their lock, so a kick or
+    // teardown forgetting what it removed finds it in one or the other
+    {
+        let mut cleanups = pending_cleanups.lock().unwrap();
+        let connection = connections.remove(&conn_id).map(|(_, connection)| connection);
+
+        // Keep what the session owns around for a bit in case it resumes
+        if let Some(Connection { session_id: Some(session_id), channels, voice_states, resumable, .. }) = connection {
+            if !channels.is_empty() || !voice_states.is_empty() {
+                // Left to the next cleanup sweep, which retries if Redis fails
+                let grace_period = if resumable { config.session_grace_period } else { Duration::ZERO };
+                debug!(target: "socket", "Keeping session {} of {} for {:?}", &session_id, &conn_id, &grace_period);
+
+                cleanups.insert(session_id, PendingCleanup {
+                    expires: Instant::now() + grace_period,
+                    channels,
+                    voice_states
+                });
+            }
+        }
+    }
+
+    // The nonce is only good for this connection
+    let nonce_key = format!("{}_nonce", conn_id);
+    if let Err(e) = shards.run(&conn_id, move |redis| redis.del::<_, ()>(nonce_key)).await {
+        warn!(target: "socket", "Failed to remove nonce of {}: {}", &conn_id, e);
+    }
+
+    let reason = match &result {
+        Ok(()) => "closed",
+        Err(e) => ErrorCategory::of(e).map(|category| category.as_str()).unwrap_or("closed")
+    };
+    AuditEvent::ConnectionClosed { conn_id: &conn_id, reason }.emit();
+
+    if let Err(e) = result {
+        if let Some(category) = ErrorCategory::of(&e) {
+            METRICS.connection_error(category);
+
+            match category {
+                ErrorCategory::Protocol => debug!(target: "socket", "Protocol error from {} ({}): {}", &conn_id, &peer, e),
+                ErrorCategory::Io | ErrorCategory::Tls => warn!(target: "socket", "Connection {} ({}) failed: {}", &conn_id, &peer, e),
+                ErrorCategory::Other => error!(target: "socket", "Error on connection {} ({}): {}", &conn_id, &peer, e)
+            }
+        }
+    }
+}
+
+/// Sending half of a connection
+struct WsSender<S> {
+    sink: SplitSink<WebSocketStream<S>, Message>,
+
+    /// Notified once the connection is found too slow, see
+    /// [`connections::send_to`]
+    too_slow: Arc<Notify>
+}
+
+/// How long a closing connection gets to take the close frame, it might not be
+/// reading anymore
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Send a message to the connection, logging the raw frame if enabled
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(ws_sender: &mut WsSender<S>, config: &Config, conn_id: &str, msg: Message) -> tokio_tungstenite::tungstenite::Result<()> {
+    if config.log_raw_frames {
+        log_raw_frame("out", conn_id, &msg);
+    }
+
+    // A peer that stopped reading would hold the send forever, and the
+    // connection with it. Once it's found too slow the send is given up on,
+    // the connection is closed right after
+    tokio::select! {
+        result = ws_sender.sink.send(msg) => result,
+        _ = ws_sender.too_slow.notified() => {
+            ws_sender.too_slow.notify_one();
+
+            Ok(())
+        }
+    }
+}
+
+/// Send a protocol message to the connection
+///
+/// Messages whose data doesn't go with their opcode are a bug on our side,
+/// they're logged and dropped instead of being sent.
+async fn send_message<S: AsyncRead + AsyncWrite + Unpin>(ws_sender: &mut WsSender<S>, config: &Config, conn_id: &str, msg: &SocketMessage) -> tokio_tungstenite::tungstenite::Result<()> {
+    if let Err(expected) = msg.validate() {
+        error!(target: "socket", "Not sending {:?} to {} with data for {:?}: {:?}", &msg.op, conn_id, expected, &msg.d);
+        return Ok(());
+    }
+
+    send(ws_sender, config, conn_id, Message::Text(serde_json::to_string(msg).unwrap())).await
+}
+
+/// Answer the connection with an ERROR carrying `code`, it stays open
+async fn send_error<S: AsyncRead + AsyncWrite + Unpin>(ws_sender: &mut WsSender<S>, config: &Config, conn_id: &str, code: ErrorCode) -> tokio_tungstenite::tungstenite::Result<()> {
+    send_message(ws_sender, config, conn_id, &SocketMessage::error(code)).await
+}
+
+/// Answer the connection with a RATE_LIMITED ERROR telling it when to retry,
+/// it stays open
+async fn send_rate_limited<S: AsyncRead + AsyncWrite + Unpin>(ws_sender: &mut WsSender<S>, config: &Config, conn_id: &str, retry_after: Duration) -> tokio_tungstenite::tungstenite::Result<()> {
+    send_message(ws_sender, config, conn_id, &SocketMessage::rate_limited(retry_after.as_millis() as u64)).await
+}
+
+/// Close the connection with `code` and its reconnect advisory, with `reason`
+/// or the message of the code as the reason
+///
+/// Only sends the close frame, the caller stops handling the connection.
+async fn close_with_error<S: AsyncRead + AsyncWrite + Unpin>(ws_sender: &mut WsSender<S>, config: &Config, conn_id: &str, code: ErrorCode, reason: Option<&str>) -> tokio_tungstenite::tungstenite::Result<()> {
+    let frame = match reason {
+        Some(reason) => code.close_frame_with(reason),
+        None => code.close_frame()
+    };
+
+    send(ws_sender, config, conn_id, Message::Close(Some(frame))).await
+}
+
+/// Close the connection with 1001 (going away) on shutdown, advising to
+/// reconnect right away, to another node
+///
+/// Only sends the close frame, the caller stops handling the connection.
+async fn close_going_away<S: AsyncRead + AsyncWrite + Unpin>(ws_sender: &mut WsSender<S>, config: &Config, conn_id: &str) -> tokio_tungstenite::tungstenite::Result<()> {
+    let advice = CloseAdvice { reason: "Server shutting down".to_string(), reconnectable: true, retry_after_ms: None };
+    let frame = CloseFrame { code: CloseCode::Away, reason: serde_json::to_string(&advice).unwrap().into() };
+
+    send(ws_sender, config, conn_id, Message::Close(Some(frame))).await
+}
+
+/// Close reason for connections Redis failed under
+const REDIS_UNAVAILABLE: &str = "Redis unavailable, try again later";
+
+/// Answer a message Redis failed on with GENERAL and close the connection,
+/// what its session owns is kept for the grace period so it can RESUME once
+/// Redis is back
+///
+/// The caller stops handling the connection.
+async fn close_on_redis_error<S: AsyncRead + AsyncWrite + Unpin>(ws_sender: &mut WsSender<S>, config: &Config, conn_id: &str) -> tokio_tungstenite::tungstenite::Result<()> {
+    send_error(ws_sender, config, conn_id, ErrorCode::GENERAL).await?;
+    close_with_error(ws_sender, config, conn_id, ErrorCode::GENERAL, Some(REDIS_UNAVAILABLE)).await
+}
+
+/// Count a message an unidentified connection wasn't allowed to send,
+/// answering it with `code` unless `quiet_pre_auth` is set
+///
+/// Gives true once the connection sent too many of them and was closed.
+async fn pre_auth_violation<S: AsyncRead + AsyncWrite + Unpin>(ws_sender: &mut WsSender<S>, config: &Config, conn_id: &str, violations: &mut usize, code: ErrorCode) -> tokio_tungstenite::tungstenite::Result<bool> {
+    *violations += 1;
+
+    if config.max_pre_auth_violations > 0 && *violations >= config.max_pre_auth_violations {
+        debug!(target: "socket", "{} sent {} messages before IDENTIFY, closing", conn_id, violations);
+        close_with_error(ws_sender, config, conn_id, ErrorCode::AUTH, Some("Too many messages before IDENTIFY")).await?;
+
+        return Ok(true);
+    }
+
+    if !config.quiet_pre_auth {
+        send_error(ws_sender, config, conn_id, code).await?;
+    }
+
+    Ok(false)
+}
+
+/// Check a token against the nonce of the peer, gives Some(true) if it was made
+/// with the admin secret, Some(false) with the shared secret and None if it's
+/// not valid
+fn check_token(config: &Config, nonce: Option<&str>, token: &str) -> Result<Option<bool>, TokenError> {
+    let secrets = config.secrets.read().unwrap();
+
+    if let Some(admin_secret) = &secrets.admin_secret {
+        if verify_token(admin_secret, nonce, token)? {
+            return Ok(Some(true));
+        }
+    }
+
+    Ok(verify_token(&secrets.secret, nonce, token)?.then_some(false))
+}
+
+/// Store the nonce the connection has to IDENTIFY or RESUME with
+async fn store_nonce(shards: &Shards, conn_id: &str, nonce: &str) -> RedisResult<()> {
+    let (key, nonce) = (format!("{}_nonce", conn_id), nonce.to_string());
+
+    shards.run(conn_id, move |redis| redis.set(key, nonce)).await
+}
+
+/// Look up the voice state `session_id`, to check who owns it and to find the
+/// guild whose shard the work on it goes through
+///
+/// Routed by the session ID since the guild isn't known until it's read.
+async fn find_voice_state(shards: &Shards, session_id: &str) -> RedisResult<Option<VoiceState>> {
+    let lookup_id = session_id.to_string();
+
+    shards.run(session_id, move |redis| get_voice_state(redis, &lookup_id)).await
+}
+
+/// Log the outcome of an IDENTIFY or RESUME, and record it to the auth audit
+/// stream if there is one
+async fn audit_auth_attempt(state: &Arc<ServerState>, peer: &str, event: AuditEvent<'_>) {
+    event.emit();
+
+    let fields = match event.auth_attempt_fields() {
+        Some(fields) if state.config.auth_audit_stream.is_some() => fields,
+        _ => return
+    };
+
+    let (record_state, record_peer) = (state.clone(), peer.to_string());
+    let recorded = state.shards.run(peer, move |redis| record_auth_attempt(redis, &record_state.config, &record_peer, fields)).await;
+
+    if let Err(e) = recorded {
+        warn!(target: "socket", "Failed to record auth attempt of {}: {}", peer, e);
+    }
+}
+
+/// Answer a failed IDENTIFY or RESUME with AUTH, recording why it failed
+async fn reject_auth<S: AsyncRead + AsyncWrite + Unpin>(ws_sender: &mut WsSender<S>, state: &Arc<ServerState>, peer: &str, conn_id: &str, reason: &str, resumed: bool) -> tokio_tungstenite::tungstenite::Result<()> {
+    let config = &state.config;
+    audit_auth_attempt(state, peer, AuditEvent::IdentifyFailed { conn_id, reason, resumed }).await;
+    send_error(ws_sender, config, conn_id, ErrorCode::AUTH).await
+}
+
+/// Optional protocol features, as listed in SERVER_INFO
+const FEATURES: &[&str] = &["resume", "reidentify", "server_proof", "destroy_ack", "channel_exists", "channel_token_refresh", "update_ack"];
+
+/// What this server supports, as sent in SERVER_INFO
+fn server_info(config: &Config) -> InfoData {
+    InfoData::SERVER_INFO(SERVER_INFO {
+        version: version::VERSION.to_string(),
+        protocol_version: version::LVSP_VERSION,
+        git_hash: version::GIT_HASH.to_string(),
+        region: config.region.clone(),
+        features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        encryption_modes: config.encryption_modes.clone(),
+        limits: ServerLimits {
+            max_string_length: config.max_string_length,
+            max_session_channels: config.max_session_channels,
+            max_session_voice_states: config.max_session_voice_states
+        }
+    })
+}
+
+/// What `state` looks like once `update` is applied to it
+fn updated_voice_state(update: &VST_UPDATE, state: &VoiceState) -> VST_UPDATE_ACK {
+    let (channel_id, guild_id) = match &update.channel_id {
+        Some(channel_id) => (channel_id.clone(), update.guild_id.clone()),
+        None => (state.channel.channel.clone(), state.channel.guild_id().map(str::to_string))
+    };
+
+    VST_UPDATE_ACK {
+        session_id: update.session_id.clone(),
+        channel_id,
+        guild_id,
+        mute: update.mute.unwrap_or(state.mute),
+        deaf: update.deaf.unwrap_or(state.deaf),
+        self_mute: update.self_mute.unwrap_or(state.self_mute),
+        self_deaf: update.self_deaf.unwrap_or(state.self_deaf)
+    }
+}
+
+/// Answer a client's challenge with the secret it identified with, as told
+/// by check_token
+fn identified_proof(config: &Config, admin: bool, challenge: &str) -> String {
+    let secrets = config.secrets.read().unwrap();
+
+    match (admin, &secrets.admin_secret) {
+        (true, Some(admin_secret)) => server_proof(admin_secret, challenge),
+        _ => server_proof(&secrets.secret, challenge)
+    }
+}
+
+/// What handling an INFO request came to, acted on once the requests before
+/// it were answered
+enum InfoReply {
+    Message(SocketMessage),
+    Error(ErrorCode),
+    RateLimited(Duration),
+
+    /// Nothing to answer with
+    Nothing,
+
+    /// Redis failed, the connection is closed like [`close_on_redis_error`]
+    /// does
+    RedisFailed,
+
+    /// The connection is closed with the code
+    Close(ErrorCode)
+}
+
+/// Handle the decoded INFO `info` from the connection `conn_id`, giving what
+/// to answer with
+///
+/// Runs alongside the connection's other INFO requests, up to
+/// `INFO_CONCURRENCY` at once, so it can't rely on the ones before it being
+/// done.
+async fn handle_info(state: Arc<ServerState>, conn_id: String, admin: bool, info: (InfoType, InfoData), validate_only: bool) -> InfoReply {
+    let ServerState { config, connections, pending_cleanups, channel_index, guild_rate_limiter, shards, .. } = &*state;
+
+    debug!(target: "socket", "INFO from {} with type {:?}", &conn_id,  &info.0);
+
+    if info.1.strings().iter().any(|string| string.len() > config.max_string_length) {
+        debug!(target: "socket", "INFO from {} has a string longer than {} bytes", &conn_id, config.max_string_length);
+        return InfoReply::Error(ErrorCode::DECODE);
+    }
+
+    // Admin requests act on other connections, there's nothing to
+    // reply with that a dry run could check against
+    if validate_only && matches!(info.0, InfoType::VST_KICK | InfoType::TEARDOWN_REQ | InfoType::REIDENTIFY_REQ) {
+        debug!(target: "socket", "Refusing validate_only {:?} from {}", &info.0, &conn_id);
+        return InfoReply::Error(ErrorCode::UNSUPPORTED);
+    }
+
+    match info.0 {
+        InfoType::CHANNEL_REQ => {
+            if let InfoData::CHANNEL_REQ(dn) = info.1 {
+                if DRAINING.load(Ordering::Relaxed) {
+                    debug!(target: "socket", "Refusing CHANNEL_REQ from {} while draining", &conn_id);
+                    return InfoReply::Error(ErrorCode::DRAINING);
+                }
+
+                if let Err(retry_after) = guild_rate_limiter.check(dn.guild_id.as_ref().unwrap_or(&dn.channel_id)) {
+                    debug!(target: "socket", "Rate limiting CHANNEL_REQ from {} for {}", &conn_id, &dn.channel_id);
+                    METRICS.rate_limited.fetch_add(1, Ordering::Relaxed);
+                    return InfoReply::RateLimited(retry_after);
+                }
+
+                let key = ChannelKey::new(dn.guild_id.as_deref(), &dn.channel_id);
+                let channel_key = key.to_redis_key();
+
+                let mut over_quota = false;
+                connections::update(connections, &conn_id, |connection| {
+                    over_quota = connection.over_channel_quota(&channel_key, config.max_session_channels);
+                });
+
+                if over_quota {
+                    debug!(target: "socket", "Refusing CHANNEL_REQ from {}, it owns {} channels already", &conn_id, config.max_session_channels);
+                    return InfoReply::Error(ErrorCode::LIMIT);
+                }
+
+                debug!(target: "socket", "Creating voice channel for {} in {}", &key.channel, &key.guild);
+
+                let mode = match negotiate_mode(&config.encryption_modes, dn.modes.as_deref()) {
+                    Some(mode) => mode,
+                    None => {
+                        debug!(target: "socket", "No encryption mode in common with {} for {}", &conn_id, &dn.channel_id);
+                        return InfoReply::Error(ErrorCode::ENCRYPTION);
+                    }
+                };
+
+                if validate_only {
+                    debug!(target: "socket", "CHANNEL_ASSIGN to {} for a dry run", &conn_id);
+
+                    return InfoReply::Message(SocketMessage::info(
+                        InfoType::CHANNEL_ASSIGN,
+                        InfoData::CHANNEL_ASSIGN(CHANNEL_ASSIGN {
+                            channel_id: dn.channel_id,
+                            guild_id: dn.guild_id,
+                            token: String::new(),
+                            mode,
+                            region: config.region.clone(),
+                            token_ttl: config.channel_token_ttl.map(|ttl| ttl.as_secs())
+                        })
+                    ));
+                }
+
+                let token: String = generate_token(64, config.unambiguous_tokens);
+
+                let added = shards.run(key.shard_key(), {
+                    let (channel_key, token, ttl) = (channel_key.clone(), token.clone(), config.channel_token_ttl);
+                    let assigned = ClusterEvent::ChannelAssigned { node: config.node_id.clone(), channel: channel_key.clone() };
+
+                    move |redis| {
+                        let added = add_channel_token(redis, &channel_key, &token, ttl)?;
+
+                        if added {
+                            assigned.publish(redis);
+                        }
+
+                        Ok(added)
+                    }
+                }).await;
+
+                let added = match added {
+                    Ok(added) => added,
+                    Err(e) => {
+                        warn!(target: "socket", "Failed to create channel {} for {}: {}", &channel_key, &conn_id, e);
+                        return InfoReply::RedisFailed;
+                    }
+                };
+
+                // Nothing gets added if the token is already in the channel
+                if added {
+                    AuditEvent::ChannelCreated { conn_id: &conn_id, channel: &channel_key }.emit();
+
+                    if let Some(node) = channel_index.lock().unwrap().get(&channel_key).filter(|node| **node != config.node_id) {
+                        warn!(target: "socket", "Assigning {} which is also assigned on node {}", &channel_key, node);
+                    }
+
+                    connections::update(connections, &conn_id, |connection| {
+                        connection.channels.insert(channel_key);
+                    });
+
+                    debug!(target: "socket", "CHANNEL_ASSIGN to {}", &conn_id);
+
+                    return InfoReply::Message(SocketMessage::info(
+                        InfoType::CHANNEL_ASSIGN,
+                        InfoData::CHANNEL_ASSIGN(CHANNEL_ASSIGN {
+                            channel_id: dn.channel_id,
+                            guild_id: dn.guild_id,
+                            token,
+                            mode,
+                            region: config.region.clone(),
+                            token_ttl: config.channel_token_ttl.map(|ttl| ttl.as_secs())
+                        })
+                    ));
+                } else {
+                    warn!(target: "socket", "Generated an ID that's already in {}, dropping {}", &channel_key, &conn_id);
+                    return InfoReply::Close(ErrorCode::GENERAL);
+                }
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        InfoType::CHANNEL_DESTROY => {
+            if let InfoData::CHANNEL_DESTROY(dn) = info.1 {
+                if let Err(retry_after) = guild_rate_limiter.check(dn.guild_id.as_ref().unwrap_or(&dn.channel_id)) {
+                    debug!(target: "socket", "Rate limiting CHANNEL_DESTROY from {} for {}", &conn_id, &dn.channel_id);
+                    METRICS.rate_limited.fetch_add(1, Ordering::Relaxed);
+                    return InfoReply::RateLimited(retry_after);
+                }
+
+                let key = ChannelKey::new(dn.guild_id.as_deref(), &dn.channel_id);
+                let channel_key = key.to_redis_key();
+
+                let mut owned = false;
+                connections::update(connections, &conn_id, |connection| {
+                    owned = connection.channels.contains(&channel_key);
+                });
+
+                if !owned {
+                    debug!(target: "socket", "CHANNEL_DESTROY from {} for channel {} it doesn't own", &conn_id, &channel_key);
+                    return InfoReply::Error(ErrorCode::STATE);
+                }
+
+                let destroyed = shards.run(key.shard_key(), {
+                    let channel_key = channel_key.clone();
+                    let destroyed_event = ClusterEvent::ChannelDestroyed { node: config.node_id.clone(), channel: channel_key.clone() };
+
+                    move |redis| {
+                        if validate_only {
+                            return redis.exists(&channel_key).map(|exists: bool| exists.then(Vec::new));
+                        }
+
+                        let destroyed = destroy_channel(redis, &channel_key)?;
+
+                        if destroyed.is_some() {
+                            destroyed_event.publish(redis);
+                        }
+
+                        Ok(destroyed)
+                    }
+                }).await;
+
+                match destroyed {
+                    Ok(Some(_)) if validate_only => {
+                        debug!(target: "socket", "CHANNEL_DESTROY_ACK to {} for a dry run", &conn_id);
+                        return InfoReply::Message(SocketMessage::info(
+                            InfoType::CHANNEL_DESTROY_ACK,
+                            InfoData::CHANNEL_DESTROY_ACK(CHANNEL_DESTROY_ACK {
+                                channel_id: dn.channel_id,
+                                guild_id: dn.guild_id
+                            })
+                        ));
+                    },
+                    Ok(Some(voice_states)) => {
+                        debug!(target: "socket", "Destroyed channel {}", &channel_key);
+
+                        for session_id in &voice_states {
+                            AuditEvent::VoiceStateDestroyed { conn_id: Some(&conn_id), session_id, reason: "channel destroyed" }.emit();
+                        }
+
+                        AuditEvent::ChannelDestroyed { conn_id: Some(&conn_id), channel: &channel_key, reason: "destroyed" }.emit();
+
+                        connections::forget(connections, pending_cleanups, Some(&channel_key), &voice_states);
+
+                        debug!(target: "socket", "CHANNEL_DESTROY_ACK to {}", &conn_id);
+                        return InfoReply::Message(SocketMessage::info(
+                            InfoType::CHANNEL_DESTROY_ACK,
+                            InfoData::CHANNEL_DESTROY_ACK(CHANNEL_DESTROY_ACK {
+                                channel_id: dn.channel_id,
+                                guild_id: dn.guild_id
+                            })
+                        ));
+                    },
+                    Ok(None) => {
+                        debug!(target: "socket", "CHANNEL_DESTROY from {} for unknown channel {}", &conn_id, &channel_key);
+                        return InfoReply::Error(ErrorCode::STATE);
+                    },
+                    Err(e) => {
+                        warn!(target: "socket", "Failed to destroy channel {} for {}: {}", &channel_key, &conn_id, e);
+                        return InfoReply::RedisFailed;
+                    }
+                }
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        InfoType::VST_CREATE => {
+            if let InfoData::VST_CREATE(dn) = info.1 {
+                let mut over_quota = false;
+                connections::update(connections, &conn_id, |connection| {
+                    over_quota = connection.over_voice_state_quota(config.max_session_voice_states);
+                });
+
+                if over_quota {
+                    debug!(target: "socket", "Refusing VST_CREATE from {}, it owns {} voice states already", &conn_id, config.max_session_voice_states);
+                    return InfoReply::Error(ErrorCode::LIMIT);
+                }
+
+                let key = ChannelKey::new(dn.guild_id.as_deref(), &dn.channel_id);
+                debug!(target: "socket", "Creating voice state for {} in {}", &key.channel, &key.guild);
+
+                if validate_only {
+                    debug!(target: "socket", "VOICE_STATE_DONE to {} for a dry run", &conn_id);
+
+                    return InfoReply::Message(SocketMessage::info(
+                        InfoType::VST_DONE,
+                        InfoData::VST_DONE(VST_DONE {
+                            user_id: dn.user_id,
+                            channel_id: dn.channel_id,
+                            guild_id: dn.guild_id,
+                            session_id: String::new(),
+                            mute: dn.mute,
+                            deaf: dn.deaf,
+                            self_mute: dn.self_mute,
+                            self_deaf: dn.self_deaf
+                        })
+                    ));
+                }
+
+                let session_id: String = generate_token(32, config.unambiguous_tokens);
+
+                let channel_key = key.to_redis_key();
+
+                let added = shards.run(key.shard_key(), {
+                    let (channel_key, session_id, owner, flags) = (channel_key.clone(), session_id.clone(), conn_id.clone(), dn.flags());
+                    move |redis| create_voice_state(redis, &channel_key, &session_id, &owner, &flags)
+                }).await;
+
+                let added = match added {
+                    Ok(added) => added,
+                    Err(e) => {
+                        warn!(target: "socket", "Failed to create voice state in {} for {}: {}", &channel_key, &conn_id, e);
+                        return InfoReply::RedisFailed;
+                    }
+                };
+
+                // Nothing gets added if the session ID is already in the channel
+                if added {
+                    AuditEvent::VoiceStateCreated { conn_id: &conn_id, session_id: &session_id, channel: &channel_key }.emit();
+
+                    connections::update(connections, &conn_id, |connection| {
+                        connection.voice_states.insert(session_id.clone());
+                    });
+
+                    debug!(target: "socket", "VOICE_STATE_DONE to {}", &conn_id);
+
+                    return InfoReply::Message(SocketMessage::info(
+                        InfoType::VST_DONE,
+                        InfoData::VST_DONE(VST_DONE {
+                            user_id: dn.user_id,
+                            channel_id: dn.channel_id,
+                            guild_id: dn.guild_id,
+                            session_id,
+                            mute: dn.mute,
+                            deaf: dn.deaf,
+                            self_mute: dn.self_mute,
+                            self_deaf: dn.self_deaf
+                        })
+                    ));
+                } else {
+                    warn!(target: "socket", "Generated an ID that's already in {}, dropping {}", &channel_key, &conn_id);
+                    return InfoReply::Close(ErrorCode::GENERAL);
+                }
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        InfoType::VST_UPDATE => {
+            if let InfoData::VST_UPDATE(dn) = info.1 {
+                let state = match find_voice_state(shards, &dn.session_id).await {
+                    Ok(state) => state,
+                    Err(e) => {
+                        warn!(target: "socket", "Failed to look up voice state {} for {}: {}", &dn.session_id, &conn_id, e);
+                        return InfoReply::RedisFailed;
+                    }
+                };
+
+                let state = match state {
+                    Some(state) if state.connection == conn_id => state,
+                    _ => {
+                        debug!(target: "socket", "VST_UPDATE from {} for unknown or someone else's voice state {}", &conn_id, &dn.session_id);
+                        return InfoReply::Error(ErrorCode::STATE);
+                    }
+                };
+
+                // A voice state stays in its guild, or in the dms
+                if dn.channel_id.is_some() && dn.guild_id.as_deref() != state.channel.guild_id() {
+                    debug!(target: "socket", "VST_UPDATE from {} moving voice state {} out of {}", &conn_id, &dn.session_id, &state.channel.guild);
+                    return InfoReply::Error(ErrorCode::STATE);
+                }
+
+                let updated = updated_voice_state(&dn, &state);
+
+                if validate_only {
+                    debug!(target: "socket", "VST_UPDATE_ACK to {} for a dry run", &conn_id);
+                    return InfoReply::Message(SocketMessage::info(InfoType::VST_UPDATE_ACK, InfoData::VST_UPDATE_ACK(updated)));
+                }
+
+                // Goes through the shard of the channel the voice state ends up
+                // in, the same as the channel's own work
+                let mut shard_key = state.channel.shard_key().to_string();
+
+                if let Some(channel_id) = &dn.channel_id {
+                    let key = ChannelKey::new(dn.guild_id.as_deref(), channel_id);
+                    debug!(target: "socket", "Moving voice state {} to {} in {}", &dn.session_id, &key.channel, &key.guild);
+
+                    shard_key = key.shard_key().to_string();
+
+                    let moved = shards.run(&shard_key, {
+                        let (session_id, from, to) = (dn.session_id.clone(), state.channel.to_redis_key(), key.to_redis_key());
+                        move |redis| move_voice_state(redis, &session_id, &from, &to)
+                    }).await;
+
+                    match moved {
+                        Ok(true) => (),
+                        Ok(false) => {
+                            debug!(target: "socket", "VST_UPDATE from {} lost voice state {} or the channel it was moving to", &conn_id, &dn.session_id);
+                            return InfoReply::Error(ErrorCode::STATE);
+                        },
+                        Err(e) => {
+                            warn!(target: "socket", "Failed to move voice state {} for {}: {}", &dn.session_id, &conn_id, e);
+                            return InfoReply::RedisFailed;
+                        }
+                    }
+                }
+
+                let flags = dn.flags();
+
+                if !flags.is_empty() {
+                    debug!(target: "socket", "Setting {:?} on voice state {}", &flags, &dn.session_id);
+
+                    let session_key = format!("{}_session", &dn.session_id);
+
+                    if let Err(e) = shards.run(&shard_key, move |redis| redis.hset_multiple::<_, _, _, ()>(session_key, &flags)).await {
+                        warn!(target: "socket", "Failed to update voice state {} for {}: {}", &dn.session_id, &conn_id, e);
+                        return InfoReply::RedisFailed;
+                    }
+                }
+
+                debug!(target: "socket", "VST_UPDATE_ACK to {}", &conn_id);
+                return InfoReply::Message(SocketMessage::info(InfoType::VST_UPDATE_ACK, InfoData::VST_UPDATE_ACK(updated)));
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        InfoType::VST_DESTROY => {
+            if let InfoData::VST_DESTROY(dn) = info.1 {
+                let destroyed = match find_voice_state(shards, &dn.session_id).await {
+                    Ok(Some(state)) if state.connection == conn_id && validate_only => Ok(true),
+                    Ok(Some(state)) if state.connection == conn_id => shards.run(state.channel.shard_key(), {
+                        let (session_id, owner) = (dn.session_id.clone(), conn_id.clone());
+                        move |redis| destroy_voice_state(redis, &session_id, Some(&owner))
+                    }).await,
+                    Ok(_) => Ok(false),
+                    Err(e) => Err(e)
+                };
+
+                match destroyed {
+                    Ok(true) if validate_only => {
+                        debug!(target: "socket", "VST_DESTROY_ACK to {} for a dry run", &conn_id);
+                        return InfoReply::Message(SocketMessage::info(
+                            InfoType::VST_DESTROY_ACK,
+                            InfoData::VST_DESTROY_ACK(VST_DESTROY_ACK { session_id: dn.session_id })
+                        ));
+                    },
+                    Ok(true) => {
+                        debug!(target: "socket", "Destroyed voice state {}", &dn.session_id);
+
+                        AuditEvent::VoiceStateDestroyed { conn_id: Some(&conn_id), session_id: &dn.session_id, reason: "destroyed" }.emit();
+
+                        connections::update(connections, &conn_id, |connection| {
+                            connection.voice_states.remove(&dn.session_id);
+                        });
+
+                        debug!(target: "socket", "VST_DESTROY_ACK to {}", &conn_id);
+                        return InfoReply::Message(SocketMessage::info(
+                            InfoType::VST_DESTROY_ACK,
+                            InfoData::VST_DESTROY_ACK(VST_DESTROY_ACK { session_id: dn.session_id })
+                        ));
+                    },
+                    Ok(false) => {
+                        debug!(target: "socket", "VST_DESTROY from {} for unknown or someone else's voice state {}", &conn_id, &dn.session_id);
+                        return InfoReply::Error(ErrorCode::STATE);
+                    },
+                    Err(e) => {
+                        warn!(target: "socket", "Failed to destroy voice state {} for {}: {}", &dn.session_id, &conn_id, e);
+                        return InfoReply::RedisFailed;
+                    }
+                }
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        InfoType::VST_KICK => {
+            if !admin {
+                warn!(target: "socket", "VST_KICK from non-admin {}", &conn_id);
+                return InfoReply::Error(ErrorCode::AUTH);
+            } else if let InfoData::VST_KICK(dn) = info.1 {
+                // Same script as VST_DESTROY, so a move going on at the
+                // same time can't leave the voice state behind
+                let kicked = match find_voice_state(shards, &dn.session_id).await {
+                    Ok(Some(state)) => shards.run(state.channel.shard_key(), {
+                        let session_id = dn.session_id.clone();
+                        move |redis| destroy_voice_state(redis, &session_id, None)
+                    }).await,
+                    Ok(None) => Ok(false),
+                    Err(e) => Err(e)
+                };
+
+                let kicked = match kicked {
+                    Ok(kicked) => kicked,
+                    Err(e) => {
+                        warn!(target: "socket", "Failed to kick voice state {} for {}: {}", &dn.session_id, &conn_id, e);
+                        return InfoReply::RedisFailed;
+                    }
+                };
+
+                if kicked {
+                    info!(target: "socket", "Kicked voice state {} on behalf of {}", &dn.session_id, &conn_id);
+
+                    AuditEvent::VoiceStateDestroyed { conn_id: Some(&conn_id), session_id: &dn.session_id, reason: "kicked" }.emit();
+
+                    // Also drops it from a session pending cleanup, which would
+                    // otherwise bring its session back on RESUME
+                    let owners = connections::forget(connections, pending_cleanups, None, std::slice::from_ref(&dn.session_id));
+
+                    for owner in owners {
+                        connections::send_to(connections, &owner, Outbound::Message(Message::Close(Some(ErrorCode::GENERAL.close_frame_with("Voice state kicked")))));
+                    }
+
+                    debug!(target: "socket", "VST_DESTROY_ACK to {} for a kick", &conn_id);
+                    return InfoReply::Message(SocketMessage::info(
+                        InfoType::VST_DESTROY_ACK,
+                        InfoData::VST_DESTROY_ACK(VST_DESTROY_ACK { session_id: dn.session_id })
+                    ));
+                } else {
+                    debug!(target: "socket", "VST_KICK from {} for unknown voice state {}", &conn_id, &dn.session_id);
+                    return InfoReply::Error(ErrorCode::STATE);
+                }
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        InfoType::TEARDOWN_REQ => {
+            if !admin {
+                warn!(target: "socket", "TEARDOWN_REQ from non-admin {}", &conn_id);
+                return InfoReply::Error(ErrorCode::AUTH);
+            } else if let InfoData::TEARDOWN_REQ(dn) = info.1 {
+                match (dn.session_id, dn.channel_id) {
+                    (Some(session_id), None) => {
+                        let destroyed = match find_voice_state(shards, &session_id).await {
+                            Ok(Some(state)) => shards.run(state.channel.shard_key(), {
+                                let session_id = session_id.clone();
+                                move |redis| destroy_voice_state(redis, &session_id, None)
+                            }).await,
+                            Ok(None) => Ok(false),
+                            Err(e) => Err(e)
+                        };
+
+                        let destroyed = match destroyed {
+                            Ok(destroyed) => destroyed,
+                            Err(e) => {
+                                warn!(target: "socket", "Failed to tear down voice state {} for {}: {}", &session_id, &conn_id, e);
+                                return InfoReply::RedisFailed;
+                            }
+                        };
+
+                        if destroyed {
+                            info!(target: "socket", "Tearing down voice state {} on behalf of {}", &session_id, &conn_id);
+
+                            AuditEvent::VoiceStateDestroyed { conn_id: Some(&conn_id), session_id: &session_id, reason: "torn down" }.emit();
+
+                            let owners = connections::forget(connections, pending_cleanups, None, std::slice::from_ref(&session_id));
+                            let notice = SocketMessage::info(InfoType::VST_DESTROY, InfoData::VST_DESTROY(VST_DESTROY { session_id }));
+
+                            for owner in owners {
+                                connections::send_to(connections, &owner, Outbound::Message(Message::Text(serde_json::to_string(&notice).unwrap())));
+                            }
+                        } else {
+                            debug!(target: "socket", "TEARDOWN_REQ from {} for unknown voice state {}", &conn_id, &session_id);
+                            return InfoReply::Error(ErrorCode::STATE);
+                        }
+                    },
+                    (None, Some(channel_id)) => {
+                        let key = ChannelKey::new(dn.guild_id.as_deref(), &channel_id);
+                        let channel_key = key.to_redis_key();
+
+                        let destroyed = shards.run(key.shard_key(), {
+                            let channel_key = channel_key.clone();
+                            let destroyed_event = ClusterEvent::ChannelDestroyed { node: config.node_id.clone(), channel: channel_key.clone() };
+
+                            move |redis| {
+                                let destroyed = destroy_channel(redis, &channel_key)?;
+
+                                if destroyed.is_some() {
+                                    destroyed_event.publish(redis);
+                                }
+
+                                Ok(destroyed)
+                            }
+                        }).await;
+
+                        let destroyed = match destroyed {
+                            Ok(destroyed) => destroyed,
+                            Err(e) => {
+                                warn!(target: "socket", "Failed to tear down channel {} for {}: {}", &channel_key, &conn_id, e);
+                                return InfoReply::RedisFailed;
+                            }
+                        };
+
+                        if let Some(voice_states) = destroyed {
+                            info!(target: "socket", "Tearing down channel {} on behalf of {}", &channel_key, &conn_id);
+
+                            for session_id in &voice_states {
+                                AuditEvent::VoiceStateDestroyed { conn_id: Some(&conn_id), session_id, reason: "torn down" }.emit();
+                            }
+
+                            AuditEvent::ChannelDestroyed { conn_id: Some(&conn_id), channel: &channel_key, reason: "torn down" }.emit();
+
+                            let owners = connections::forget(connections, pending_cleanups, Some(&channel_key), &voice_states);
+                            let notice = SocketMessage::info(InfoType::CHANNEL_DESTROY, InfoData::CHANNEL_DESTROY(CHANNEL_DESTROY { channel_id, guild_id: dn.guild_id }));
+
+                            for owner in owners {
+                                connections::send_to(connections, &owner, Outbound::Message(Message::Text(serde_json::to_string(&notice).unwrap())));
+                            }
+                        } else {
+                            debug!(target: "socket", "TEARDOWN_REQ from {} for unknown channel {}", &conn_id, &channel_key);
+                            return InfoReply::Error(ErrorCode::STATE);
+                        }
+                    },
+                    _ => {
+                        return InfoReply::Error(ErrorCode::DECODE);
+                    }
+                }
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        InfoType::CHANNEL_EXISTS_REQ => {
+            if let InfoData::CHANNEL_EXISTS_REQ(dn) = info.1 {
+                let key = ChannelKey::new(dn.guild_id.as_deref(), &dn.channel_id);
+                let channel_key = key.to_redis_key();
+
+                let exists = shards.run(key.shard_key(), {
+                    let channel_key = channel_key.clone();
+                    move |redis| redis.exists(&channel_key)
+                }).await;
+
+                match exists {
+                    Ok(exists) => {
+                        debug!(target: "socket", "CHANNEL_EXISTS_RESULT to {} for {}: {}", &conn_id, &channel_key, exists);
+                        return InfoReply::Message(SocketMessage::info(
+                            InfoType::CHANNEL_EXISTS_RESULT,
+                            InfoData::CHANNEL_EXISTS_RESULT(CHANNEL_EXISTS_RESULT {
+                                channel_id: dn.channel_id,
+                                guild_id: dn.guild_id,
+                                exists
+                            })
+                        ));
+                    },
+                    Err(e) => {
+                        warn!(target: "socket", "Failed to look up channel {} for {}: {}", &channel_key, &conn_id, e);
+                        return InfoReply::RedisFailed;
+                    }
+                }
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        InfoType::CHANNEL_TOKEN_REFRESH => {
+            if let InfoData::CHANNEL_TOKEN_REFRESH(dn) = info.1 {
+                let key = ChannelKey::new(dn.guild_id.as_deref(), &dn.channel_id);
+                let channel_key = key.to_redis_key();
+
+                let token = if validate_only { String::new() } else { generate_token(64, config.unambiguous_tokens) };
+
+                let refreshed = shards.run(key.shard_key(), {
+                    let (channel_key, current, token, ttl) = (channel_key.clone(), dn.token.clone(), token.clone(), config.channel_token_ttl);
+
+                    move |redis| if validate_only {
+                        check_channel_token(redis, &channel_key, &current, ttl.is_some())
+                    } else {
+                        refresh_channel_token(redis, &channel_key, &current, &token, ttl)
+                    }
+                }).await;
+
+                match refreshed {
+                    Ok(true) => {
+                        debug!(target: "socket", "CHANNEL_TOKEN_REFRESH_ACK to {} for {}", &conn_id, &channel_key);
+                        return InfoReply::Message(SocketMessage::info(
+                            InfoType::CHANNEL_TOKEN_REFRESH_ACK,
+                            InfoData::CHANNEL_TOKEN_REFRESH_ACK(CHANNEL_TOKEN_REFRESH_ACK {
+                                channel_id: dn.channel_id,
+                                guild_id: dn.guild_id,
+                                token,
+                                token_ttl: config.channel_token_ttl.map(|ttl| ttl.as_secs())
+                            })
+                        ));
+                    },
+                    Ok(false) => {
+                        debug!(target: "socket", "CHANNEL_TOKEN_REFRESH from {} with a token that isn't valid for {}", &conn_id, &channel_key);
+                        return InfoReply::Error(ErrorCode::STATE);
+                    },
+                    Err(e) => {
+                        warn!(target: "socket", "Failed to refresh the token of {} for {}: {}", &channel_key, &conn_id, e);
+                        return InfoReply::RedisFailed;
+                    }
+                }
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        InfoType::SERVER_INFO_REQ => {
+            debug!(target: "socket", "SERVER_INFO to {}", &conn_id);
+            return InfoReply::Message(SocketMessage::info(InfoType::SERVER_INFO, server_info(config)));
+        },
+        InfoType::SESSION_LIST_REQ => {
+            if !admin {
+                warn!(target: "socket", "SESSION_LIST_REQ from non-admin {}", &conn_id);
+                return InfoReply::Error(ErrorCode::AUTH);
+            } else if let InfoData::SESSION_LIST_REQ(dn) = info.1 {
+                let (sessions, pages) = connections::list(connections, dn.page);
+
+                debug!(target: "socket", "SESSION_LIST to {}", &conn_id);
+
+                return InfoReply::Message(SocketMessage::info(
+                    InfoType::SESSION_LIST,
+                    InfoData::SESSION_LIST(SESSION_LIST {
+                        sessions,
+                        page: dn.page,
+                        pages
+                    })
+                ));
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        InfoType::REIDENTIFY_REQ => {
+            if !admin {
+                warn!(target: "socket", "REIDENTIFY_REQ from non-admin {}", &conn_id);
+                return InfoReply::Error(ErrorCode::AUTH);
+            } else if let InfoData::REIDENTIFY_REQ(dn) = info.1 {
+                let targets: Vec<String> = match dn.id {
+                    Some(target) => vec![target],
+                    None => connections.iter()
+                        .map(|connection| connection.key().clone())
+                        .filter(|target| *target != conn_id)
+                        .collect()
+                };
+
+                info!(target: "socket", "Asking {} connections to reidentify on behalf of {}", targets.len(), &conn_id);
+
+                for target in targets {
+                    connections::send_to(connections, &target, Outbound::Reidentify);
+                }
+            } else {
+                return InfoReply::Error(ErrorCode::DECODE);
+            }
+        },
+        _ => {
+            return InfoReply::Error(ErrorCode::DECODE);
+        }
+    }
+
+    InfoReply::Nothing
+}
+
+async fn handle_conn<S: AsyncRead + AsyncWrite + Unpin + Send>(state: &Arc<ServerState>, conn_id: String, peer: String, stream: S, requests: &mut OrderedQueue<InfoReply>) -> tokio_tungstenite::tungstenite::Result<()> {
+    let ServerState { config, connections, pending_cleanups, shards, shutdown, .. } = &**state;
+    let mut shutdown = shutdown.clone();
+    let mut handshake = HandshakeInfo::default();
+
+    // The error response is tungstenite's to pick, and never returned here
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        handshake = HandshakeInfo::from_request(request);
+
+        Ok(response)
+    };
+
+    let ws_stream = tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(config.websocket_config())).await;
+
+    if ws_stream.is_err() {
+        warn!(target: "initial", "Failed to complete the websocket handshake! Dropping {}!", peer);
+
+        return Ok(());
+    }
+
+    let ws_stream = ws_stream.unwrap();
+
+    info!(target: "socket", "Connected to peer {} as {}!", &peer, &conn_id);
+    debug!(target: "socket", "Handshake of {}: {:?}", &conn_id, &handshake);
+
+    let (sink, mut ws_receiver) = ws_stream.split();
+    let too_slow = Arc::new(Notify::new());
+    let mut ws_sender = WsSender { sink, too_slow: too_slow.clone() };
+
+    if !WARMED_UP.load(Ordering::Relaxed) {
+        debug!(target: "socket", "Still warming up, closing {}", &conn_id);
+
+        close_with_error(&mut ws_sender, config, &conn_id, ErrorCode::OVERLOADED, Some("Warming up, try again later")).await?;
+
+        return Ok(());
+    }
+
+    if PAUSED.load(Ordering::Relaxed) {
+        debug!(target: "socket", "Not accepting connections, closing {}", &conn_id);
+
+        close_with_error(&mut ws_sender, config, &conn_id, ErrorCode::OVERLOADED, Some("Not accepting connections, try again later")).await?;
+
+        return Ok(());
+    }
+
+    let health = compute_health(config, connections);
+
+    if health.get() < config.shed_threshold {
+        warn!(target: "socket", "Health is {}, shedding {}!", health.get(), &conn_id);
+        METRICS.shed_connections.fetch_add(1, Ordering::Relaxed);
+
+        close_with_error(&mut ws_sender, config, &conn_id, ErrorCode::OVERLOADED, None).await?;
+
+        return Ok(());
+    }
+
+    let heartbeat_interval = jittered_heartbeat_interval(config);
+
+    let (outbound_sender, mut outbound_receiver) = tokio::sync::mpsc::channel(config.outbound_queue_size);
+    connections.insert(conn_id.clone(), Connection::new(peer.clone(), handshake, outbound_sender, too_slow.clone(), heartbeat_interval));
+    let mut heartbeat = tokio::time::interval(Duration::from_millis(1000));
+
+    let mut nonce: String = generate_token(NONCE_LENGTH, config.unambiguous_tokens);
+
+    // Nothing works without Redis, tell the client to come back later instead
+    // of taking the connection down with a panic
+    if let Err(e) = store_nonce(shards, &conn_id, &nonce).await {
+        warn!(target: "socket", "Failed to store nonce of {}, closing: {}", &conn_id, e);
+
+        close_with_error(&mut ws_sender, config, &conn_id, ErrorCode::GENERAL, Some(REDIS_UNAVAILABLE)).await?;
+
+        return Ok(());
+    }
+
+    debug!(target: "socket", "HELLO to {}", &conn_id);
+    send_message(&mut ws_sender, config, &conn_id, &SocketMessage::hello(heartbeat_interval, nonce.clone())).await?;
+
+    let mut identified: bool = false;
+    let mut admin: bool = false;
+    let mut heartbeat_acks = HeartbeatAckCache::default();
+    let mut identify_deadline = Instant::now() + config.identify_timeout;
+    let mut pre_auth_violations: usize = 0;
+
+    loop {
+        tokio::select! {
+            // Refusals wait their turn too, past `max_busy_replies` of them it
+            // isn't read until replies went out
+            msg = ws_receiver.next(), if requests.len() < config.max_in_flight + config.max_busy_replies => {
+                match msg {
+                    Some(msg) => {
+                        let msg = msg?;
+
+                        if config.log_raw_frames {
+                            log_raw_frame("in", &conn_id, &msg);
+                        }
+
+                        if msg.is_text() {
+                            if config.log_unknown_fields {
+                                let unknown = unknown_fields(&msg);
+
+                                if !unknown.is_empty() {
+                                    debug!(target: "socket", "Ignoring unknown fields {:?} from {}", unknown, &conn_id);
+                                }
+                            }
+
+                            let op = match get_opcode(msg.clone()) {
+                                Ok(op) => op,
+                                Err(code) => {
+                                    if !identified {
+                                        if pre_auth_violation(&mut ws_sender, config, &conn_id, &mut pre_auth_violations, code).await? {
+                                            break;
+                                        }
+                                    } else if claimed_opcode(&msg) == Some(OpCode::INFO) {
+                                        // Answered in turn, like the INFO requests that decode
+                                        requests.push(future::ready(InfoReply::Error(code)));
+                                    } else {
+                                        send_error(&mut ws_sender, config, &conn_id, code).await?;
+                                    }
+
+                                    continue;
+                                }
+                            };
+
+                            // Check if identified, HEARTBEAT is fine before that so slow
+                            // clients can keep alive while identifying
+                            if !identified && !matches!(op.0, OpCode::IDENTIFY | OpCode::RESUME | OpCode::HEARTBEAT) {
+                                debug!(target: "socket", "{:?} from {} before IDENTIFY", &op.0, &conn_id);
+
+                                if pre_auth_violation(&mut ws_sender, config, &conn_id, &mut pre_auth_violations, ErrorCode::AUTH).await? {
+                                    break;
+                                }
+
+                                continue;
+                            }
+
+                            // Identifying again would replace the session, READY is only sent once
+                            if identified && matches!(op.0, OpCode::IDENTIFY | OpCode::RESUME) {
+                                debug!(target: "socket", "{:?} from {} after it identified", &op.0, &conn_id);
+                                send_error(&mut ws_sender, config, &conn_id, ErrorCode::STATE).await?;
+
+                                continue;
+                            }
+
+                            match op.0 {
+                                OpCode::IDENTIFY => {
+                                    if let MessageData::IDENTIFY(dn) = op.1 {
+                                        debug!(target: "socket", "IDENTIFY from {}", &conn_id);
+
+                                        let nonce_conn_id = conn_id.clone();
+                                        let nonce = match shards.run(&conn_id, move |redis| take_nonce(redis, &nonce_conn_id)).await {
+                                            Ok(nonce) => nonce,
+                                            Err(e) => {
+                                                warn!(target: "socket", "Failed to get nonce of {}: {}", &conn_id, e);
+                                                close_on_redis_error(&mut ws_sender, config, &conn_id).await?;
+
+                                                break;
+                                            }
+                                        };
+
+                                        match check_token(config, nonce.as_deref(), &dn.token) {
+                                            Ok(Some(is_admin)) => {
+                                                let session_id: String = generate_token(32, config.unambiguous_tokens);
+
+                                                connections::update(connections, &conn_id, |connection| {
+                                                    connection.identified_at = Some(SystemTime::now());
+                                                    connection.session_id = Some(session_id.clone());
+                                                });
+
+                                                audit_auth_attempt(state, &peer, AuditEvent::IdentifySucceeded { conn_id: &conn_id, session_id: &session_id, admin: is_admin, resumed: false }).await;
+
+                                                debug!(target: "socket", "READY to {}", &conn_id);
+                                                let proof = dn.challenge.map(|challenge| identified_proof(config, is_admin, &challenge));
+                                                send_message(&mut ws_sender, config, &conn_id, &SocketMessage::ready(compute_health(config, connections), session_id, proof, config.resume_endpoint.clone())).await?;
+
+                                                identified = true;
+                                                admin = is_admin;
+                                            },
+                                            Ok(None) => reject_auth(&mut ws_sender, state, &peer, &conn_id, "invalid token", false).await?,
+                                            Err(TokenError::MissingNonce) => {
+                                                debug!(target: "socket", "{:?} from {} after its nonce was used", &op.0, &conn_id);
+                                                reject_auth(&mut ws_sender, state, &peer, &conn_id, "nonce already used", false).await?;
+                                            },
+                                            Err(e) => {
+                                                warn!(target: "socket", "Failed to verify token from {}: {}", &conn_id, e);
+                                                reject_auth(&mut ws_sender, state, &peer, &conn_id, &e.to_string(), false).await?;
+                                            }
+                                        }
+                                    } else {
+                                        send_error(&mut ws_sender, config, &conn_id, ErrorCode::DECODE).await?;
+                                    }
+                                }
+
+                                OpCode::RESUME => {
+                                    if let MessageData::RESUME(dn) = op.1 {
+                                        debug!(target: "socket", "RESUME from {}", &conn_id);
+
+                                        let nonce_conn_id = conn_id.clone();
+                                        let nonce = match shards.run(&conn_id, move |redis| take_nonce(redis, &nonce_conn_id)).await {
+                                            Ok(nonce) => nonce,
+                                            Err(e) => {
+                                                warn!(target: "socket", "Failed to get nonce of {}: {}", &conn_id, e);
+                                                close_on_redis_error(&mut ws_sender, config, &conn_id).await?;
+
+                                                break;
+                                            }
+                                        };
+
+                                        match check_token(config, nonce.as_deref(), &dn.token) {
+                                            Ok(Some(is_admin)) => {
+                                                let resumed = pending_cleanups.lock().unwrap().remove(&dn.session_id);
+
+                                                if let Some(cleanup) = resumed {
+                                                    debug!(target: "socket", "Resuming session {} on {}", &dn.session_id, &conn_id);
+
+                                                    let (voice_states, owner) = (cleanup.voice_states.clone(), conn_id.clone());
+                                                    let reassigned: RedisResult<()> = shards.run(&conn_id, move |redis| voice_states.iter()
+                                                        .try_for_each(|session_id| redis.hset(format!("{}_session", session_id), "connection", &owner))).await;
+
+                                                    if let Err(e) = reassigned {
+                                                        warn!(target: "socket", "Failed to resume session {} on {}: {}", &dn.session_id, &conn_id, e);
+
+                                                        // Still up for grabs by a RESUME once Redis is back
+                                                        pending_cleanups.lock().unwrap().insert(dn.session_id, cleanup);
+                                                        close_on_redis_error(&mut ws_sender, config, &conn_id).await?;
+
+                                                        break;
+                                                    }
+
+                                                    connections::update(connections, &conn_id, |connection| {
+                                                        connection.identified_at = Some(SystemTime::now());
+                                                        connection.session_id = Some(dn.session_id.clone());
+                                                        connection.channels = cleanup.channels;
+                                                        connection.voice_states = cleanup.voice_states;
+                                                    });
+
+                                                    audit_auth_attempt(state, &peer, AuditEvent::IdentifySucceeded { conn_id: &conn_id, session_id: &dn.session_id, admin: is_admin, resumed: true }).await;
+
+                                                    debug!(target: "socket", "READY to {}", &conn_id);
+                                                    let proof = dn.challenge.map(|challenge| identified_proof(config, is_admin, &challenge));
+                                                    send_message(&mut ws_sender, config, &conn_id, &SocketMessage::ready(compute_health(config, connections), dn.session_id, proof, config.resume_endpoint.clone())).await?;
+
+                                                    identified = true;
+                                                    admin = is_admin;
+                                                } else {
+                                                    debug!(target: "socket", "RESUME from {} for unknown session {}", &conn_id, &dn.session_id);
+                                                    audit_auth_attempt(state, &peer, AuditEvent::IdentifyFailed { conn_id: &conn_id, reason: "unknown session", resumed: true }).await;
+                                                    send_error(&mut ws_sender, config, &conn_id, ErrorCode::STATE).await?;
+                                                }
+                                            },
+                                            Ok(None) => reject_auth(&mut ws_sender, state, &peer, &conn_id, "invalid token", true).await?,
+                                            Err(TokenError::MissingNonce) => {
+                                                debug!(target: "socket", "{:?} from {} after its nonce was used", &op.0, &conn_id);
+                                                reject_auth(&mut ws_sender, state, &peer, &conn_id, "nonce already used", true).await?;
+                                            },
+                                            Err(e) => {
+                                                warn!(target: "socket", "Failed to verify token from {}: {}", &conn_id, e);
+                                                reject_auth(&mut ws_sender, state, &peer, &conn_id, &e.to_string(), true).await?;
+                                            }
+                                        }
+                                    } else {
+                                        send_error(&mut ws_sender, config, &conn_id, ErrorCode::DECODE).await?;
+                                    }
+                                }
+
+                                OpCode::HEARTBEAT => {
+                                    debug!(target: "socket", "HEARTBEAT from {}", &conn_id);
+                                    let mut previous = None;
+                                    let mut expected = heartbeat_interval;
+                                    connections::update(connections, &conn_id, |connection| {
+                                        previous = connection.last_heartbeat.replace(Instant::now());
+                                        expected = connection.heartbeat_interval;
+                                    });
+
+                                    if let Some(previous) = previous {
+                                        let elapsed = previous.elapsed();
+
+                                        if heartbeat_deviates(config, expected, elapsed) {
+                                            warn!(target: "socket", "{} heartbeated after {:?}, expected every {}s", &conn_id, elapsed, expected);
+                                            METRICS.heartbeat_deviations.fetch_add(1, Ordering::Relaxed);
+                                        }
+                                    }
+
+                                    debug!(target: "socket", "HEARTBEAT_ACK to {}", &conn_id);
+                                    let ack = heartbeat_acks.encode(compute_health(config, connections)).to_string();
+                                    send(&mut ws_sender, config, &conn_id, Message::Text(ack)).await?;
+                                }
+
+                                // Replies go out in the order the requests came in, whatever
+                                // order they finish in
+                                OpCode::INFO => {
+                                    if requests.len() >= config.max_in_flight {
+                                        debug!(target: "socket", "Refusing INFO from {}, {} requests are in flight", &conn_id, requests.len());
+                                        METRICS.busy_requests.fetch_add(1, Ordering::Relaxed);
+                                        requests.push(future::ready(InfoReply::Error(ErrorCode::BUSY)));
+
+                                        continue;
+                                    }
+
+                                    if let MessageData::INFO(info) = op.1 {
+                                        requests.push(handle_info(state.clone(), conn_id.clone(), admin, (info._type, info.data), info.validate_only));
+                                    }
+                                },
+
+                                _ => {
+                                    send_error(&mut ws_sender, config, &conn_id, ErrorCode::UNSUPPORTED).await?;
+                                }
+                            }
+                        } else if let Message::Close(frame) = msg {
+                            // INFO requests still in flight finish before the
+                            // cleanup. A normal close means the session is over,
+                            // don't keep its state around for a RESUME that
+                            // won't come
+                            if matches!(frame, Some(CloseFrame { code: CloseCode::Normal, .. })) {
+                                connections::update(connections, &conn_id, |connection| connection.resumable = false);
+                            }
+
+                            // Send the close reply tungstenite queued
+                            let _ = tokio::time::timeout(CLOSE_TIMEOUT, ws_sender.sink.flush()).await;
+
+                            break;
+                        }
+                    },
+                    None => break,
+                }
+            },
+            Some(reply) = requests.next(), if !requests.is_empty() => {
+                match reply {
+                    InfoReply::Message(msg) => send_message(&mut ws_sender, config, &conn_id, &msg).await?,
+                    InfoReply::Error(code) => send_error(&mut ws_sender, config, &conn_id, code).await?,
+                    InfoReply::RateLimited(retry_after) => send_rate_limited(&mut ws_sender, config, &conn_id, retry_after).await?,
+                    InfoReply::Nothing => (),
+                    InfoReply::RedisFailed => {
+                        close_on_redis_error(&mut ws_sender, config, &conn_id).await?;
+
+                        break;
+                    },
+                    InfoReply::Close(code) => {
+                        close_with_error(&mut ws_sender, config, &conn_id, code, None).await?;
+
+                        break;
+                    }
+                }
+            },
+            Some(outbound) = outbound_receiver.recv() => {
+                match outbound {
+                    Outbound::Message(msg) => {
+                        let close = msg.is_close();
+                        send(&mut ws_sender, config, &conn_id, msg).await?;
+
+                        if close {
+                            break;
+                        }
+                    },
+                    Outbound::Reidentify => {
+                        identified = false;
+                        admin = false;
+                        identify_deadline = Instant::now() + config.identify_timeout;
+                        pre_auth_violations = 0;
+                        connections::update(connections, &conn_id, |connection| connection.identified_at = None);
+
+                        nonce = generate_token(NONCE_LENGTH, config.unambiguous_tokens);
+
+                        if let Err(e) = store_nonce(shards, &conn_id, &nonce).await {
+                            warn!(target: "socket", "Failed to store nonce of {}, closing: {}", &conn_id, e);
+                            close_on_redis_error(&mut ws_sender, config, &conn_id).await?;
+
+                            break;
+                        }
+
+                        debug!(target: "socket", "REIDENTIFY to {}", &conn_id);
+                        send_message(&mut ws_sender, config, &conn_id, &SocketMessage::reidentify(nonce.clone())).await?;
+                    }
+                }
+            },
+            _ = shutdown.changed() => {
+                debug!(target: "socket", "Shutting down, closing {}", &conn_id);
+
+                // Nothing is kept for a RESUME, the node is going away
+                connections::update(connections, &conn_id, |connection| {
+                    connection.resumable = false;
+                });
+
+                let close = close_going_away(&mut ws_sender, config, &conn_id);
+                let _ = tokio::time::timeout(CLOSE_TIMEOUT, close).await;
+
+                break;
+            },
+            _ = too_slow.notified() => {
+                warn!(target: "socket", "Outbound queue of {} is full, closing it", &conn_id);
+                METRICS.slow_connections.fetch_add(1, Ordering::Relaxed);
+
+                // It isn't reading, don't wait on it for long
+                let close = close_with_error(&mut ws_sender, config, &conn_id, ErrorCode::SLOW, None);
+                let _ = tokio::time::timeout(CLOSE_TIMEOUT, close).await;
+
+                break;
+            },
+            _ = heartbeat.tick() => {
+                if !identified && Instant::now() >= identify_deadline {
+                    debug!(target: "socket", "{} didn't IDENTIFY within {:?}, closing", &conn_id, &config.identify_timeout);
+                    close_with_error(&mut ws_sender, config, &conn_id, ErrorCode::AUTH, Some("Didn't IDENTIFY in time")).await?;
+
+                    break;
+                }
+
+                //send(&mut ws_sender, &config, &conn_id, Message::Text("deez".to_owned())).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num::FromPrimitive;
+    use serde_json::{json, Value};
+    use tokio::io::DuplexStream;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use crate::config::Settings;
+    use crate::opcodes::{Health, HEARTBEAT_ACK};
+
+    fn config() -> Config {
+        Config::from_settings(&Settings::from_pairs([("SECRET", "s3cret"), ("REGION", "test")])).unwrap()
+    }
+
+    fn error_codes() -> Vec<ErrorCode> {
+        (4000..=4011).map(|code| ErrorCode::from_u16(code).unwrap()).collect()
+    }
+
+    /// Server side sender and client side socket of an in-memory websocket
+    async fn socket_pair() -> (WsSender<DuplexStream>, WebSocketStream<DuplexStream>) {
+        let (server, client) = tokio::io::duplex(4096);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+        (WsSender { sink: server.split().0, too_slow: Arc::new(Notify::new()) }, client)
+    }
+
+    async fn received(client: &mut WebSocketStream<DuplexStream>) -> Message {
+        client.next().await.unwrap().unwrap()
+    }
+
+    fn json(msg: Message) -> Value {
+        serde_json::from_str(msg.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_error_wire_format() {
+        let config = config();
+
+        for code in error_codes() {
+            let (mut sender, mut client) = socket_pair().await;
+            send_error(&mut sender, &config, "conn", code).await.unwrap();
+
+            assert_eq!(json(received(&mut client).await), json!({"op": 7, "d": {"code": code as u16, "message": code.message()}}));
+        }
+    }
+
+    #[tokio::test]
+    async fn send_rate_limited_wire_format() {
+        let (mut sender, mut client) = socket_pair().await;
+        send_rate_limited(&mut sender, &config(), "conn", Duration::from_millis(1500)).await.unwrap();
+
+        assert_eq!(json(received(&mut client).await), json!({"op": 7, "d": {"code": 4010, "message": "Rate limited", "retry_after_ms": 1500}}));
+    }
+
+    #[tokio::test]
+    async fn mismatched_messages_arent_sent() {
+        let config = config();
+        let (mut sender, mut client) = socket_pair().await;
+
+        let mismatched = SocketMessage { op: OpCode::READY, d: MessageData::HEARTBEAT_ACK(HEARTBEAT_ACK { health: Health::MAX }) };
+        send_message(&mut sender, &config, "conn", &mismatched).await.unwrap();
+        send_message(&mut sender, &config, "conn", &SocketMessage::heartbeat_ack(Health::MAX)).await.unwrap();
+
+        // Only the valid one made it
+        assert_eq!(json(received(&mut client).await), json!({"op": 5, "d": {"health": 1.0}}));
+    }
+
+    #[tokio::test]
+    async fn close_with_error_wire_format() {
+        let config = config();
+
+        for code in error_codes() {
+            for reason in [None, Some("Custom reason")] {
+                let (mut sender, mut client) = socket_pair().await;
+                close_with_error(&mut sender, &config, "conn", code, reason).await.unwrap();
+
+                let frame = match received(&mut client).await {
+                    Message::Close(Some(frame)) => frame,
+                    msg => panic!("Expected a close frame, got {:?}", msg)
+                };
+                let advice: Value = serde_json::from_str(&frame.reason).unwrap();
+
+                assert_eq!(u16::from(frame.code), code as u16);
+                assert_eq!(advice, serde_json::to_value(code.close_advice(reason.unwrap_or(code.message()))).unwrap());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pre_auth_violation_closes_at_limit() {
+        let mut config = config();
+        config.max_pre_auth_violations = 2;
+        let (mut sender, mut client) = socket_pair().await;
+        let mut violations = 0;
+
+        assert!(!pre_auth_violation(&mut sender, &config, "conn", &mut violations, ErrorCode::AUTH).await.unwrap());
+        assert_eq!(json(received(&mut client).await)["d"]["code"], 4001);
+
+        assert!(pre_auth_violation(&mut sender, &config, "conn", &mut violations, ErrorCode::AUTH).await.unwrap());
+        assert!(matches!(received(&mut client).await, Message::Close(Some(frame)) if u16::from(frame.code) == 4001));
+    }
+}
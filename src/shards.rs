@@ -0,0 +1,142 @@
+//! Redis connections on threads of their own, which the connections hand their
+//! Redis work to instead of each holding a Redis connection
+//!
+//! Work is routed by key. Anything that changes a guild's channels or the voice
+//! states in them is routed by the guild ID (the channel ID for dms), so it goes
+//! through the same shard in the order it was handed over, and a busy guild
+//! only holds back the guilds sharing its shard. Finding which channel a voice
+//! state is in is a read routed by its session ID, since the guild isn't known
+//! before it. Work about no guild, like nonces, the auth audit and handing
+//! voice states over on RESUME, is routed by the connection or the peer.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc;
+use std::thread;
+use ::redis::{Client, Connection, ErrorKind, RedisError, RedisResult};
+use tokio::sync::oneshot;
+
+/// Work for a shard, given its connection or why there's none, giving whether
+/// the connection broke and has to be opened again
+type Job = Box<dyn FnOnce(RedisResult<&mut Connection>) -> bool + Send>;
+
+/// Redis shards, cheap to clone
+#[derive(Clone)]
+pub struct Shards {
+    jobs: Vec<mpsc::Sender<Job>>
+}
+
+impl Shards {
+    /// Start `count` shards on threads of their own, each connecting to Redis
+    /// on its first job
+    pub fn start(client: &Client, count: usize) -> Shards {
+        let jobs = (0..count.max(1))
+            .map(|shard| {
+                let (sender, receiver) = mpsc::channel();
+                let client = client.clone();
+
+                thread::Builder::new()
+                    .name(format!("redis-shard-{}", shard))
+                    .spawn(move || work(shard, client, receiver))
+                    .expect("Failed to start Redis shard!");
+
+                sender
+            })
+            .collect();
+
+        Shards { jobs }
+    }
+
+    /// Shard `key` is routed to
+    pub fn shard_of(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        (hasher.finish() % self.jobs.len() as u64) as usize
+    }
+
+    /// Run `job` on the shard of `key` and give its result, or the error
+    /// connecting to Redis failed with
+    ///
+    /// Jobs on the same shard run one at a time, in the order they were run.
+    pub async fn run<T, F>(&self, key: &str, job: F) -> RedisResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> RedisResult<T> + Send + 'static
+    {
+        let (result_sender, result) = oneshot::channel();
+
+        let job: Job = Box::new(move |redis| {
+            let result = redis.and_then(job);
+            let broken = matches!(&result, Err(e) if e.is_io_error() || e.is_connection_dropped() || e.is_timeout());
+            let _ = result_sender.send(result);
+
+            broken
+        });
+
+        if self.jobs[self.shard_of(key)].send(job).is_err() {
+            return Err(shard_gone());
+        }
+
+        result.await.unwrap_or_else(|_| Err(shard_gone()))
+    }
+}
+
+/// Run the jobs of a shard until every sender is gone
+fn work(shard: usize, client: Client, jobs: mpsc::Receiver<Job>) {
+    let mut redis: Option<Connection> = None;
+
+    for job in jobs {
+        if redis.is_none() {
+            match client.get_connection() {
+                Ok(connection) => redis = Some(connection),
+                Err(e) => {
+                    warn!(target: "shards", "Shard {} failed to connect to Redis: {}", shard, e);
+                    job(Err(e));
+
+                    continue;
+                }
+            }
+        }
+
+        if job(Ok(redis.as_mut().unwrap())) {
+            debug!(target: "shards", "Connection of shard {} broke, reconnecting on the next job", shard);
+            redis = None;
+        }
+    }
+}
+
+fn shard_gone() -> RedisError {
+    RedisError::from((ErrorKind::IoError, "Redis shard stopped"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_stick_to_a_shard() {
+        let shards = Shards { jobs: (0..4).map(|_| mpsc::channel().0).collect() };
+
+        for key in ["1", "9876543210987654321", "dm"] {
+            assert_eq!(shards.shard_of(key), shards.shard_of(key));
+            assert!(shards.shard_of(key) < 4);
+        }
+
+        // Spread over every shard
+        let mut used = [false; 4];
+        for guild in 0..100 {
+            used[shards.shard_of(&guild.to_string())] = true;
+        }
+        assert_eq!(used, [true; 4]);
+    }
+
+    #[tokio::test]
+    async fn unreachable_redis_fails_the_job() {
+        // Nothing listens on port 1
+        let client = Client::open("redis://127.0.0.1:1/").unwrap();
+        let shards = Shards::start(&client, 2);
+
+        let result = shards.run("1", |_| Ok(())).await;
+        assert!(result.is_err());
+    }
+}
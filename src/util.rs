@@ -1,13 +1,269 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
 use hmac::{Hmac, Mac};
+use serde_json::Value;
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use rand::seq::SliceRandom;
 use sha2::Sha256;
+use tokio_tungstenite::tungstenite::Message;
+use crate::config::Config;
 
 type HmacSha256 = Hmac<Sha256>;
 
-pub async fn verify_token(secret: String, nonce: Option<String>, token: String) -> bool {
+/// Max amount of characters of a frame shown when logging raw frames
+const RAW_FRAME_PREVIEW: usize = 512;
+
+/// Length of the nonce sent in HELLO
+pub const NONCE_LENGTH: usize = 10;
+
+/// What the challenge is prefixed with in the server's proof, `-` and `:` are
+/// never in a nonce
+const SERVER_PROOF_PREFIX: &str = "lvsp-server-proof:";
+
+/// Length of the ID given to each connection
+pub const CONNECTION_ID_LENGTH: usize = 16;
+
+/// Crockford's base32 alphabet, without the easily confused I, L, O and U
+const UNAMBIGUOUS_ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Reasons a token couldn't be checked at all, as opposed to just not matching
+#[derive(Debug)]
+pub enum TokenError {
+    /// There's no nonce stored for the connection, it's removed once it
+    /// verified a token
+    MissingNonce,
+
+    /// The stored nonce isn't NONCE_LENGTH long, so it got truncated or
+    /// corrupted in Redis
+    NonceLength(usize),
+
+    /// The token isn't a hex string
+    Decode
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::MissingNonce => write!(f, "no nonce stored for the connection, it was already used"),
+            TokenError::NonceLength(len) => write!(f, "stored nonce is {} characters long, expected {}", len, NONCE_LENGTH),
+            TokenError::Decode => write!(f, "token isn't valid hex")
+        }
+    }
+}
+
+/// Check that `token` is the hex HMAC-SHA256 of `nonce` keyed with `secret`
+pub fn verify_token(secret: &str, nonce: Option<&str>, token: &str) -> Result<bool, TokenError> {
+    let nonce = nonce.ok_or(TokenError::MissingNonce)?;
+
+    if nonce.len() != NONCE_LENGTH {
+        return Err(TokenError::NonceLength(nonce.len()));
+    }
+
+    let token = hex::decode(token).map_err(|_| TokenError::Decode)?;
+
     let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
         .expect("Failed to load key for hmac verification!");
 
-    mac.update(nonce.expect("Missing nonce?").as_bytes());
+    mac.update(nonce.as_bytes());
+
+    Ok(mac.verify_slice(token.as_slice()).is_ok())
+}
+
+/// Prove knowledge of `secret` to a client that sent `challenge`, as the hex
+/// HMAC-SHA256 of SERVER_PROOF_PREFIX followed by the challenge
+///
+/// The prefix can't show up in a nonce, so a token made by a client can never
+/// pass for a proof.
+pub fn server_proof(secret: &str, challenge: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("Failed to load key for hmac proof!");
+
+    mac.update(SERVER_PROOF_PREFIX.as_bytes());
+    mac.update(challenge.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Log a frame going `direction` to/from the connection at trace, with any `token`
+/// field redacted and the payload capped to a preview
+pub fn log_raw_frame(direction: &str, conn_id: &str, msg: &Message) {
+    match msg {
+        Message::Text(text) => match serde_json::from_str::<Value>(text) {
+            Ok(mut json) => {
+                redact_tokens(&mut json);
+
+                let op = json.get("op").cloned().unwrap_or(Value::Null);
+                let preview: String = json.to_string().chars().take(RAW_FRAME_PREVIEW).collect();
+
+                trace!(target: "frames", "{} {} op: {} size: {} {}", direction, conn_id, op, text.len(), preview);
+            },
+            Err(_) => {
+                let preview: String = text.chars().take(RAW_FRAME_PREVIEW).collect();
+
+                trace!(target: "frames", "{} {} size: {} {}", direction, conn_id, text.len(), preview);
+            }
+        },
+        msg => trace!(target: "frames", "{} {} size: {} {:?}", direction, conn_id, msg.len(), msg)
+    }
+}
+
+fn redact_tokens(json: &mut Value) {
+    match json {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if key == "token" {
+                    *value = Value::String("<redacted>".to_string());
+                } else {
+                    redact_tokens(value);
+                }
+            }
+        },
+        Value::Array(values) => values.iter_mut().for_each(redact_tokens),
+        _ => ()
+    }
+}
+
+/// Heartbeat interval to send in HELLO, randomized within `heartbeat_jitter`
+/// of the configured one so clients don't all heartbeat in lockstep
+///
+/// Never goes under 1, as the interval is a whole number.
+pub fn jittered_heartbeat_interval(config: &Config) -> i32 {
+    if config.heartbeat_jitter <= 0.0 {
+        return config.heartbeat_interval;
+    }
+
+    let base = config.heartbeat_interval as f32;
+    let jitter = base * config.heartbeat_jitter;
+    let interval = rand::thread_rng().gen_range(base - jitter..=base + jitter);
+
+    (interval.round() as i32).max(1)
+}
+
+/// Whether the time between two heartbeats is further from the heartbeat
+/// interval advertised to the connection than the configured tolerance
+pub fn heartbeat_deviates(config: &Config, heartbeat_interval: i32, elapsed: Duration) -> bool {
+    let expected = heartbeat_interval as f32;
+    let deviation = (elapsed.as_secs_f32() - expected).abs();
+
+    deviation > expected * config.heartbeat_tolerance
+}
+
+/// Generate a random token of `length` alphanumerics, or of characters from
+/// `UNAMBIGUOUS_ALPHABET` if `unambiguous` is set
+pub fn generate_token(length: usize, unambiguous: bool) -> String {
+    let mut rng = rand::thread_rng();
+
+    if unambiguous {
+        (0..length)
+            .map(|_| *UNAMBIGUOUS_ALPHABET.choose(&mut rng).unwrap() as char)
+            .collect()
+    } else {
+        rng.sample_iter(&Alphanumeric)
+            .take(length)
+            .map(char::from)
+            .collect()
+    }
+}
+
+/// Field names the messages use, so random JSON gets past the first checks
+/// now and then
+#[cfg(test)]
+const FUZZ_FIELDS: &[&str] = &[
+    "op", "d", "type", "data", "token", "session_id", "channel_id", "guild_id", "user_id",
+    "modes", "challenge", "validate_only", "heartbeat_interval", "nonce", "health"
+];
+
+/// Random JSON at most `depth` levels deep, for checking decoding holds up
+/// against anything
+#[cfg(test)]
+pub fn random_json(rng: &mut impl Rng, depth: usize) -> Value {
+    let choice = if depth == 0 { rng.gen_range(0..6) } else { rng.gen_range(0..8) };
+
+    match choice {
+        0 => Value::Null,
+        1 => Value::Bool(rng.gen()),
+        2 => Value::from(rng.gen_range(-2..24)),
+        3 => [Value::from(u64::MAX), Value::from(i64::MIN), Value::from(rng.gen::<f64>())].choose(rng).unwrap().clone(),
+        4 => Value::String(rng.gen_range(0..u64::MAX).to_string()),
+        5 => Value::String((0..rng.gen_range(0..12)).map(|_| rng.gen::<char>()).collect()),
+        6 => Value::Array((0..rng.gen_range(0..4)).map(|_| random_json(rng, depth - 1)).collect()),
+        _ => Value::Object((0..rng.gen_range(0..6)).map(|_| (FUZZ_FIELDS.choose(rng).unwrap().to_string(), random_json(rng, depth - 1))).collect())
+    }
+}
+
+/// `text` with a few random bytes changed, added or removed, as a message
+/// that may not be valid UTF-8
+#[cfg(test)]
+pub fn mangle(rng: &mut impl Rng, text: &str) -> Message {
+    let mut bytes = text.as_bytes().to_vec();
+
+    for _ in 0..rng.gen_range(1..4) {
+        let at = rng.gen_range(0..=bytes.len());
+
+        match rng.gen_range(0..3) {
+            0 if at < bytes.len() => bytes[at] = rng.gen(),
+            1 if at < bytes.len() => { bytes.remove(at); },
+            _ => bytes.insert(at, *b"{}[]\",:0\\".choose(rng).unwrap())
+        }
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(text) => Message::Text(text),
+        Err(e) => Message::Binary(e.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: &str = "0123456789";
+
+    fn token(secret: &str, nonce: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(nonce.as_bytes());
+
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn tokens_are_checked_against_the_nonce() {
+        assert!(verify_token("secret", Some(NONCE), &token("secret", NONCE)).unwrap());
+        assert!(!verify_token("secret", Some(NONCE), &token("other", NONCE)).unwrap());
+        assert!(!verify_token("secret", Some(NONCE), &token("secret", "9876543210")).unwrap());
+    }
+
+    #[test]
+    fn nonce_of_the_wrong_length() {
+        // Whatever the token, a truncated or padded nonce is never checked
+        for nonce in ["", "012345678", "0123456789a", "01234"] {
+            assert!(matches!(
+                verify_token("secret", Some(nonce), &token("secret", nonce)),
+                Err(TokenError::NonceLength(len)) if len == nonce.len()
+            ), "Checked against {:?}", nonce);
+        }
+    }
+
+    #[test]
+    fn proofs_and_tokens_dont_pass_for_each_other() {
+        // A challenge that looks like a nonce
+        let proof = server_proof("secret", NONCE);
+
+        assert_eq!(proof.len(), 64);
+        assert_eq!(proof, server_proof("secret", NONCE));
+        assert_ne!(proof, server_proof("other", NONCE));
+        assert_ne!(proof, server_proof("secret", "9876543210"));
+
+        // A client's token over the challenge isn't the proof, and the proof
+        // isn't a token for the nonce
+        assert_ne!(proof, token("secret", NONCE));
+        assert!(!verify_token("secret", Some(NONCE), &proof).unwrap());
+    }
 
-    mac.verify_slice(hex::decode(token).expect("Failed to get token as bytes!").as_slice()).is_ok()
-}
\ No newline at end of file
+    #[test]
+    fn missing_nonce_or_undecodable_token() {
+        assert!(matches!(verify_token("secret", None, &token("secret", NONCE)), Err(TokenError::MissingNonce)));
+        assert!(matches!(verify_token("secret", Some(NONCE), "not hex"), Err(TokenError::Decode)));
+    }
+}
@@ -0,0 +1,9 @@
+/// Version of the voice server protocol (LVSP) this server speaks
+pub const LVSP_VERSION: u32 = 1;
+
+/// Version of the server
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Commit the server was built from, `unknown` if it wasn't built from a git
+/// checkout
+pub const GIT_HASH: &str = env!("LVSP_GIT_HASH");
@@ -0,0 +1,105 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+
+/// Voice encryption mode, named like Discord clients expect them
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum VoiceMode {
+    #[serde(rename = "xsalsa20_poly1305")]
+    XSalsa20Poly1305,
+
+    #[serde(rename = "xsalsa20_poly1305_suffix")]
+    XSalsa20Poly1305Suffix,
+
+    #[serde(rename = "xsalsa20_poly1305_lite")]
+    XSalsa20Poly1305Lite,
+
+    #[serde(rename = "aead_aes256_gcm_rtpsize")]
+    AeadAes256GcmRtpSize
+}
+
+impl VoiceMode {
+    pub const ALL: [VoiceMode; 4] = [
+        VoiceMode::XSalsa20Poly1305,
+        VoiceMode::XSalsa20Poly1305Suffix,
+        VoiceMode::XSalsa20Poly1305Lite,
+        VoiceMode::AeadAes256GcmRtpSize
+    ];
+
+    /// Name of the mode on the wire
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VoiceMode::XSalsa20Poly1305 => "xsalsa20_poly1305",
+            VoiceMode::XSalsa20Poly1305Suffix => "xsalsa20_poly1305_suffix",
+            VoiceMode::XSalsa20Poly1305Lite => "xsalsa20_poly1305_lite",
+            VoiceMode::AeadAes256GcmRtpSize => "aead_aes256_gcm_rtpsize"
+        }
+    }
+}
+
+impl Display for VoiceMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Mode name that isn't one of [`VoiceMode::ALL`]
+#[derive(Debug)]
+pub struct UnknownVoiceMode(pub String);
+
+impl FromStr for VoiceMode {
+    type Err = UnknownVoiceMode;
+
+    fn from_str(name: &str) -> Result<VoiceMode, UnknownVoiceMode> {
+        VoiceMode::ALL.into_iter()
+            .find(|mode| mode.as_str() == name)
+            .ok_or_else(|| UnknownVoiceMode(name.to_string()))
+    }
+}
+
+/// Pick the encryption mode for a channel, the first of the server's modes the
+/// client supports, or the server's most preferred one if the client didn't say
+///
+/// The client's modes stay strings, modes this server doesn't know about are
+/// just never picked.
+pub fn negotiate_mode(server_modes: &[VoiceMode], client_modes: Option<&[String]>) -> Option<VoiceMode> {
+    match client_modes {
+        Some(client_modes) => server_modes.iter()
+            .find(|mode| client_modes.iter().any(|client_mode| client_mode == mode.as_str()))
+            .copied(),
+        None => server_modes.first().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_use_their_wire_names() {
+        let names = [
+            (VoiceMode::XSalsa20Poly1305, "xsalsa20_poly1305"),
+            (VoiceMode::XSalsa20Poly1305Suffix, "xsalsa20_poly1305_suffix"),
+            (VoiceMode::XSalsa20Poly1305Lite, "xsalsa20_poly1305_lite"),
+            (VoiceMode::AeadAes256GcmRtpSize, "aead_aes256_gcm_rtpsize")
+        ];
+        assert_eq!(names.len(), VoiceMode::ALL.len());
+
+        for (mode, name) in names {
+            assert_eq!(serde_json::to_value(mode).unwrap(), name);
+            assert_eq!(serde_json::from_value::<VoiceMode>(name.into()).unwrap(), mode);
+            assert_eq!(mode.as_str(), name);
+            assert_eq!(mode.to_string(), name);
+            assert_eq!(name.parse::<VoiceMode>().unwrap(), mode);
+        }
+    }
+
+    #[test]
+    fn unknown_modes_are_refused() {
+        for name in ["", "xsalsa20", "XSALSA20_POLY1305", "XSalsa20Poly1305"] {
+            assert!(serde_json::from_value::<VoiceMode>(name.into()).is_err(), "Decoded {:?}", name);
+            assert!(name.parse::<VoiceMode>().is_err(), "Parsed {:?}", name);
+        }
+    }
+}
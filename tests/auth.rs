@@ -0,0 +1,145 @@
+//! IDENTIFY, which is what keeps anyone without the secret out
+mod common;
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+
+use common::{info, token, TestClient, TestServer, ADMIN_SECRET, SECRET};
+
+/// Proof a server knowing `secret` gives for `challenge`
+fn proof(secret: &str, challenge: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(b"lvsp-server-proof:");
+    mac.update(challenge.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[tokio::test]
+async fn nonces_are_unique_per_connection() {
+    let server = TestServer::start(&[]).await;
+
+    // All from the same address, at the same time
+    let clients = join_all((0..32).map(|_| server.connect())).await;
+
+    let nonces: HashSet<String> = clients.iter().map(|client| client.nonce()).collect();
+    assert_eq!(nonces.len(), clients.len());
+
+    // Each one under a key of its own, holding the nonce its connection got
+    let keys = server.redis.keys("*_nonce");
+    assert_eq!(keys.len(), clients.len());
+
+    let stored: HashSet<String> = keys.iter().map(|key| server.redis.get(key).unwrap()).collect();
+    assert_eq!(stored, nonces);
+}
+
+#[tokio::test]
+async fn identify_with_another_connections_nonce() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+    let mut other = server.connect().await;
+
+    client.send(json!({"op": 1, "d": {"token": token(SECRET, &other.nonce())}})).await;
+    assert_eq!(client.error().await, 4001);
+
+    // The other connection's nonce is still good for it
+    other.identify().await;
+}
+
+#[tokio::test]
+async fn right_token_identifies() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+
+    client.send(json!({"op": 1, "d": {"token": token(SECRET, &client.nonce())}})).await;
+    let ready = client.json().await;
+    assert_eq!(ready["op"], 3, "Expected READY, got {}", ready);
+    assert!(!ready["d"]["session_id"].as_str().unwrap().is_empty());
+
+    // Identified, so INFO goes through
+    let result = client.info(16, json!({"channel_id": "1"})).await;
+    assert_eq!(result["d"]["type"], 17, "Expected CHANNEL_EXISTS_RESULT, got {}", result);
+}
+
+#[tokio::test]
+async fn wrong_secret_doesnt_identify() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+
+    client.send(json!({"op": 1, "d": {"token": token("not the secret", &client.nonce())}})).await;
+    assert_eq!(client.error().await, 4001);
+
+    // No READY came, and INFO is still refused
+    client.send(info(16, json!({"channel_id": "1"}))).await;
+    assert_eq!(client.error().await, 4001);
+}
+
+#[tokio::test]
+async fn token_over_another_nonce_doesnt_identify() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+
+    client.send(json!({"op": 1, "d": {"token": token(SECRET, "0123456789")}})).await;
+    assert_eq!(client.error().await, 4001);
+
+    client.send(info(16, json!({"channel_id": "1"}))).await;
+    assert_eq!(client.error().await, 4001);
+}
+
+#[tokio::test]
+async fn server_proves_it_knows_the_secret() {
+    let server = TestServer::start(&[("ADMIN_SECRET", ADMIN_SECRET)]).await;
+
+    let mut client = server.connect().await;
+    client.send(json!({"op": 1, "d": {"token": token(SECRET, &client.nonce()), "challenge": "abc"}})).await;
+    let ready = client.json().await;
+    assert_eq!(ready["op"], 3, "Expected READY, got {}", ready);
+    assert_eq!(ready["d"]["proof"], proof(SECRET, "abc"));
+    assert_ne!(ready["d"]["proof"], proof("not the secret", "abc"));
+
+    // Proven with the secret the client identified with
+    let mut admin = server.connect().await;
+    admin.send(json!({"op": 1, "d": {"token": token(ADMIN_SECRET, &admin.nonce()), "challenge": "abc"}})).await;
+    assert_eq!(admin.json().await["d"]["proof"], proof(ADMIN_SECRET, "abc"));
+
+    // No challenge, no proof
+    let ready = server.connect().await.identify().await;
+    assert!(ready["d"].get("proof").is_none(), "Got {}", ready);
+}
+
+#[tokio::test]
+async fn resume_gets_a_proof_too() {
+    let server = TestServer::start(&[("ADMIN_SECRET", ADMIN_SECRET)]).await;
+    let mut admin = server.admin().await;
+    let mut client = server.connect().await;
+    let session_id = client.identify().await["d"]["session_id"].clone();
+    client.info(0, json!({"channel_id": "1", "guild_id": "2"})).await;
+
+    // Dropped, so the session waits for a RESUME
+    drop(client);
+    eventually_alone(&mut admin).await;
+
+    let mut client = server.connect().await;
+    client.send(json!({"op": 2, "d": {"token": token(SECRET, &client.nonce()), "session_id": session_id, "challenge": "def"}})).await;
+    let ready = client.json().await;
+    assert_eq!(ready["op"], 3, "Expected READY, got {}", ready);
+    assert_eq!(ready["d"]["proof"], proof(SECRET, "def"));
+}
+
+/// Wait until `admin` is the only connection left
+async fn eventually_alone(admin: &mut TestClient) {
+    for _ in 0..100 {
+        if admin.info(8, json!({})).await["d"]["data"]["sessions"].as_array().unwrap().len() == 1 {
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    panic!("The other connections didn't go away");
+}
@@ -0,0 +1,139 @@
+//! Channels as clients ask for them
+mod common;
+
+use serde_json::json;
+
+use common::{info, TestServer};
+
+#[tokio::test]
+async fn channel_req_gets_assigned() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    let assign = client.info(0, json!({"channel_id": "1", "guild_id": "2"})).await;
+    assert_eq!(assign["op"], 6);
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+    let data = &assign["d"]["data"];
+    assert_eq!(data["channel_id"], "1");
+    assert_eq!(data["guild_id"], "2");
+    assert_eq!(data["region"], "test");
+    assert_eq!(data["mode"], "xsalsa20_poly1305_lite");
+
+    let token = data["token"].as_str().unwrap();
+    assert!(!token.is_empty());
+    assert!(server.redis.members("2_1_voice").contains(&format!("token_{}", token)));
+}
+
+#[tokio::test]
+async fn dm_channel_req_gets_assigned() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    let assign = client.info(0, json!({"channel_id": "1"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+    let data = &assign["d"]["data"];
+    assert_eq!(data["channel_id"], "1");
+    assert_eq!(data["guild_id"], serde_json::Value::Null);
+
+    let token = data["token"].as_str().unwrap();
+    assert!(!token.is_empty());
+    assert!(server.redis.members("dm_1_voice").contains(&format!("token_{}", token)));
+}
+
+#[tokio::test]
+async fn dms_dont_share_keys_with_guilds() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    let dm = client.info(0, json!({"channel_id": "1"})).await;
+    let guild = client.info(0, json!({"channel_id": "1", "guild_id": "1"})).await;
+    assert_eq!(dm["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", dm);
+    assert_eq!(guild["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", guild);
+
+    let done = client.info(3, json!({"user_id": "2", "channel_id": "1"})).await;
+    assert_eq!(done["d"]["type"], 4, "Expected VST_DONE, got {}", done);
+    assert_eq!(done["d"]["data"]["guild_id"], serde_json::Value::Null);
+    let session_id = done["d"]["data"]["session_id"].as_str().unwrap().to_string();
+
+    let mut keys = server.redis.keys("*_voice");
+    keys.sort();
+    assert_eq!(keys, vec!["1_1_voice".to_string(), "dm_1_voice".to_string()]);
+
+    // The voice state only went in the dm
+    assert!(server.redis.members("dm_1_voice").contains(&session_id));
+    assert!(!server.redis.members("1_1_voice").contains(&session_id));
+    assert_eq!(server.redis.field(&format!("{}_session", session_id), "channel").as_deref(), Some("dm_1_voice"));
+}
+
+#[tokio::test]
+async fn integer_snowflakes() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    let assign = client.info(0, json!({"channel_id": 1, "guild_id": 2})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+    assert_eq!(assign["d"]["data"]["channel_id"], "1");
+    assert_eq!(assign["d"]["data"]["guild_id"], "2");
+
+    let done = client.info(3, json!({"user_id": 3, "channel_id": 1, "guild_id": "2"})).await;
+    assert_eq!(done["d"]["type"], 4, "Expected VST_DONE, got {}", done);
+    assert_eq!(done["d"]["data"]["user_id"], "3");
+
+    // The same channel, whichever way it was sent
+    assert_eq!(server.redis.keys("*_voice"), vec!["2_1_voice".to_string()]);
+}
+
+#[tokio::test]
+async fn guilds_on_every_shard() {
+    let server = TestServer::start(&[("REDIS_SHARDS", "3")]).await;
+    let mut clients = Vec::new();
+
+    for guild in 1..=12 {
+        let mut client = server.identified().await;
+        client.send(info(0, json!({"channel_id": "1", "guild_id": guild.to_string()}))).await;
+        clients.push(client);
+    }
+
+    for (guild, client) in (1..=12).zip(&mut clients) {
+        let assign = client.json().await;
+        assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+        assert_eq!(assign["d"]["data"]["guild_id"], guild.to_string());
+        assert!(server.redis.exists(&format!("{}_1_voice", guild)));
+    }
+}
+
+#[tokio::test]
+async fn guild_rate_limit() {
+    let server = TestServer::start(&[("GUILD_CHANNEL_RATE", "1"), ("GUILD_CHANNEL_BURST", "2")]).await;
+    let mut client = server.identified().await;
+
+    for channel in ["1", "2"] {
+        let assign = client.info(0, json!({"channel_id": channel, "guild_id": "9"})).await;
+        assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+    }
+
+    client.send(info(0, json!({"channel_id": "3", "guild_id": "9"}))).await;
+    let error = client.json().await;
+    assert_eq!(error["op"], 7, "Expected ERROR, got {}", error);
+    assert_eq!(error["d"]["code"], 4010);
+
+    let retry_after_ms = error["d"]["retry_after_ms"].as_u64().unwrap();
+    assert!(retry_after_ms > 0 && retry_after_ms <= 1000, "Retry after {}ms", retry_after_ms);
+    assert!(!server.redis.exists("9_3_voice"));
+
+    // Destroying counts against the same guild
+    client.send(info(2, json!({"channel_id": "1", "guild_id": "9"}))).await;
+    assert_eq!(client.error().await, 4010);
+    assert!(server.redis.exists("9_1_voice"));
+
+    // Other guilds aren't held back, and the connection stays open
+    let assign = client.info(0, json!({"channel_id": "3", "guild_id": "10"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+    // Done once the guild waited as long as it was told to
+    tokio::time::sleep(std::time::Duration::from_millis(retry_after_ms)).await;
+    let assign = client.info(0, json!({"channel_id": "3", "guild_id": "9"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+}
@@ -0,0 +1,46 @@
+//! `--check` against a working setup and a broken one
+mod common;
+
+use std::collections::HashMap;
+
+use bannana_pho::check;
+use bannana_pho::config::{Config, Settings};
+
+use common::{FakeRedis, SECRET};
+
+fn config(redis_addr: &str, extra: &[(&str, &str)]) -> Result<Config, String> {
+    let mut pairs: HashMap<&str, &str> = [
+        ("SECRET", SECRET),
+        ("LISTEN_ADDR", "127.0.0.1:0"),
+        ("REDIS_ADDR", redis_addr),
+        ("REDIS_CONNECT_TIMEOUT", "1")
+    ].into_iter().collect();
+    pairs.extend(extra.iter().copied());
+
+    Config::from_settings(&Settings::from_pairs(pairs))
+}
+
+#[tokio::test]
+async fn passes_with_redis_up() {
+    let redis = FakeRedis::start();
+
+    assert!(check::run(config(&redis.addr(), &[])).await);
+}
+
+#[tokio::test]
+async fn fails_with_redis_down() {
+    let redis = FakeRedis::start();
+    redis.set_down(true);
+
+    assert!(!check::run(config(&redis.addr(), &[])).await);
+}
+
+#[tokio::test]
+async fn fails_with_an_invalid_config() {
+    let redis = FakeRedis::start();
+
+    // Secrets are read along with the rest of the config, so a missing one
+    // fails there
+    assert!(!check::run(config(&redis.addr(), &[("SECRET_FILE", "/nonexistent/secret")])).await);
+    assert!(!check::run(config(&redis.addr(), &[("HEARTBEAT_INTERVAL", "0")])).await);
+}
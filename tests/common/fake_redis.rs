@@ -0,0 +1,580 @@
+//! Redis stand-in speaking enough RESP for the server, so the integration
+//! tests run without a Redis
+//!
+//! Keeps everything in memory behind one lock, so each command (and each
+//! MULTI/EXEC) is atomic like in Redis. The Lua scripts of the server are
+//! recognized by what they call and run natively.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+enum Value {
+    Str(Vec<u8>),
+    Set(BTreeSet<Vec<u8>>),
+    Hash(BTreeMap<Vec<u8>, Vec<u8>>),
+    Stream(Vec<(Vec<u8>, Vec<Vec<u8>>)>)
+}
+
+enum Reply {
+    Nil,
+    Int(i64),
+    Bulk(Vec<u8>),
+    Status(&'static str),
+    Error(String),
+    Array(Vec<Reply>)
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Int(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            },
+            Reply::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                items.iter().for_each(|item| item.encode(out));
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Store {
+    values: HashMap<Vec<u8>, Value>,
+    expiry: HashMap<Vec<u8>, Instant>,
+    scripts: HashMap<Vec<u8>, Vec<u8>>,
+    subscribers: Vec<(Vec<u8>, TcpStream)>
+}
+
+/// In-memory Redis on a random local port, stopped when dropped
+pub struct FakeRedis {
+    pub port: u16,
+    store: Arc<Mutex<Store>>,
+    down: Arc<AtomicBool>,
+    stalled: Arc<AtomicBool>,
+    sockets: Arc<Mutex<Vec<TcpStream>>>,
+    stopped: Arc<AtomicBool>
+}
+
+impl FakeRedis {
+    pub fn start() -> FakeRedis {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let fake = FakeRedis {
+            port: listener.local_addr().unwrap().port(),
+            store: Arc::default(),
+            down: Arc::default(),
+            stalled: Arc::default(),
+            sockets: Arc::default(),
+            stopped: Arc::default()
+        };
+
+        let (store, down, stalled, sockets, stopped) = (fake.store.clone(), fake.down.clone(), fake.stalled.clone(), fake.sockets.clone(), fake.stopped.clone());
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                if stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                let socket = match socket {
+                    Ok(socket) => socket,
+                    Err(_) => continue
+                };
+
+                // Refused, as far as the client can tell
+                if down.load(Ordering::Relaxed) {
+                    let _ = socket.shutdown(Shutdown::Both);
+                    continue;
+                }
+
+                sockets.lock().unwrap().push(socket.try_clone().unwrap());
+
+                let (store, stalled) = (store.clone(), stalled.clone());
+                thread::spawn(move || serve(socket, store, stalled));
+            }
+        });
+
+        fake
+    }
+
+    pub fn addr(&self) -> String {
+        format!("redis://127.0.0.1:{}", self.port)
+    }
+
+    /// Drop every connection and refuse new ones while `down`, like a Redis
+    /// that went away
+    pub fn set_down(&self, down: bool) {
+        self.down.store(down, Ordering::Relaxed);
+
+        if down {
+            for socket in self.sockets.lock().unwrap().drain(..) {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// Hold every command until unstalled, like a Redis too busy to answer
+    pub fn set_stalled(&self, stalled: bool) {
+        self.stalled.store(stalled, Ordering::Relaxed);
+    }
+
+    /// Keys matching a glob with `*` only, sorted
+    pub fn keys(&self, pattern: &str) -> Vec<String> {
+        let mut store = self.store.lock().unwrap();
+        purge(&mut store);
+
+        let mut keys: Vec<String> = store.values.keys()
+            .map(|key| String::from_utf8_lossy(key).to_string())
+            .filter(|key| glob(pattern.as_bytes(), key.as_bytes()))
+            .collect();
+        keys.sort();
+
+        keys
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        !self.keys(key).is_empty()
+    }
+
+    /// Members of a set, sorted, empty if it doesn't exist
+    pub fn members(&self, key: &str) -> Vec<String> {
+        match self.store.lock().unwrap().values.get(key.as_bytes()) {
+            Some(Value::Set(set)) => set.iter().map(|member| String::from_utf8_lossy(member).to_string()).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    /// Field of a hash
+    pub fn field(&self, key: &str, field: &str) -> Option<String> {
+        match self.store.lock().unwrap().values.get(key.as_bytes()) {
+            Some(Value::Hash(hash)) => hash.get(field.as_bytes()).map(|value| String::from_utf8_lossy(value).to_string()),
+            _ => None
+        }
+    }
+
+    /// Value of a string key
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self.store.lock().unwrap().values.get(key.as_bytes()) {
+            Some(Value::Str(value)) => Some(String::from_utf8_lossy(value).to_string()),
+            _ => None
+        }
+    }
+
+    /// Add members to a set directly, e.g. to set up state another node made
+    pub fn sadd(&self, key: &str, members: &[&str]) {
+        let mut store = self.store.lock().unwrap();
+
+        if let Value::Set(set) = store.values.entry(key.as_bytes().to_vec()).or_insert_with(|| Value::Set(BTreeSet::new())) {
+            set.extend(members.iter().map(|member| member.as_bytes().to_vec()));
+        }
+    }
+
+    /// Length of a stream, 0 if it doesn't exist
+    pub fn stream_len(&self, key: &str) -> usize {
+        match self.store.lock().unwrap().values.get(key.as_bytes()) {
+            Some(Value::Stream(entries)) => entries.len(),
+            _ => 0
+        }
+    }
+}
+
+impl Drop for FakeRedis {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.set_down(true);
+
+        // Wake the accept loop up so it sees it's stopped
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+    }
+}
+
+fn serve(socket: TcpStream, store: Arc<Mutex<Store>>, stalled: Arc<AtomicBool>) {
+    let mut reader = BufReader::new(socket.try_clone().unwrap());
+    let mut writer = socket;
+    let mut queued: Option<Vec<Vec<Vec<u8>>>> = None;
+
+    while let Some(args) = read_command(&mut reader) {
+        if args.is_empty() {
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+
+        while stalled.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let reply = {
+            let mut store = store.lock().unwrap();
+            purge(&mut store);
+
+            match (name.as_str(), &mut queued) {
+                ("MULTI", _) => {
+                    queued = Some(Vec::new());
+                    Reply::Status("OK")
+                },
+                ("EXEC", queue) => match queue.take() {
+                    Some(commands) => Reply::Array(commands.iter().map(|args| run(&mut store, &writer, args)).collect()),
+                    None => Reply::Error("ERR EXEC without MULTI".to_string())
+                },
+                (_, Some(queue)) => {
+                    queue.push(args);
+                    Reply::Status("QUEUED")
+                },
+                (_, None) => run(&mut store, &writer, &args)
+            }
+        };
+
+        let mut out = Vec::new();
+        reply.encode(&mut out);
+
+        if writer.write_all(&out).is_err() {
+            return;
+        }
+    }
+}
+
+fn read_command(reader: &mut impl BufRead) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).ok()?;
+        arg.truncate(len);
+
+        args.push(arg);
+    }
+
+    Some(args)
+}
+
+fn purge(store: &mut Store) {
+    let now = Instant::now();
+    let expired: Vec<Vec<u8>> = store.expiry.iter()
+        .filter(|(_, at)| **at <= now)
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    for key in expired {
+        store.values.remove(&key);
+        store.expiry.remove(&key);
+    }
+}
+
+/// Match `*` globs, the only wildcard KEYS is used with
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob(rest, &text[skip..])),
+        Some((c, rest)) => text.first() == Some(c) && glob(rest, &text[1..])
+    }
+}
+
+fn int(arg: &[u8]) -> i64 {
+    String::from_utf8_lossy(arg).parse().unwrap_or(0)
+}
+
+fn set_mut<'a>(store: &'a mut Store, key: &[u8]) -> &'a mut BTreeSet<Vec<u8>> {
+    match store.values.entry(key.to_vec()).or_insert_with(|| Value::Set(BTreeSet::new())) {
+        Value::Set(set) => set,
+        _ => panic!("WRONGTYPE {}", String::from_utf8_lossy(key))
+    }
+}
+
+fn hash_mut<'a>(store: &'a mut Store, key: &[u8]) -> &'a mut BTreeMap<Vec<u8>, Vec<u8>> {
+    match store.values.entry(key.to_vec()).or_insert_with(|| Value::Hash(BTreeMap::new())) {
+        Value::Hash(hash) => hash,
+        _ => panic!("WRONGTYPE {}", String::from_utf8_lossy(key))
+    }
+}
+
+fn hget(store: &Store, key: &[u8], field: &[u8]) -> Option<Vec<u8>> {
+    match store.values.get(key) {
+        Some(Value::Hash(hash)) => hash.get(field).cloned(),
+        _ => None
+    }
+}
+
+fn is_member(store: &Store, key: &[u8], member: &[u8]) -> bool {
+    matches!(store.values.get(key), Some(Value::Set(set)) if set.contains(member))
+}
+
+/// Remove members of a set, deleting it once empty like Redis does
+fn srem(store: &mut Store, key: &[u8], members: &[Vec<u8>]) -> i64 {
+    let removed = match store.values.get_mut(key) {
+        Some(Value::Set(set)) => members.iter().filter(|member| set.remove(*member)).count(),
+        _ => 0
+    };
+
+    if matches!(store.values.get(key), Some(Value::Set(set)) if set.is_empty()) {
+        store.values.remove(key);
+    }
+
+    removed as i64
+}
+
+fn del(store: &mut Store, key: &[u8]) -> bool {
+    store.expiry.remove(key);
+    store.values.remove(key).is_some()
+}
+
+fn set_str(store: &mut Store, key: &[u8], value: &[u8], ttl: Option<i64>) {
+    store.values.insert(key.to_vec(), Value::Str(value.to_vec()));
+
+    match ttl {
+        Some(ttl) => store.expiry.insert(key.to_vec(), Instant::now() + Duration::from_secs(ttl as u64)),
+        None => store.expiry.remove(key)
+    };
+}
+
+fn run(store: &mut Store, socket: &TcpStream, args: &[Vec<u8>]) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let key = args.get(1).map(Vec::as_slice).unwrap_or_default();
+
+    match name.as_str() {
+        "PING" => Reply::Status("PONG"),
+        "SELECT" | "AUTH" | "CLIENT" => Reply::Status("OK"),
+        "FLUSHALL" | "FLUSHDB" => {
+            store.values.clear();
+            store.expiry.clear();
+            Reply::Status("OK")
+        },
+        "SET" => {
+            let ttl = (args.len() > 4 && args[3].eq_ignore_ascii_case(b"EX")).then(|| int(&args[4]));
+            set_str(store, key, &args[2], ttl);
+            Reply::Status("OK")
+        },
+        "SETEX" => {
+            set_str(store, key, &args[3], Some(int(&args[2])));
+            Reply::Status("OK")
+        },
+        "GET" => match store.values.get(key) {
+            Some(Value::Str(value)) => Reply::Bulk(value.clone()),
+            _ => Reply::Nil
+        },
+        "GETDEL" => match store.values.get(key) {
+            Some(Value::Str(value)) => {
+                let value = value.clone();
+                del(store, key);
+                Reply::Bulk(value)
+            },
+            _ => Reply::Nil
+        },
+        "DEL" => Reply::Int(args[1..].iter().filter(|key| del(store, key)).count() as i64),
+        "EXISTS" => Reply::Int(args[1..].iter().filter(|key| store.values.contains_key(*key)).count() as i64),
+        "EXPIRE" => {
+            let exists = store.values.contains_key(key);
+            if exists {
+                store.expiry.insert(key.to_vec(), Instant::now() + Duration::from_secs(int(&args[2]) as u64));
+            }
+            Reply::Int(exists as i64)
+        },
+        "SADD" => {
+            let set = set_mut(store, key);
+            Reply::Int(args[2..].iter().filter(|member| set.insert(member.to_vec())).count() as i64)
+        },
+        "SREM" => Reply::Int(srem(store, key, &args[2..])),
+        "SMEMBERS" => match store.values.get(key) {
+            Some(Value::Set(set)) => Reply::Array(set.iter().cloned().map(Reply::Bulk).collect()),
+            _ => Reply::Array(Vec::new())
+        },
+        "SCARD" => match store.values.get(key) {
+            Some(Value::Set(set)) => Reply::Int(set.len() as i64),
+            _ => Reply::Int(0)
+        },
+        "SISMEMBER" => Reply::Int(is_member(store, key, &args[2]) as i64),
+        "HSET" | "HMSET" => {
+            let hash = hash_mut(store, key);
+            let added = args[2..].chunks(2).filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none()).count();
+
+            if name == "HSET" { Reply::Int(added as i64) } else { Reply::Status("OK") }
+        },
+        "HGET" => hget(store, key, &args[2]).map(Reply::Bulk).unwrap_or(Reply::Nil),
+        "HGETALL" => match store.values.get(key) {
+            Some(Value::Hash(hash)) => Reply::Array(hash.iter().flat_map(|(field, value)| [Reply::Bulk(field.clone()), Reply::Bulk(value.clone())]).collect()),
+            _ => Reply::Array(Vec::new())
+        },
+        "HDEL" => {
+            let removed = match store.values.get_mut(key) {
+                Some(Value::Hash(hash)) => args[2..].iter().filter(|field| hash.remove(*field).is_some()).count(),
+                _ => 0
+            };
+            Reply::Int(removed as i64)
+        },
+        "KEYS" => {
+            let mut keys: Vec<Vec<u8>> = store.values.keys().filter(|candidate| glob(key, candidate)).cloned().collect();
+            keys.sort();
+            Reply::Array(keys.into_iter().map(Reply::Bulk).collect())
+        },
+        "SUBSCRIBE" => {
+            for channel in &args[1..] {
+                store.subscribers.push((channel.clone(), socket.try_clone().unwrap()));
+            }
+            Reply::Array(vec![Reply::Bulk(b"subscribe".to_vec()), Reply::Bulk(key.to_vec()), Reply::Int(1)])
+        },
+        "PUBLISH" => {
+            let mut message = Vec::new();
+            Reply::Array(vec![Reply::Bulk(b"message".to_vec()), Reply::Bulk(key.to_vec()), Reply::Bulk(args[2].clone())]).encode(&mut message);
+
+            let delivered = store.subscribers.iter()
+                .filter(|(channel, _)| channel == key)
+                .filter(|(_, subscriber)| (&*subscriber).write_all(&message).is_ok())
+                .count();
+            Reply::Int(delivered as i64)
+        },
+        "SCRIPT" if key.eq_ignore_ascii_case(b"LOAD") => {
+            let sha = sha1::Sha1::from(&args[2]).digest().to_string();
+            store.scripts.insert(sha.clone().into_bytes(), args[2].clone());
+            Reply::Bulk(sha.into_bytes())
+        },
+        "EVALSHA" | "EVAL" => {
+            let script = match name.as_str() {
+                "EVAL" => key.to_vec(),
+                _ => match store.scripts.get(key) {
+                    Some(script) => script.clone(),
+                    None => return Reply::Error("NOSCRIPT No matching script. Please use EVAL.".to_string())
+                }
+            };
+
+            let key_count = int(&args[2]) as usize;
+            eval(store, &script, &args[3..3 + key_count], &args[3 + key_count..])
+        },
+        "XADD" => {
+            let mut i = 2;
+            let mut max_len = None;
+
+            if args[i].eq_ignore_ascii_case(b"MAXLEN") {
+                i += 1;
+                if args[i] == b"~" || args[i] == b"=" {
+                    i += 1;
+                }
+                max_len = Some(int(&args[i]) as usize);
+                i += 1;
+            }
+
+            // The ID, always `*` from the server
+            i += 1;
+
+            let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            let entries = match store.values.entry(key.to_vec()).or_insert_with(|| Value::Stream(Vec::new())) {
+                Value::Stream(entries) => entries,
+                _ => return Reply::Error("WRONGTYPE".to_string())
+            };
+            let id = format!("{}-{}", millis, entries.len()).into_bytes();
+            entries.push((id.clone(), args[i..].to_vec()));
+
+            if let Some(max_len) = max_len {
+                let excess = entries.len().saturating_sub(max_len);
+                entries.drain(..excess);
+            }
+
+            Reply::Bulk(id)
+        },
+        "XLEN" => match store.values.get(key) {
+            Some(Value::Stream(entries)) => Reply::Int(entries.len() as i64),
+            _ => Reply::Int(0)
+        },
+        _ => Reply::Error(format!("ERR unknown command '{}'", name))
+    }
+}
+
+/// Run one of the server's scripts, told apart by the commands they call
+fn eval(store: &mut Store, script: &[u8], keys: &[Vec<u8>], argv: &[Vec<u8>]) -> Reply {
+    let script = String::from_utf8_lossy(script);
+
+    if script.contains("'SISMEMBER'") {
+        // REFRESH_CHANNEL_TOKEN
+        let old = [b"token_".as_slice(), &argv[0]].concat();
+        let new = [b"token_".as_slice(), &argv[1]].concat();
+        let ttl = int(&argv[2]);
+
+        if !is_member(store, &keys[0], &old) || (ttl > 0 && !store.values.contains_key(&keys[1])) {
+            return Reply::Int(0);
+        }
+
+        srem(store, &keys[0], &[old]);
+        del(store, &keys[1]);
+        set_mut(store, &keys[0]).insert(new);
+
+        if ttl > 0 {
+            set_str(store, &keys[2], &keys[0], Some(ttl));
+        }
+
+        Reply::Int(1)
+    } else if script.contains("'SMEMBERS'") {
+        // DESTROY_CHANNEL
+        let members: Vec<Vec<u8>> = match store.values.get(&keys[0]) {
+            Some(Value::Set(set)) => set.iter().cloned().collect(),
+            _ => Vec::new()
+        };
+
+        for member in &members {
+            match member.strip_prefix(b"token_".as_slice()) {
+                Some(token) => del(store, &[token, b"_token".as_slice()].concat()),
+                None => del(store, &[member.as_slice(), b"_session".as_slice()].concat())
+            };
+        }
+
+        del(store, &keys[0]);
+
+        Reply::Array(members.into_iter().map(Reply::Bulk).collect())
+    } else if script.contains("'SADD'") && !script.contains("'SREM'") {
+        // CREATE_VOICE_STATE
+        if !set_mut(store, &keys[0]).insert(argv[0].clone()) {
+            return Reply::Int(0);
+        }
+
+        let hash = hash_mut(store, &keys[1]);
+        hash.insert(b"channel".to_vec(), keys[0].clone());
+        hash.insert(b"connection".to_vec(), argv[1].clone());
+        argv[2..].chunks(2).for_each(|pair| { hash.insert(pair[0].clone(), pair[1].clone()); });
+
+        Reply::Int(1)
+    } else if script.contains("'SADD'") {
+        // MOVE_VOICE_STATE
+        if hget(store, &keys[0], b"channel").as_deref() != Some(keys[1].as_slice()) || !store.values.contains_key(&keys[2]) {
+            return Reply::Int(0);
+        }
+
+        srem(store, &keys[1], &argv[..1]);
+        set_mut(store, &keys[2]).insert(argv[0].clone());
+        hash_mut(store, &keys[0]).insert(b"channel".to_vec(), keys[2].clone());
+
+        Reply::Int(1)
+    } else {
+        // DESTROY_VOICE_STATE
+        let channel = match hget(store, &keys[0], b"channel") {
+            Some(channel) => channel,
+            None => return Reply::Int(0)
+        };
+
+        if argv.len() > 1 && hget(store, &keys[0], b"connection").as_ref() != Some(&argv[1]) {
+            return Reply::Int(0);
+        }
+
+        srem(store, &channel, &argv[..1]);
+        del(store, &keys[0]);
+
+        Reply::Int(1)
+    }
+}
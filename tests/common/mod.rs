@@ -0,0 +1,253 @@
+//! Servers and clients for the integration tests, each server with a
+//! [`FakeRedis`] of its own
+#![allow(dead_code)]
+
+pub mod fake_redis;
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+use bannana_pho::config::{Config, Settings};
+use bannana_pho::health::WARMED_UP;
+use bannana_pho::listener::Listener;
+use bannana_pho::server;
+
+pub use fake_redis::FakeRedis;
+
+pub const SECRET: &str = "s3cret";
+pub const ADMIN_SECRET: &str = "4dmin";
+
+/// How long a reply can take before a test fails
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Server on a random local port, along with its Redis
+pub struct TestServer {
+    pub addr: String,
+    pub redis: FakeRedis,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>
+}
+
+impl TestServer {
+    /// Start a server with the test defaults overridden by `settings`
+    pub async fn start(settings: &[(&str, &str)]) -> TestServer {
+        let redis = FakeRedis::start();
+
+        let mut pairs: HashMap<String, String> = [
+            ("SECRET", SECRET),
+            ("REGION", "test"),
+            ("HEARTBEAT_INTERVAL", "30"),
+            ("REDIS_CONNECT_TIMEOUT", "1"),
+            ("REDIS_PING_INTERVAL", "1")
+        ].iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        pairs.insert("REDIS_ADDR".to_string(), redis.addr());
+        pairs.extend(settings.iter().map(|(name, value)| (name.to_string(), value.to_string())));
+
+        let config = Config::from_settings(&Settings::from_pairs(pairs)).expect("Invalid test config!");
+
+        let listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr();
+
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            server::serve(config, listener, async { let _ = stopped.await; }).await.unwrap();
+        });
+
+        while !WARMED_UP.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        TestServer { addr, redis, shutdown: Some(shutdown), task: Some(task) }
+    }
+
+    pub async fn connect(&self) -> TestClient {
+        TestClient::connect(&self.addr).await
+    }
+
+    /// Connect and IDENTIFY with the shared secret
+    pub async fn identified(&self) -> TestClient {
+        let mut client = self.connect().await;
+        client.identify().await;
+
+        client
+    }
+
+    /// Connect and IDENTIFY with the admin secret, which has to be set
+    pub async fn admin(&self) -> TestClient {
+        let mut client = self.connect().await;
+        client.identify_with(ADMIN_SECRET).await;
+
+        client
+    }
+
+    /// Shut the server down the way SIGTERM does, and wait for it to stop
+    pub async fn stop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+
+        if let Some(task) = self.task.take() {
+            tokio::time::timeout(TIMEOUT, task).await.expect("Server didn't stop!").unwrap();
+        }
+    }
+}
+
+/// What a client got from the server
+#[derive(Debug)]
+pub enum Frame {
+    Json(Value),
+    Close(Option<u16>, String),
+
+    /// The connection ended without a close frame
+    Closed
+}
+
+/// Websocket client speaking LVSP
+pub struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pub hello: Value
+}
+
+impl TestClient {
+    /// Connect without waiting for HELLO, for connections the server turns
+    /// away
+    pub async fn open(addr: &str) -> TestClient {
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+
+        TestClient { ws, hello: Value::Null }
+    }
+
+    /// Connect and read HELLO
+    pub async fn connect(addr: &str) -> TestClient {
+        let mut client = TestClient::open(addr).await;
+
+        client.hello = client.json().await;
+        assert_eq!(client.hello["op"], 0, "Expected HELLO, got {}", client.hello);
+
+        client
+    }
+
+    pub fn nonce(&self) -> String {
+        self.hello["d"]["nonce"].as_str().unwrap().to_string()
+    }
+
+    pub async fn send(&mut self, msg: Value) {
+        self.send_text(&msg.to_string()).await;
+    }
+
+    pub async fn send_text(&mut self, text: &str) {
+        self.ws.send(Message::Text(text.to_string())).await.unwrap();
+    }
+
+    /// Next frame, failing the test if nothing comes in time
+    pub async fn recv(&mut self) -> Frame {
+        loop {
+            let msg = tokio::time::timeout(TIMEOUT, self.ws.next()).await.expect("Timed out waiting for the server!");
+
+            return match msg {
+                Some(Ok(Message::Text(text))) => Frame::Json(serde_json::from_str(&text).unwrap()),
+                Some(Ok(Message::Close(frame))) => Frame::Close(frame.as_ref().map(|frame| frame.code.into()), frame.map(|frame| frame.reason.to_string()).unwrap_or_default()),
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(msg)) => panic!("Unexpected message {:?}", msg),
+                Some(Err(_)) | None => Frame::Closed
+            };
+        }
+    }
+
+    /// Next frame, which has to be a JSON message
+    pub async fn json(&mut self) -> Value {
+        match self.recv().await {
+            Frame::Json(msg) => msg,
+            frame => panic!("Expected a message, got {:?}", frame)
+        }
+    }
+
+    /// Next frame, which has to be a close frame, giving its code and reason
+    pub async fn close_frame(&mut self) -> (u16, String) {
+        match self.recv().await {
+            Frame::Close(Some(code), reason) => (code, reason),
+            frame => panic!("Expected a close frame, got {:?}", frame)
+        }
+    }
+
+    /// Next frame, which has to be an ERROR, giving its code
+    pub async fn error(&mut self) -> i64 {
+        let msg = self.json().await;
+        assert_eq!(msg["op"], 7, "Expected ERROR, got {}", msg);
+
+        msg["d"]["code"].as_i64().unwrap()
+    }
+
+    /// Whether the connection is still up, checked with a heartbeat
+    pub async fn alive(&mut self) -> bool {
+        self.send(json!({"op": 4, "d": {}})).await;
+
+        matches!(self.recv().await, Frame::Json(msg) if msg["op"] == 5)
+    }
+
+    /// IDENTIFY with the shared secret, giving READY
+    pub async fn identify(&mut self) -> Value {
+        self.identify_with(SECRET).await
+    }
+
+    /// IDENTIFY with a token made with `secret`, giving READY
+    pub async fn identify_with(&mut self, secret: &str) -> Value {
+        let token = token(secret, &self.nonce());
+        self.send(json!({"op": 1, "d": {"token": token}})).await;
+
+        let ready = self.json().await;
+        assert_eq!(ready["op"], 3, "IDENTIFY failed: {}", ready);
+
+        ready
+    }
+
+    /// Send an INFO and give the reply
+    pub async fn info(&mut self, info_type: u8, data: Value) -> Value {
+        self.send(info(info_type, data)).await;
+        self.json().await
+    }
+
+    /// Close the connection normally, ending its session
+    pub async fn close(mut self) {
+        let _ = self.ws.close(Some(CloseFrame { code: CloseCode::Normal, reason: "".into() })).await;
+
+        // Wait for the server to answer, so it's done with the connection
+        while let Some(Ok(_)) = tokio::time::timeout(TIMEOUT, self.ws.next()).await.unwrap_or(None) {}
+    }
+}
+
+/// INFO message of `info_type` carrying `data`
+pub fn info(info_type: u8, data: Value) -> Value {
+    json!({"op": 6, "d": {"type": info_type, "data": data}})
+}
+
+/// Token for `nonce` made with `secret`
+pub fn token(secret: &str, nonce: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(nonce.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Wait until `check` passes, failing the test if it doesn't in time
+pub async fn eventually(what: &str, mut check: impl FnMut() -> bool) {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+
+    while !check() {
+        assert!(tokio::time::Instant::now() < deadline, "Timed out waiting for {}", what);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
@@ -0,0 +1,123 @@
+//! INFO requests of a connection handled several at once
+mod common;
+
+use std::time::Duration;
+
+use serde_json::json;
+
+use common::{eventually, info, TestServer};
+
+#[tokio::test]
+async fn pipelined_requests_all_answered() {
+    let server = TestServer::start(&[("INFO_CONCURRENCY", "4")]).await;
+    let mut client = server.identified().await;
+
+    for channel in 1..=10 {
+        client.send(info(0, json!({"channel_id": channel.to_string(), "guild_id": "9"}))).await;
+    }
+
+    for channel in 1..=10 {
+        let assign = client.json().await;
+        assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+        assert_eq!(assign["d"]["data"]["channel_id"], channel.to_string());
+    }
+
+    assert_eq!(server.redis.keys("9_*_voice").len(), 10);
+}
+
+#[tokio::test]
+async fn heartbeats_answered_while_requests_run() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    client.send(info(0, json!({"channel_id": "1", "guild_id": "9"}))).await;
+    client.send(json!({"op": 4, "d": {}})).await;
+
+    // Either can come first, the heartbeat doesn't wait on Redis
+    let mut ops = vec![client.json().await["op"].as_i64().unwrap(), client.json().await["op"].as_i64().unwrap()];
+    ops.sort();
+    assert_eq!(ops, vec![5, 6]);
+}
+
+#[tokio::test]
+async fn requests_before_a_close_still_happen() {
+    let server = TestServer::start(&[("INFO_CONCURRENCY", "2")]).await;
+    let mut client = server.identified().await;
+
+    for channel in 1..=6 {
+        client.send(info(0, json!({"channel_id": channel.to_string(), "guild_id": "9"}))).await;
+    }
+
+    // Closed without waiting for the replies, what they created is still
+    // owned by the session and cleaned up with it
+    client.close().await;
+
+    eventually("the cleanup", || server.redis.keys("*").is_empty()).await;
+}
+
+#[tokio::test]
+async fn close_mid_channel_req() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    // Held up in Redis when the close comes
+    server.redis.set_stalled(true);
+    client.send(info(0, json!({"channel_id": "1", "guild_id": "9"}))).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let closed = tokio::spawn(client.close());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!server.redis.exists("9_1_voice"));
+
+    // The channel is created once Redis answers, then removed with the session
+    server.redis.set_stalled(false);
+    closed.await.unwrap();
+
+    eventually("the cleanup", || server.redis.keys("*").is_empty()).await;
+}
+
+#[tokio::test]
+async fn dropped_mid_channel_req() {
+    let server = TestServer::start(&[("SESSION_GRACE_PERIOD", "0")]).await;
+    let mut client = server.identified().await;
+
+    server.redis.set_stalled(true);
+    client.send(info(0, json!({"channel_id": "1", "guild_id": "9"}))).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Gone without a close frame
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    server.redis.set_stalled(false);
+    eventually("the cleanup", || server.redis.keys("*").is_empty()).await;
+}
+
+#[tokio::test]
+async fn replies_keep_request_order() {
+    let server = TestServer::start(&[("INFO_CONCURRENCY", "8")]).await;
+    let mut client = server.identified().await;
+
+    // Requests going to Redis are held up, the others are answered right away
+    // but still wait their turn
+    server.redis.set_stalled(true);
+
+    client.send(info(0, json!({"channel_id": "1", "guild_id": "9"}))).await;
+    client.send(info(14, json!({}))).await;
+    client.send(info(16, json!({"channel_id": "1", "guild_id": "8"}))).await;
+    client.send(info(16, json!({"guild_id": "9"}))).await;
+    client.send(info(16, json!({"channel_id": "1", "guild_id": "9"}))).await;
+    client.send(info(0, json!({"channel_id": "1", "guild_id": "9", "modes": ["rot13"]}))).await;
+    client.send(info(14, json!({}))).await;
+
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    server.redis.set_stalled(false);
+
+    let mut replies = Vec::new();
+    for _ in 0..7 {
+        let reply = client.json().await;
+        replies.push(if reply["op"] == 7 { format!("error {}", reply["d"]["code"]) } else { format!("type {}", reply["d"]["type"]) });
+    }
+
+    assert_eq!(replies, ["type 1", "type 15", "type 17", "error 4002", "type 17", "error 4005", "type 15"]);
+}
@@ -0,0 +1,163 @@
+//! What each error looks like on the wire, for every path that answers with
+//! one
+mod common;
+
+use serde_json::{json, Value};
+
+use common::{info, token, TestServer, ADMIN_SECRET};
+
+fn error(code: u16, message: &str) -> Value {
+    json!({"op": 7, "d": {"code": code, "message": message}})
+}
+
+#[tokio::test]
+async fn undecodable_message() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    client.send_text("{not json").await;
+    assert_eq!(client.json().await, error(4002, "Failed to decode message"));
+
+    client.send(json!({"op": 1})).await;
+    assert_eq!(client.json().await, error(4002, "Failed to decode message"));
+}
+
+#[tokio::test]
+async fn unknown_opcode() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    client.send(json!({"op": 99, "d": {}})).await;
+    assert_eq!(client.json().await, error(4004, "Unsupported opcode"));
+
+    // Server-only opcodes are as unsupported coming from a client
+    client.send(json!({"op": 0, "d": {"heartbeat_interval": 1, "nonce": "0123456789"}})).await;
+    assert_eq!(client.json().await, error(4004, "Unsupported opcode"));
+}
+
+#[tokio::test]
+async fn identify_with_invalid_token() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+
+    client.send(json!({"op": 1, "d": {"token": token("wrong", &client.nonce())}})).await;
+    assert_eq!(client.json().await, error(4001, "Authentication failed"));
+}
+
+#[tokio::test]
+async fn identify_with_used_nonce() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+
+    client.send(json!({"op": 1, "d": {"token": token("wrong", &client.nonce())}})).await;
+    assert_eq!(client.json().await, error(4001, "Authentication failed"));
+
+    // The first attempt used up the nonce, even a good token fails now
+    client.send(json!({"op": 1, "d": {"token": token(common::SECRET, &client.nonce())}})).await;
+    assert_eq!(client.json().await, error(4001, "Authentication failed"));
+}
+
+#[tokio::test]
+async fn identify_with_undecodable_token() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+
+    client.send(json!({"op": 1, "d": {"token": "not hex"}})).await;
+    assert_eq!(client.json().await, error(4001, "Authentication failed"));
+}
+
+#[tokio::test]
+async fn resume_with_invalid_token() {
+    let server = TestServer::start(&[("ADMIN_SECRET", ADMIN_SECRET)]).await;
+    let mut client = server.connect().await;
+
+    client.send(json!({"op": 2, "d": {"token": token("wrong", &client.nonce()), "session_id": "gone"}})).await;
+    assert_eq!(client.json().await, error(4001, "Authentication failed"));
+}
+
+#[tokio::test]
+async fn resume_unknown_session() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+
+    client.send(json!({"op": 2, "d": {"token": token(common::SECRET, &client.nonce()), "session_id": "gone"}})).await;
+    assert_eq!(client.json().await, error(4003, "Invalid state transition"));
+}
+
+#[tokio::test]
+async fn identify_twice() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    client.send(json!({"op": 1, "d": {"token": token(common::SECRET, &client.nonce())}})).await;
+    assert_eq!(client.json().await, error(4003, "Invalid state transition"));
+}
+
+#[tokio::test]
+async fn malformed_info() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    let malformed = [
+        // No d
+        json!({"op": 6}),
+        // No type
+        json!({"op": 6, "d": {"data": {"channel_id": "1"}}}),
+        // Data of another type
+        json!({"op": 6, "d": {"type": 0, "data": {"session_id": "abc"}}}),
+        // Type out of range
+        json!({"op": 6, "d": {"type": 99, "data": {}}}),
+        json!({"op": 6, "d": {"type": -1, "data": {}}})
+    ];
+
+    for msg in malformed {
+        client.send(msg.clone()).await;
+        assert_eq!(client.json().await, error(4002, "Failed to decode message"), "For {}", msg);
+        assert!(client.alive().await, "Closed after {}", msg);
+    }
+
+    assert!(server.redis.keys("*_voice").is_empty());
+}
+
+#[tokio::test]
+async fn string_too_long() {
+    let server = TestServer::start(&[("MAX_STRING_LENGTH", "8")]).await;
+    let mut client = server.identified().await;
+
+    client.send(info(16, json!({"channel_id": "1234567890123456789", "guild_id": "1"}))).await;
+    assert_eq!(client.json().await, error(4002, "Failed to decode message"));
+}
+
+#[tokio::test]
+async fn snowflake_colliding_with_dms() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    client.send(info(0, json!({"channel_id": "1", "guild_id": "dm"}))).await;
+    assert_eq!(client.json().await, error(4002, "Failed to decode message"));
+    assert!(server.redis.keys("*_voice").is_empty());
+}
+
+#[tokio::test]
+async fn too_many_messages_before_identify() {
+    let server = TestServer::start(&[("MAX_PRE_AUTH_VIOLATIONS", "2")]).await;
+    let mut client = server.connect().await;
+
+    client.send(info(14, json!({}))).await;
+    assert_eq!(client.json().await, error(4001, "Authentication failed"));
+
+    client.send(info(14, json!({}))).await;
+    let (code, reason) = client.close_frame().await;
+    assert_eq!(code, 4001);
+    assert_eq!(serde_json::from_str::<Value>(&reason).unwrap(), json!({"reason": "Too many messages before IDENTIFY", "reconnectable": false}));
+}
+
+#[tokio::test]
+async fn identify_timeout() {
+    let server = TestServer::start(&[("IDENTIFY_TIMEOUT", "1")]).await;
+    let mut client = server.connect().await;
+
+    let (code, reason) = client.close_frame().await;
+    assert_eq!(code, 4001);
+    assert_eq!(serde_json::from_str::<Value>(&reason).unwrap(), json!({"reason": "Didn't IDENTIFY in time", "reconnectable": false}));
+}
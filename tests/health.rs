@@ -0,0 +1,39 @@
+//! Health going down as connections stop heartbeating, kept apart from the
+//! other tests since the share of stale connections is cached globally
+mod common;
+
+use std::time::Duration;
+
+use serde_json::json;
+
+use common::TestServer;
+
+#[tokio::test]
+async fn stale_connections_lower_health() {
+    let server = TestServer::start(&[("HEARTBEAT_INTERVAL", "1"), ("HEARTBEAT_JITTER", "0")]).await;
+    let mut quiet = server.identified().await;
+    let mut client = server.identified().await;
+
+    client.send(json!({"op": 4, "d": {}})).await;
+    let healthy = client.json().await["d"]["health"].as_f64().unwrap();
+    assert!(healthy > 0.9, "Health {} with hardly any load", healthy);
+
+    // The quiet one goes past two intervals without a heartbeat
+    for _ in 0..7 {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        client.send(json!({"op": 4, "d": {}})).await;
+        client.json().await;
+    }
+
+    client.send(json!({"op": 4, "d": {}})).await;
+    let health = client.json().await["d"]["health"].as_f64().unwrap();
+    assert!((health - healthy / 2.0).abs() < 0.01, "Health {} with half the connections stale, was {}", health, healthy);
+
+    // Back to healthy once it heartbeats again, when the share is next worked out
+    quiet.send(json!({"op": 4, "d": {}})).await;
+    quiet.json().await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    client.send(json!({"op": 4, "d": {}})).await;
+    assert_eq!(client.json().await["d"]["health"].as_f64().unwrap(), healthy);
+}
@@ -0,0 +1,57 @@
+//! Heartbeats, which keep connections alive whether they identified or not
+mod common;
+
+use std::time::Duration;
+
+use serde_json::json;
+
+use common::{Frame, TestServer};
+
+#[tokio::test]
+async fn heartbeat_before_identify() {
+    let server = TestServer::start(&[("MAX_PRE_AUTH_VIOLATIONS", "1")]).await;
+    let mut client = server.connect().await;
+
+    // Not counted against the connection, even with no room for a violation
+    for _ in 0..3 {
+        client.send(json!({"op": 4, "d": {}})).await;
+        assert_eq!(client.json().await["op"], 5);
+    }
+
+    client.identify().await;
+}
+
+#[tokio::test]
+async fn heartbeat_after_identify() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    for _ in 0..3 {
+        client.send(json!({"op": 4, "d": {}})).await;
+
+        let ack = client.json().await;
+        assert_eq!(ack["op"], 5, "Expected HEARTBEAT_ACK, got {}", ack);
+        assert!(ack["d"]["health"].is_number(), "No health in {}", ack);
+    }
+}
+
+#[tokio::test]
+async fn heartbeat_doesnt_extend_identify_timeout() {
+    let server = TestServer::start(&[("IDENTIFY_TIMEOUT", "1")]).await;
+    let mut client = server.connect().await;
+
+    loop {
+        client.send(json!({"op": 4, "d": {}})).await;
+
+        match client.recv().await {
+            Frame::Json(ack) => assert_eq!(ack["op"], 5, "Expected HEARTBEAT_ACK, got {}", ack),
+            Frame::Close(code, _) => {
+                assert_eq!(code, Some(4001));
+                break;
+            },
+            Frame::Closed => panic!("Closed without a close frame")
+        }
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
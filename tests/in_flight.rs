@@ -0,0 +1,47 @@
+//! Connections sending INFO requests faster than they're answered, in a file
+//! of its own since it counts on the metrics
+mod common;
+
+use std::sync::atomic::Ordering;
+
+use serde_json::json;
+
+use bannana_pho::metrics::METRICS;
+use common::{eventually, info, TestServer};
+
+#[tokio::test]
+async fn flood_gets_busy_errors() {
+    let server = TestServer::start(&[("MAX_IN_FLIGHT", "4"), ("MAX_BUSY_REPLIES", "3")]).await;
+    let mut client = server.identified().await;
+
+    server.redis.set_stalled(true);
+
+    for channel in 1..=10 {
+        client.send(info(16, json!({"channel_id": channel.to_string(), "guild_id": "9"}))).await;
+    }
+
+    // Past 4 in flight the requests are refused, past 3 refusals the
+    // connection isn't read anymore
+    eventually("the refusals", || METRICS.busy_requests.load(Ordering::Relaxed) == 3).await;
+    server.redis.set_stalled(false);
+
+    for channel in 1..=4 {
+        let result = client.json().await;
+        assert_eq!(result["d"]["type"], 17, "Expected CHANNEL_EXISTS_RESULT, got {}", result);
+        assert_eq!(result["d"]["data"]["channel_id"], channel.to_string());
+    }
+
+    // In order, after the replies to the requests before them
+    for _ in 0..3 {
+        assert_eq!(client.error().await, 4011);
+    }
+
+    // Read once replies went out, refused or not depending on how many did
+    for _ in 0..3 {
+        let reply = client.json().await;
+        assert!(reply["op"] == 7 || reply["d"]["type"] == 17, "Expected BUSY or CHANNEL_EXISTS_RESULT, got {}", reply);
+    }
+
+    let result = client.info(16, json!({"channel_id": "1", "guild_id": "9"})).await;
+    assert_eq!(result["d"]["type"], 17, "Expected CHANNEL_EXISTS_RESULT, got {}", result);
+}
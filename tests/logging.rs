@@ -0,0 +1,63 @@
+//! The connection ID on log lines, captured from the global logger so it's
+//! in a file of its own
+mod common;
+
+use std::sync::Mutex;
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Value};
+
+use bannana_pho::logging;
+use common::TestServer;
+
+static LINES: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+/// Keeps the JSON line of every record instead of writing it out
+struct Capture;
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        LINES.lock().unwrap().push(logging::json_line(String::new(), record));
+    }
+
+    fn flush(&self) {}
+}
+
+#[tokio::test]
+async fn conn_id_on_lines_from_helpers() {
+    log::set_logger(&Capture).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let mut server = TestServer::start(&[("LOG_RAW_FRAMES", "true")]).await;
+    let mut client = server.identified().await;
+
+    let assign = client.info(0, json!({"channel_id": "1", "guild_id": "2"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+    client.close().await;
+    server.stop().await;
+
+    let lines = LINES.lock().unwrap();
+
+    // Logged by util::log_raw_frame, which only gets the ID to print it
+    let frames: Vec<&Value> = lines.iter().filter(|line| line["target"] == "frames").collect();
+    assert!(!frames.is_empty(), "No frames logged in {:?}", lines);
+
+    let conn_id = frames[0]["conn"].as_str().expect("Frame logged without a connection ID");
+    for line in &frames {
+        assert_eq!(line["conn"], conn_id, "Frame of another connection: {}", line);
+        assert!(line["message"].as_str().unwrap().contains(conn_id), "Frame with the wrong connection ID: {}", line);
+    }
+
+    // Every line about the connection has it, not only the frames
+    let targets: Vec<&Value> = lines.iter().filter(|line| line["conn"] == conn_id).map(|line| &line["target"]).collect();
+    assert!(targets.iter().any(|target| *target != "frames"), "Only frames have the connection ID: {:?}", targets);
+
+    // Lines from outside any connection don't
+    let redis = lines.iter().find(|line| line["message"].as_str().unwrap().starts_with("Connecting to Redis")).expect("Connecting to Redis wasn't logged");
+    assert!(redis.get("conn").is_none(), "Got a connection ID: {}", redis);
+}
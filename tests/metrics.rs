@@ -0,0 +1,62 @@
+//! The metrics server, on its own
+use std::net::TcpListener;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+use bannana_pho::metrics;
+
+/// Local address nothing listens on, for the metrics server to bind
+fn free_addr() -> String {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+}
+
+/// Connect to the metrics server, waiting for it to be up
+async fn connect(addr: &str) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return stream;
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    panic!("The metrics server didn't come up");
+}
+
+async fn response(mut stream: TcpStream) -> String {
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    response
+}
+
+#[tokio::test]
+async fn stops_accepting_after_shutdown() {
+    let addr = free_addr();
+    let (shutdown, stopped) = watch::channel(false);
+    let server = tokio::spawn(metrics::serve(addr.clone(), stopped));
+
+    let mut stream = connect(&addr).await;
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await.unwrap();
+    assert!(response(stream).await.starts_with("HTTP/1.1 200 OK"));
+
+    // Half sent when the shutdown comes, it still gets its answer
+    let mut unfinished = connect(&addr).await;
+    unfinished.write_all(b"GET /metrics HTTP/1.1\r\n").await.unwrap();
+
+    // Accepted before the shutdown comes
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    shutdown.send(true).unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(TcpStream::connect(&addr).await.is_err(), "Still accepting after shutdown");
+
+    unfinished.write_all(b"\r\n").await.unwrap();
+    assert!(response(unfinished).await.starts_with("HTTP/1.1 200 OK"));
+
+    tokio::time::timeout(Duration::from_secs(5), server).await.expect("The metrics server didn't stop").unwrap();
+}
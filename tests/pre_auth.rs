@@ -0,0 +1,53 @@
+//! Messages sent before IDENTIFY, which only HEARTBEAT gets through
+mod common;
+
+use serde_json::json;
+
+use common::{info, TestServer};
+
+#[tokio::test]
+async fn info_before_identify_does_nothing() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+
+    client.send(info(0, json!({"channel_id": "1", "guild_id": "2"}))).await;
+    assert_eq!(client.error().await, 4001);
+
+    client.send(info(3, json!({"user_id": "1", "channel_id": "1", "guild_id": "2"}))).await;
+    assert_eq!(client.error().await, 4001);
+
+    assert!(server.redis.keys("*_voice").is_empty(), "Created {:?}", server.redis.keys("*_voice"));
+    assert!(server.redis.keys("*_session").is_empty(), "Created {:?}", server.redis.keys("*_session"));
+
+    // Still free to IDENTIFY
+    client.identify().await;
+    let assign = client.info(0, json!({"channel_id": "1", "guild_id": "2"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+}
+
+#[tokio::test]
+async fn too_many_messages_before_identify() {
+    let server = TestServer::start(&[("MAX_PRE_AUTH_VIOLATIONS", "2")]).await;
+    let mut client = server.connect().await;
+
+    client.send(info(0, json!({"channel_id": "1", "guild_id": "2"}))).await;
+    assert_eq!(client.error().await, 4001);
+
+    client.send(info(0, json!({"channel_id": "1", "guild_id": "2"}))).await;
+    let (code, _) = client.close_frame().await;
+    assert_eq!(code, 4001);
+
+    assert!(server.redis.keys("*_voice").is_empty(), "Created {:?}", server.redis.keys("*_voice"));
+}
+
+#[tokio::test]
+async fn quiet_before_identify() {
+    let server = TestServer::start(&[("QUIET_PRE_AUTH", "true")]).await;
+    let mut client = server.connect().await;
+
+    client.send(info(0, json!({"channel_id": "1", "guild_id": "2"}))).await;
+
+    // Nothing comes back for the INFO, the heartbeat is the next reply
+    assert!(client.alive().await);
+    assert!(server.redis.keys("*_voice").is_empty(), "Created {:?}", server.redis.keys("*_voice"));
+}
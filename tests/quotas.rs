@@ -0,0 +1,67 @@
+//! How many channels and voice states a session can own at once
+mod common;
+
+use serde_json::json;
+
+use common::{info, TestServer};
+
+#[tokio::test]
+async fn channel_quota() {
+    let server = TestServer::start(&[("MAX_SESSION_CHANNELS", "2")]).await;
+    let mut client = server.identified().await;
+
+    for channel in ["1", "2"] {
+        let assign = client.info(0, json!({"channel_id": channel, "guild_id": "9"})).await;
+        assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+    }
+
+    client.send(info(0, json!({"channel_id": "3", "guild_id": "9"}))).await;
+    assert_eq!(client.error().await, 4007);
+    assert!(!server.redis.exists("9_3_voice"));
+
+    // A channel it already owns doesn't count twice
+    let assign = client.info(0, json!({"channel_id": "1", "guild_id": "9"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+    // The quota is per session
+    let mut other = server.identified().await;
+    let assign = other.info(0, json!({"channel_id": "3", "guild_id": "9"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+    // Destroying one makes room again
+    let ack = client.info(2, json!({"channel_id": "2", "guild_id": "9"})).await;
+    assert_eq!(ack["d"]["type"], 12, "Expected CHANNEL_DESTROY_ACK, got {}", ack);
+
+    let assign = client.info(0, json!({"channel_id": "4", "guild_id": "9"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+}
+
+#[tokio::test]
+async fn voice_state_quota() {
+    let server = TestServer::start(&[("MAX_SESSION_VOICE_STATES", "2")]).await;
+    let mut client = server.identified().await;
+
+    let mut session_ids = Vec::new();
+    for user in ["1", "2"] {
+        let done = client.info(3, json!({"user_id": user, "channel_id": "1", "guild_id": "9"})).await;
+        assert_eq!(done["d"]["type"], 4, "Expected VST_DONE, got {}", done);
+        session_ids.push(done["d"]["data"]["session_id"].as_str().unwrap().to_string());
+    }
+
+    client.send(info(3, json!({"user_id": "3", "channel_id": "1", "guild_id": "9"}))).await;
+    assert_eq!(client.error().await, 4007);
+    assert_eq!(server.redis.members("9_1_voice").len(), 2);
+    assert!(client.alive().await);
+
+    // The quota is per session
+    let mut other = server.identified().await;
+    let done = other.info(3, json!({"user_id": "3", "channel_id": "1", "guild_id": "9"})).await;
+    assert_eq!(done["d"]["type"], 4, "Expected VST_DONE, got {}", done);
+
+    // Destroying one makes room again
+    let ack = client.info(5, json!({"session_id": session_ids[0]})).await;
+    assert_eq!(ack["d"]["type"], 13, "Expected VST_DESTROY_ACK, got {}", ack);
+
+    let done = client.info(3, json!({"user_id": "4", "channel_id": "1", "guild_id": "9"})).await;
+    assert_eq!(done["d"]["type"], 4, "Expected VST_DONE, got {}", done);
+}
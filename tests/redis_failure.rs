@@ -0,0 +1,77 @@
+//! What clients see when Redis goes away, kept apart from the other tests
+//! since whether Redis is up is tracked globally
+mod common;
+
+use serde_json::json;
+
+use common::{info, token, TestClient, TestServer, SECRET};
+
+const REDIS_UNAVAILABLE: &str = r#"{"reason":"Redis unavailable, try again later","reconnectable":true,"retry_after_ms":1000}"#;
+
+#[tokio::test]
+async fn redis_unavailable_at_connect() {
+    let server = TestServer::start(&[]).await;
+    server.redis.set_down(true);
+
+    let mut client = TestClient::open(&server.addr).await;
+    assert_eq!(client.close_frame().await, (4000, REDIS_UNAVAILABLE.to_string()));
+
+    // Connections work again as soon as Redis is back
+    server.redis.set_down(false);
+    server.identified().await;
+}
+
+#[tokio::test]
+async fn redis_failing_on_identify() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+
+    server.redis.set_down(true);
+
+    client.send(json!({"op": 1, "d": {"token": token(SECRET, &client.nonce())}})).await;
+    assert_eq!(client.error().await, 4000);
+    assert_eq!(client.close_frame().await, (4000, REDIS_UNAVAILABLE.to_string()));
+}
+
+#[tokio::test]
+async fn redis_failing_mid_session() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    let assign = client.info(0, json!({"channel_id": "1", "guild_id": "2"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+    assert!(server.redis.exists("2_1_voice"));
+
+    server.redis.set_down(true);
+
+    client.send(info(3, json!({"user_id": "3", "channel_id": "1", "guild_id": "2"}))).await;
+    assert_eq!(client.error().await, 4000);
+    assert_eq!(client.close_frame().await, (4000, REDIS_UNAVAILABLE.to_string()));
+}
+
+#[tokio::test]
+async fn resume_after_redis_failed() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.connect().await;
+    let ready = client.identify().await;
+    let session_id = ready["d"]["session_id"].as_str().unwrap().to_string();
+
+    client.info(0, json!({"channel_id": "1", "guild_id": "2"})).await;
+
+    server.redis.set_down(true);
+
+    client.send(info(0, json!({"channel_id": "4", "guild_id": "2"}))).await;
+    assert_eq!(client.error().await, 4000);
+    assert_eq!(client.close_frame().await.0, 4000);
+
+    server.redis.set_down(false);
+
+    // What the session owned is still there to pick up
+    let mut client = server.connect().await;
+    let resume_token = token(SECRET, &client.nonce());
+    client.send(json!({"op": 2, "d": {"token": resume_token, "session_id": session_id}})).await;
+
+    let ready = client.json().await;
+    assert_eq!(ready["op"], 3, "RESUME failed: {}", ready);
+    assert!(server.redis.exists("2_1_voice"));
+}
@@ -0,0 +1,45 @@
+//! What shutting down leaves behind, nothing
+mod common;
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use common::{TestServer, ADMIN_SECRET};
+
+#[tokio::test]
+async fn shutdown_closes_and_cleans_up() {
+    let mut server = TestServer::start(&[("ADMIN_SECRET", ADMIN_SECRET)]).await;
+    let mut admin = server.admin().await;
+
+    let mut client = server.identified().await;
+    client.info(0, json!({"channel_id": "1", "guild_id": "2"})).await;
+    client.info(3, json!({"user_id": "3", "channel_id": "1", "guild_id": "2"})).await;
+
+    // Dropped, so what it owns waits for a RESUME that won't come
+    let mut dropped = server.identified().await;
+    dropped.info(0, json!({"channel_id": "4", "guild_id": "2"})).await;
+    drop(dropped);
+
+    // Not identified, only has a nonce
+    let mut unidentified = server.connect().await;
+
+    // Left are the admin, the client and the unidentified one
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while admin.info(8, json!({})).await["d"]["data"]["sessions"].as_array().unwrap().len() > 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("The dropped connection didn't go away");
+    assert!(server.redis.exists("2_4_voice"));
+    assert_eq!(server.redis.keys("*_nonce").len(), 1);
+
+    server.stop().await;
+
+    for client in [&mut admin, &mut client, &mut unidentified] {
+        let (code, reason) = client.close_frame().await;
+        assert_eq!(code, 1001);
+        assert_eq!(serde_json::from_str::<Value>(&reason).unwrap(), json!({"reason": "Server shutting down", "reconnectable": true}));
+    }
+
+    assert!(server.redis.keys("*").is_empty(), "Left behind {:?}", server.redis.keys("*"));
+}
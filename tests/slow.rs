@@ -0,0 +1,69 @@
+//! Connections that don't read what's sent to them, in a file of their own
+//! since it counts on the metrics
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpSocket;
+use tokio_tungstenite::tungstenite::Message;
+
+use bannana_pho::metrics::METRICS;
+use common::{eventually, info, TestServer, ADMIN_SECRET};
+
+#[tokio::test]
+async fn slow_reader_gets_closed() {
+    let server = TestServer::start(&[("ADMIN_SECRET", ADMIN_SECRET), ("OUTBOUND_QUEUE_SIZE", "1")]).await;
+    let mut admin = server.admin().await;
+
+    // A small receive buffer, so the server can't get far ahead of it
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let stream = socket.connect(server.addr.parse().unwrap()).await.unwrap();
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}", server.addr), stream).await.unwrap();
+    ws.next().await.unwrap().unwrap();
+
+    let list = admin.info(8, json!({})).await;
+    let id = list["d"]["data"]["sessions"].as_array().unwrap().iter()
+        .find(|session| session["identified_at"].is_null())
+        .map(|session| session["id"].clone())
+        .unwrap();
+
+    // Heartbeat without reading the acks, until the server is stuck sending
+    // them and stops reading
+    let heartbeat = Message::Text(json!({"op": 4, "d": {}}).to_string());
+    while tokio::time::timeout(Duration::from_millis(200), ws.send(heartbeat.clone())).await.is_ok() {}
+
+    // More than its outbound queue holds
+    for _ in 0..3 {
+        admin.send(info(10, json!({"id": id}))).await;
+    }
+    assert!(admin.alive().await);
+
+    // It isn't read from while it's that far behind, so it's dropped without
+    // waiting for it to take the close frame
+    eventually("the slow connection to close", || METRICS.slow_connections.load(Ordering::Relaxed) == 1).await;
+
+    // Given a moment to take the close frame it won't take
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let list = admin.info(8, json!({})).await;
+    assert_eq!(list["d"]["data"]["sessions"].as_array().unwrap().len(), 1, "Still connected: {}", list);
+
+    // Whatever it gets once it reads again, the acks end in the close
+    loop {
+        match tokio::time::timeout(Duration::from_secs(5), ws.next()).await.expect("Timed out waiting for the server!") {
+            Some(Ok(Message::Text(text))) => {
+                let msg: Value = serde_json::from_str(&text).unwrap();
+                assert!(msg["op"] == 5 || msg["op"] == 8, "Unexpected {}", msg);
+            },
+            Some(Ok(Message::Close(frame))) => {
+                assert_eq!(frame.map(|frame| u16::from(frame.code)), Some(4008));
+                break;
+            },
+            Some(Ok(_)) => {},
+            Some(Err(_)) | None => break
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Voice states going away while something else happens to them
+mod common;
+
+use ::redis::Commands;
+use serde_json::{json, Value};
+
+use bannana_pho::redis::{destroy_voice_state, move_voice_state};
+use common::{eventually, info, token, TestClient, TestServer, ADMIN_SECRET, SECRET};
+
+/// Create a voice state in channel `channel_id` of guild 9, giving its session
+/// ID
+async fn create_voice_state(client: &mut TestClient, channel_id: &str) -> String {
+    let done = client.info(3, json!({"user_id": "1", "channel_id": channel_id, "guild_id": "9"})).await;
+    assert_eq!(done["d"]["type"], 4, "Expected VST_DONE, got {}", done);
+
+    done["d"]["data"]["session_id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn move_interleaved_with_destroy() {
+    let server = TestServer::start(&[]).await;
+    let mut redis = ::redis::Client::open(server.redis.addr()).unwrap().get_connection().unwrap();
+
+    let _: () = redis.sadd("9_1_voice", "moved").unwrap();
+    let _: () = redis.hset("moved_session", "channel", "9_1_voice").unwrap();
+
+    // Destroyed after the mover read the channel, but before it moved it
+    let from: String = redis.hget("moved_session", "channel").unwrap();
+    assert!(destroy_voice_state(&mut redis, "moved", None).unwrap());
+    assert!(!move_voice_state(&mut redis, "moved", &from, "9_2_voice").unwrap());
+
+    assert!(server.redis.keys("*").is_empty(), "Left behind {:?}", server.redis.keys("*"));
+
+    let _: () = redis.sadd("9_1_voice", "destroyed").unwrap();
+    let _: () = redis.hset("destroyed_session", "channel", "9_1_voice").unwrap();
+    let _: () = redis.sadd("9_2_voice", "token_abc").unwrap();
+
+    // Moved while the destroy was on its way, it goes from where it ended up
+    assert!(move_voice_state(&mut redis, "destroyed", "9_1_voice", "9_2_voice").unwrap());
+    assert!(destroy_voice_state(&mut redis, "destroyed", None).unwrap());
+
+    assert_eq!(server.redis.keys("*"), vec!["9_2_voice".to_string()]);
+    assert_eq!(server.redis.members("9_2_voice"), vec!["token_abc".to_string()]);
+}
+
+#[tokio::test]
+async fn kick_after_move() {
+    let server = TestServer::start(&[("ADMIN_SECRET", ADMIN_SECRET)]).await;
+    let mut owner = server.identified().await;
+    let mut admin = server.admin().await;
+
+    let session_id = create_voice_state(&mut owner, "1").await;
+
+    let assign = owner.info(0, json!({"channel_id": "2", "guild_id": "9"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+    let ack = owner.info(6, json!({"session_id": session_id, "channel_id": "2", "guild_id": "9"})).await;
+    assert_eq!(ack["d"]["type"], 20, "Expected VST_UPDATE_ACK, got {}", ack);
+
+    let ack = admin.info(7, json!({"session_id": session_id})).await;
+    assert_eq!(ack, json!({"op": 6, "d": {"type": 13, "data": {"session_id": session_id}}}));
+
+    let (code, reason) = owner.close_frame().await;
+    assert_eq!(code, 4000);
+    assert_eq!(serde_json::from_str::<Value>(&reason).unwrap()["reason"], "Voice state kicked");
+
+    assert!(!server.redis.exists(&format!("{}_session", session_id)));
+    assert!(!server.redis.exists("9_1_voice"));
+    assert!(!server.redis.members("9_2_voice").contains(&session_id));
+}
+
+#[tokio::test]
+async fn ended_session_leaves_nothing() {
+    let server = TestServer::start(&[("CHANNEL_TOKEN_TTL", "60")]).await;
+    let mut client = server.identified().await;
+
+    let assign = client.info(0, json!({"channel_id": "1", "guild_id": "9"})).await;
+    let token = assign["d"]["data"]["token"].as_str().unwrap().to_string();
+    assert!(server.redis.exists(&format!("{}_token", token)));
+
+    create_voice_state(&mut client, "1").await;
+    create_voice_state(&mut client, "2").await;
+
+    // Closing normally ends the session right away
+    client.close().await;
+
+    eventually("the cleanup", || server.redis.keys("*").is_empty()).await;
+}
+
+#[tokio::test]
+async fn kick_unknown_voice_state() {
+    let server = TestServer::start(&[("ADMIN_SECRET", ADMIN_SECRET)]).await;
+    let mut admin = server.admin().await;
+
+    admin.send(info(7, json!({"session_id": "gone"}))).await;
+    assert_eq!(admin.error().await, 4003);
+    assert!(admin.alive().await);
+}
+
+#[tokio::test]
+async fn kick_while_pending_cleanup() {
+    let server = TestServer::start(&[("ADMIN_SECRET", ADMIN_SECRET)]).await;
+    let mut owner = server.connect().await;
+    let ready = owner.identify().await;
+    let mut admin = server.admin().await;
+
+    let session_id = create_voice_state(&mut owner, "1").await;
+
+    // Dropped without a close, so the session waits for a RESUME
+    drop(owner);
+    eventually_alone(&mut admin).await;
+
+    let ack = admin.info(7, json!({"session_id": session_id})).await;
+    assert_eq!(ack["d"]["type"], 13, "Expected VST_DESTROY_ACK, got {}", ack);
+    assert!(!server.redis.exists(&format!("{}_session", session_id)));
+
+    let mut owner = server.connect().await;
+    owner.send(json!({"op": 2, "d": {"token": token(SECRET, &owner.nonce()), "session_id": ready["d"]["session_id"]}})).await;
+    assert_eq!(owner.json().await["op"], 3);
+
+    // Resuming doesn't bring back what was kicked
+    assert!(!server.redis.exists(&format!("{}_session", session_id)));
+}
+
+/// Wait until `admin` is the only connection left
+async fn eventually_alone(admin: &mut TestClient) {
+    for _ in 0..100 {
+        let list = admin.info(8, json!({})).await;
+
+        if list["d"]["data"]["sessions"].as_array().unwrap().len() == 1 {
+            return;
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    panic!("The other connections didn't go away");
+}
+
+#[tokio::test]
+async fn update_answers_with_the_updated_state() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+
+    let done = client.info(3, json!({"user_id": "1", "channel_id": "1", "guild_id": "9", "mute": true})).await;
+    let session_id = done["d"]["data"]["session_id"].as_str().unwrap().to_string();
+
+    let ack = client.info(6, json!({"session_id": session_id, "self_mute": true})).await;
+    assert_eq!(ack, json!({"op": 6, "d": {"type": 20, "data": {
+        "session_id": session_id, "channel_id": "1", "guild_id": "9",
+        "mute": true, "deaf": false, "self_mute": true, "self_deaf": false
+    }}}));
+
+    let assign = client.info(0, json!({"channel_id": "2", "guild_id": "9"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+    let ack = client.info(6, json!({"session_id": session_id, "channel_id": "2", "guild_id": "9", "mute": false})).await;
+    assert_eq!(ack["d"]["data"], json!({
+        "session_id": session_id, "channel_id": "2", "guild_id": "9",
+        "mute": false, "deaf": false, "self_mute": true, "self_deaf": false
+    }));
+    assert_eq!(server.redis.field(&format!("{}_session", session_id), "channel").as_deref(), Some("9_2_voice"));
+}
+
+#[tokio::test]
+async fn moves_stay_in_existing_channels_of_the_guild() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+    let session_id = create_voice_state(&mut client, "1").await;
+
+    // A channel nobody asked for
+    client.send(info(6, json!({"session_id": session_id, "channel_id": "5", "guild_id": "9"}))).await;
+    assert_eq!(client.error().await, 4003);
+    assert!(!server.redis.exists("9_5_voice"));
+
+    // Channels of another guild, or the dms
+    let assign = client.info(0, json!({"channel_id": "2", "guild_id": "8"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+    client.send(info(6, json!({"session_id": session_id, "channel_id": "2", "guild_id": "8"}))).await;
+    assert_eq!(client.error().await, 4003);
+
+    let assign = client.info(0, json!({"channel_id": "3"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+    client.send(info(6, json!({"session_id": session_id, "channel_id": "3"}))).await;
+    assert_eq!(client.error().await, 4003);
+
+    // Right where it was
+    assert_eq!(server.redis.field(&format!("{}_session", session_id), "channel").as_deref(), Some("9_1_voice"));
+    assert!(server.redis.members("9_1_voice").contains(&session_id));
+    assert!(!server.redis.members("8_2_voice").contains(&session_id));
+    assert!(!server.redis.members("dm_3_voice").contains(&session_id));
+}
+
+#[tokio::test]
+async fn update_dry_run() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.identified().await;
+    let session_id = create_voice_state(&mut client, "1").await;
+
+    client.send(json!({"op": 6, "d": {"type": 6, "data": {"session_id": session_id, "channel_id": "2", "guild_id": "9"}, "validate_only": true}})).await;
+    let ack = client.json().await;
+    assert_eq!(ack["d"]["type"], 20, "Expected VST_UPDATE_ACK, got {}", ack);
+    assert_eq!(ack["d"]["data"]["channel_id"], "2");
+
+    // Still where it was
+    assert_eq!(server.redis.field(&format!("{}_session", session_id), "channel").as_deref(), Some("9_1_voice"));
+}
+
+#[tokio::test]
+async fn only_the_owner_changes_its_state() {
+    let server = TestServer::start(&[]).await;
+    let mut owner = server.identified().await;
+    let mut other = server.identified().await;
+
+    let assign = owner.info(0, json!({"channel_id": "1", "guild_id": "9"})).await;
+    assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+    let session_id = create_voice_state(&mut owner, "1").await;
+
+    other.send(info(6, json!({"session_id": session_id, "self_mute": true}))).await;
+    assert_eq!(other.error().await, 4003);
+
+    other.send(info(5, json!({"session_id": session_id}))).await;
+    assert_eq!(other.error().await, 4003);
+
+    other.send(info(2, json!({"channel_id": "1", "guild_id": "9"}))).await;
+    assert_eq!(other.error().await, 4003);
+
+    assert!(server.redis.members("9_1_voice").contains(&session_id));
+    assert_eq!(server.redis.field(&format!("{}_session", session_id), "self_mute").as_deref(), Some("0"));
+
+    // Still up to the owner
+    let ack = owner.info(5, json!({"session_id": session_id})).await;
+    assert_eq!(ack["d"]["type"], 13, "Expected VST_DESTROY_ACK, got {}", ack);
+
+    let ack = owner.info(2, json!({"channel_id": "1", "guild_id": "9"})).await;
+    assert_eq!(ack["d"]["type"], 12, "Expected CHANNEL_DESTROY_ACK, got {}", ack);
+    assert!(!server.redis.exists("9_1_voice"));
+}
+
+#[tokio::test]
+async fn voice_states_on_every_shard() {
+    let server = TestServer::start(&[("REDIS_SHARDS", "3")]).await;
+    let mut client = server.identified().await;
+
+    // Guilds spread over the shards, and a dm routed by its channel
+    let channels: Vec<(&str, Option<String>)> = (1..=6).map(|guild| ("1", Some(guild.to_string()))).chain([("7", None)]).collect();
+
+    for (channel_id, guild_id) in &channels {
+        let assign = client.info(0, json!({"channel_id": channel_id, "guild_id": guild_id})).await;
+        assert_eq!(assign["d"]["type"], 1, "Expected CHANNEL_ASSIGN, got {}", assign);
+
+        let done = client.info(3, json!({"user_id": "1", "channel_id": channel_id, "guild_id": guild_id})).await;
+        assert_eq!(done["d"]["type"], 4, "Expected VST_DONE, got {}", done);
+        let session_id = done["d"]["data"]["session_id"].as_str().unwrap().to_string();
+
+        let ack = client.info(6, json!({"session_id": session_id, "self_mute": true})).await;
+        assert_eq!(ack["d"]["type"], 20, "Expected VST_UPDATE_ACK, got {}", ack);
+        assert_eq!(server.redis.field(&format!("{}_session", session_id), "self_mute").as_deref(), Some("1"));
+
+        let ack = client.info(5, json!({"session_id": session_id})).await;
+        assert_eq!(ack["d"]["type"], 13, "Expected VST_DESTROY_ACK, got {}", ack);
+        assert!(!server.redis.exists(&format!("{}_session", session_id)));
+    }
+}